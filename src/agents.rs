@@ -0,0 +1,116 @@
+// Families of user agents that we can recognize
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Agent {
+    Chrome,
+    Firefox,
+    Safari,
+    Curl,
+    PythonRequests,
+    Googlebot,
+    Bingbot,
+    OtherBot,
+    Empty,
+    Other,
+}
+
+// Substrings identifying each family, checked in order so that the first match wins. Order
+// matters: crawlers often claim to be browsers, and Chrome's user agent mentions Safari.
+const RULES: &[(&str, Agent)] = &[
+    ("googlebot", Agent::Googlebot),
+    ("bingbot", Agent::Bingbot),
+    ("bot", Agent::OtherBot),
+    ("crawler", Agent::OtherBot),
+    ("spider", Agent::OtherBot),
+    ("slurp", Agent::OtherBot),
+    ("curl/", Agent::Curl),
+    ("python-requests/", Agent::PythonRequests),
+    ("firefox/", Agent::Firefox),
+    ("fxios/", Agent::Firefox),
+    // Chromium-based browsers, such as Edge and Opera, count as Chrome
+    ("chrome/", Agent::Chrome),
+    ("crios/", Agent::Chrome),
+    ("safari/", Agent::Safari),
+];
+
+impl Agent {
+    pub fn classify(user_agent: &str) -> Agent {
+        if user_agent.is_empty() || user_agent == "-" {
+            return Agent::Empty;
+        }
+        RULES
+            .iter()
+            .find(|(pattern, _)| contains_ignore_case(user_agent, pattern))
+            .map_or(Agent::Other, |(_, agent)| *agent)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Agent::Chrome => "Chrome",
+            Agent::Firefox => "Firefox",
+            Agent::Safari => "Safari",
+            Agent::Curl => "curl",
+            Agent::PythonRequests => "python-requests",
+            Agent::Googlebot => "Googlebot",
+            Agent::Bingbot => "bingbot",
+            Agent::OtherBot => "other bot",
+            Agent::Empty => "(empty)",
+            Agent::Other => "other",
+        }
+    }
+
+    // Anything that's clearly not a person: crawlers, scripts, and clients that don't say
+    pub fn is_bot(&self) -> bool {
+        matches!(
+            self,
+            Agent::Googlebot
+                | Agent::Bingbot
+                | Agent::OtherBot
+                | Agent::Curl
+                | Agent::PythonRequests
+                | Agent::Empty
+        )
+    }
+}
+
+// Patterns are lowercase, so only the user agent needs folding
+fn contains_ignore_case(haystack: &str, needle: &str) -> bool {
+    haystack
+        .as_bytes()
+        .windows(needle.len())
+        .any(|window| window.eq_ignore_ascii_case(needle.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_common_agents() {
+        let cases = [
+            ("Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/101.0.4951.54 Safari/537.36", Agent::Chrome),
+            ("Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/101.0.4951.54 Safari/537.36 Edg/101.0.1210.39", Agent::Chrome),
+            ("Mozilla/5.0 (X11; Linux x86_64; rv:100.0) Gecko/20100101 Firefox/100.0", Agent::Firefox),
+            ("Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/15.4 Safari/605.1.15", Agent::Safari),
+            ("curl/7.81.0", Agent::Curl),
+            ("python-requests/2.27.1", Agent::PythonRequests),
+            ("Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)", Agent::Googlebot),
+            ("Mozilla/5.0 AppleWebKit/537.36 (KHTML, like Gecko; compatible; bingbot/2.0; +http://www.bing.com/bingbot.htm) Chrome/100.0.4896.127 Safari/537.36", Agent::Bingbot),
+            ("Mozilla/5.0 (compatible; AhrefsBot/7.0; +http://ahrefs.com/robot/)", Agent::OtherBot),
+            ("-", Agent::Empty),
+            ("", Agent::Empty),
+            ("Dalvik/2.1.0 (Linux; U; Android 12)", Agent::Other),
+        ];
+        for (user_agent, agent) in cases {
+            assert_eq!(Agent::classify(user_agent), agent, "{}", user_agent);
+        }
+    }
+
+    #[test]
+    fn bots_exclude_browsers() {
+        assert!(Agent::Googlebot.is_bot());
+        assert!(Agent::Curl.is_bot());
+        assert!(Agent::Empty.is_bot());
+        assert!(!Agent::Safari.is_bot());
+        assert!(!Agent::Other.is_bot());
+    }
+}
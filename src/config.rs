@@ -1,8 +1,20 @@
+use crate::exclude::Exclusions;
+use crate::fields::{Field, OutputFormat, DEFAULT_FIELDS};
+use crate::filter::Filter;
+use crate::net::{Cidr, RealIp, RealIpHeader};
+use crate::parsers::format::LogFormat;
 use crate::Error;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::process;
+use std::str::FromStr;
+use std::time::Duration;
 
-const USAGE_TEXT: &str = "Usage: dashlight [-f filename] [watch|convert]";
-const HELP_TEXT: &str = r#"Usage: dashlight [-f filename] [watch|convert]
+// Routes kept when --max-routes isn't given, with --unique since each route has its own
+// HyperLogLog, and with --follow since an exporter would otherwise keep every path it ever sees
+const DEFAULT_MAX_ROUTES: usize = 1000;
+const USAGE_TEXT: &str = "Usage: dashlight [-f filename] [options] [watch|convert|export]";
+const HELP_TEXT: &str = r#"Usage: dashlight [-f filename] [options] [watch|convert|export]
 
 Parse nginx access logs and either convert them to a delimited format or
 summarize the results. Reads from STDIN by default, but you can also specify an
@@ -14,28 +26,238 @@ have been receiving, and what codes they've been returning.
 The "convert" function is to facilitate piping to other analysis tools, such as
 awk.
 
+The "export" function writes the requests to a SQLite database, for querying
+with SQL.
+
 Options:
  -h             : display this message
  -f filename    : provide a filename to read for logs
+ --query-params : list the most common query parameter names for each route
+                  (names only, values are never displayed)
+ --canonicalize : normalize paths before grouping them into routes, by decoding
+                  unreserved characters, collapsing duplicate slashes, and
+                  removing "." and ".." segments
+ --depth n      : show routes as a tree of path prefixes, n segments deep, where
+                  each prefix includes the requests for everything under it
+ --top n        : show the top n routes (default: 10)
+ --sort key     : rank routes by one of total, 2xx, 3xx, 4xx, 5xx, error-rate,
+                  bytes, or p99 (default: total)
+ --max-routes n : keep at most n routes in memory, folding the least frequent
+                  ones into an "(other)" row. With --depth, each prefix keeps
+                  at most n children. The host, --clients, --subnet-v4/v6,
+                  --geoip, --asn and --agents tables are capped at n the same
+                  way
+ --clients      : list the top client addresses by number of requests
+ --subnet-v4 n  : list the top client subnets, grouping IPv4 addresses into
+                  blocks with an n-bit prefix (default: 24)
+ --subnet-v6 n  : same as above, for IPv6 addresses (default: 64)
+ --fields f[,f] : the fields for convert to write, in order (default:
+                  ip,time,method,url,status). Any of ip, user, time, method,
+                  url, path, query, request, status, bytes, referer, ua,
+                  request_time, host, agent (user agent family),
+                  referrer_domain, country (needs --geoip), asn and org (need
+                  --asn)
+ --output fmt   : how convert separates fields, either quoted (with ", the
+                  default), csv, or tsv. CSV and TSV values are quoted when
+                  needed (RFC 4180). With json, each line is written as a JSON
+                  object with typed fields, and watch writes its summary as a
+                  single JSON object. With influx, each line is written as an
+                  InfluxDB point, and watch writes points for each --interval
+                  of log time
+ --header       : start convert output with a row of field names
+ --where expr   : only include requests matching an expression, such as
+                  'status >= 500 && path ~ "^/api" && ip in 10.0.0.0/8'
+                  Fields: status, bytes, request_time, ip, method, path, query,
+                  host, referer, ua, user
+                  Operators: == != < <= > >= ~ (regex) !~ in (networks)
+                  Combine with && || ! and parentheses
+ --referrers    : list the top external referring domains, and the routes that
+                  each one sends visitors to
+ --own-host h[,h]
+                : domains (and their subdomains) that count as internal
+                  referrers, e.g. example.com
+ --unique key   : estimate the number of distinct clients overall and for each
+                  route, identifying them by ip or ip+ua (address and user
+                  agent). Implies --max-routes 1000 unless it's given
+ --agents       : list the top user agent families, such as Chrome, curl or
+                  Googlebot
+ --exclude-bots : leave out requests from crawlers, scripts, and clients without
+                  a user agent
+ --exclude-path glob
+                : leave out requests whose path matches a glob, where * matches
+                  within a segment and ** across segments, e.g. '/static/**'
+ --exclude-regex re
+                : leave out requests whose path matches a regular expression
+ --exclude-ext ext[,ext]
+                : leave out requests for files with these extensions, e.g. css,js
+ --exclude-ua re
+                : leave out requests whose user agent matches a regular
+                  expression
+ --exclude-preset name[,name]
+                : leave out static assets (static) or load balancer health
+                  checks (health)
+                  The exclusion options can be repeated, and the number of
+                  requests they leave out is shown in the summary
+ --geoip file   : list the top client countries, looked up in a local MaxMind
+                  country database (e.g. GeoLite2-Country.mmdb)
+ --asn file     : list the top client networks, looked up in a local MaxMind
+                  ASN database (e.g. GeoLite2-ASN.mmdb)
+ --log-format f : parse lines written with a custom nginx log_format, e.g.
+                  '$remote_addr - [$time_local] "$request" $status' (default:
+                  combined). If it includes $host, requests are also grouped
+                  by host, and routes are keyed by host and path
+ --set-real-ip-from cidr[,cidr]
+                : trust these proxies to report the real client address, and
+                  use that address in place of theirs
+ --real-ip-header name
+                : the log format variable holding the real client address,
+                  either http_x_forwarded_for or realip_remote_addr (default:
+                  http_x_forwarded_for)
+ --real-ip-recursive
+                : skip past every trusted proxy in X-Forwarded-For, rather than
+                  taking the last address in it
+
+ --follow       : keep reading the file as lines are appended to it, like
+                  tail -f. Watch prints its summary on every interval.
+                  Implies --max-routes 1000 unless it's given
+ --interval n   : seconds between summaries when following, and the width of
+                  each window with --output influx (default: 10)
+ --listen addr  : when following, serve Prometheus metrics over HTTP at
+                  http://addr/metrics, e.g. 127.0.0.1:9898. Only the top routes
+                  (see --top and --sort) get their own series
+ --metrics-file path
+                : write the same metrics to a file, replacing it atomically on
+                  every interval when following, and once at the end, e.g. for
+                  node_exporter's textfile collector
+ --statsd addr  : send each interval's request counts and times to a StatsD
+                  server over UDP, e.g. 127.0.0.1:8125, along with those of
+                  its top routes (see --top and --sort)
+ --dogstatsd    : with --statsd, tag the metrics with the route and status
+                  class, for DogStatsD, rather than putting them in the names
+
+ --sqlite path  : the database for export to write to, which is created if it
+                  doesn't exist, or added to if it does
 
 Examples:
     dashlight convert -f access.log     # Prints comma-delimited list of fields
     dashlight watch -f access.log       # Summarizes the request codes
+    dashlight export --sqlite out.db -f access.log
+                                        # Writes the requests to a database
 "#;
 
 #[derive(Debug, PartialEq)]
 pub enum Mode {
     CONVERT,
     WATCH,
+    EXPORT,
+}
+
+// The column that routes are ranked by
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SortKey {
+    Total,
+    X2,
+    X3,
+    X4,
+    X5,
+    ErrorRate,
+    Bytes,
+    P99,
+}
+
+// What identifies a distinct client when counting unique visitors
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum UniqueKey {
+    Ip,
+    IpAndAgent,
+}
+
+impl FromStr for UniqueKey {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ip" => Ok(UniqueKey::Ip),
+            "ip+ua" => Ok(UniqueKey::IpAndAgent),
+            _ => Err(Error::InvalidArgs),
+        }
+    }
+}
+
+impl FromStr for SortKey {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "total" => Ok(SortKey::Total),
+            "2xx" => Ok(SortKey::X2),
+            "3xx" => Ok(SortKey::X3),
+            "4xx" => Ok(SortKey::X4),
+            "5xx" => Ok(SortKey::X5),
+            "error-rate" => Ok(SortKey::ErrorRate),
+            "bytes" => Ok(SortKey::Bytes),
+            "p99" => Ok(SortKey::P99),
+            _ => Err(Error::InvalidArgs),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct Config {
     pub filename: Option<String>,
     pub mode: Mode,
+    pub query_params: bool,
+    pub canonicalize: bool,
+    pub depth: Option<usize>,
+    pub top: usize,
+    pub sort: SortKey,
+    pub max_routes: Option<usize>,
+    pub clients: bool,
+    // Prefix lengths for grouping IPv4 and IPv6 clients into subnets
+    pub subnets: Option<(u8, u8)>,
+    pub unique: Option<UniqueKey>,
+    pub filter: Option<Filter>,
+    pub referrers: bool,
+    // Our own domains, lowercased and without "www.", so that they match referrer domains
+    pub own_domains: Vec<String>,
+    pub agents: bool,
+    pub exclude_bots: bool,
+    // None when no exclusion rules were given
+    pub exclusions: Option<Exclusions>,
+    // Paths to MaxMind databases for looking up client countries and ASNs
+    pub geoip: Option<String>,
+    pub asn: Option<String>,
+    // None for the combined format, which has a faster parser of its own
+    pub log_format: Option<LogFormat>,
+    // Whether to group by virtual host, which is only possible if the log format includes it
+    pub hosts: bool,
+    pub real_ip: Option<RealIp>,
+    // The columns written by convert
+    pub fields: Vec<Field>,
+    pub output: OutputFormat,
+    pub header: bool,
+    pub follow: bool,
+    // How often to report while following
+    pub interval: Duration,
+    // Address to serve metrics on, and the file to write them to
+    pub listen: Option<SocketAddr>,
+    pub metrics_file: Option<PathBuf>,
+    // StatsD server to send each interval's counts to, and whether it takes DogStatsD tags
+    pub statsd: Option<SocketAddr>,
+    pub dogstatsd: bool,
+    // Database to export requests to
+    pub sqlite: Option<PathBuf>,
 }
 
 impl Config {
+    // Whether any metrics are exported, which needs some extra stats to be kept
+    pub fn metrics(&self) -> bool {
+        self.listen.is_some() || self.metrics_file.is_some()
+    }
+
+    // Whether the view needs the stats since they were last taken, for StatsD or influx windows
+    pub fn intervals(&self) -> bool {
+        self.statsd.is_some() || (self.mode == Mode::WATCH && self.output == OutputFormat::Influx)
+    }
+
     // TODO: convert to OsString
     pub fn new(mut args: Vec<String>) -> Config {
         if find_flag_and_remove(&mut args, "-h").is_some() {
@@ -45,6 +267,10 @@ impl Config {
 
         match parse_args(&mut args) {
             Ok(config) => config,
+            Err(Error::InvalidValue(message)) => {
+                eprintln!("{}", message);
+                process::exit(1);
+            }
             _ => {
                 eprintln!("{}", USAGE_TEXT);
                 process::exit(1);
@@ -57,15 +283,295 @@ fn parse_args(args: &mut Vec<String>) -> Result<Config, Error> {
     // If the help flag was provided, print the help text and exit
 
     let filename = find_named_and_remove(args, "-f");
+    let query_params = find_flag_and_remove(args, "--query-params").is_some();
+    let canonicalize = find_flag_and_remove(args, "--canonicalize").is_some();
+    let depth = find_named_and_remove(args, "--depth")
+        .map(|depth| depth.parse())
+        .transpose()
+        .map_err(|_| Error::InvalidArgs)?;
+    let top = find_named_and_remove(args, "--top")
+        .map_or(Ok(10), |top| top.parse())
+        .map_err(|_| Error::InvalidArgs)?;
+    let sort = find_named_and_remove(args, "--sort")
+        .map_or(Ok(SortKey::Total), |sort| SortKey::from_str(&sort))?;
+    let clients = find_flag_and_remove(args, "--clients").is_some();
+    let subnet_v4 = find_prefix_and_remove(args, "--subnet-v4", 32)?;
+    let subnet_v6 = find_prefix_and_remove(args, "--subnet-v6", 128)?;
+    let subnets = match (subnet_v4, subnet_v6) {
+        (None, None) => None,
+        (v4, v6) => Some((v4.unwrap_or(24), v6.unwrap_or(64))),
+    };
+    let unique = find_named_and_remove(args, "--unique")
+        .map(|unique| UniqueKey::from_str(&unique))
+        .transpose()?;
+    let filter = find_named_and_remove(args, "--where")
+        .map(|expression| Filter::new(&expression))
+        .transpose()
+        .map_err(|message| {
+            Error::InvalidValue(format!("Invalid --where expression: {}", message))
+        })?;
+    let referrers = find_flag_and_remove(args, "--referrers").is_some();
+    let own_domains = find_named_and_remove(args, "--own-host").map_or(vec![], |hosts| {
+        hosts
+            .split(',')
+            .map(|host| {
+                let host = host.trim().to_ascii_lowercase();
+                match host.strip_prefix("www.") {
+                    Some(host) => String::from(host),
+                    None => host,
+                }
+            })
+            .filter(|host| !host.is_empty())
+            .collect()
+    });
+    let agents = find_flag_and_remove(args, "--agents").is_some();
+    let exclude_bots = find_flag_and_remove(args, "--exclude-bots").is_some();
+    let exclusions = find_exclusions_and_remove(args)?;
+    let geoip = find_named_and_remove(args, "--geoip");
+    let asn = find_named_and_remove(args, "--asn");
+    let fields_given = args.iter().any(|arg| arg == "--fields");
+    let fields = find_fields_and_remove(args, geoip.is_some(), asn.is_some())?;
+    let output = find_named_and_remove(args, "--output")
+        .map_or(Ok(OutputFormat::Quoted), |output| {
+            OutputFormat::from_str(&output)
+        })?;
+    let header = find_flag_and_remove(args, "--header").is_some();
+    let follow = find_flag_and_remove(args, "--follow").is_some();
+    let interval = match find_named_and_remove(args, "--interval").map(|x| x.parse()) {
+        Some(Ok(seconds)) if seconds > 0 => Duration::from_secs(seconds),
+        Some(_) => return Err(Error::InvalidArgs),
+        None => Duration::from_secs(10),
+    };
+    let listen = find_named_and_remove(args, "--listen")
+        .map(|addr| addr.parse())
+        .transpose()
+        .map_err(|_| Error::InvalidArgs)?;
+    let metrics_file = find_named_and_remove(args, "--metrics-file").map(PathBuf::from);
+    let statsd = find_named_and_remove(args, "--statsd")
+        .map(|addr| addr.parse())
+        .transpose()
+        .map_err(|_| Error::InvalidArgs)?;
+    let dogstatsd = find_flag_and_remove(args, "--dogstatsd").is_some();
+    let sqlite = find_named_and_remove(args, "--sqlite").map(PathBuf::from);
+    let max_routes = find_named_and_remove(args, "--max-routes")
+        .map(|max_routes| max_routes.parse())
+        .transpose()
+        .map_err(|_| Error::InvalidArgs)?
+        .or((unique.is_some() || follow).then_some(DEFAULT_MAX_ROUTES));
+
+    let log_format = match find_named_and_remove(args, "--log-format") {
+        Some(format) if format != "combined" => Some(LogFormat::new(&format)?),
+        _ => None,
+    };
+    let real_ip = find_real_ip_and_remove(args, log_format.as_ref())?;
+    let hosts = log_format
+        .as_ref()
+        .is_some_and(|format| format.has_variable("host"));
 
     let mode_str = args.get(1).ok_or(Error::ParsingError)?;
     let mode = match mode_str.as_str() {
         "watch" => Mode::WATCH,
         "convert" => Mode::CONVERT,
+        "export" => Mode::EXPORT,
         _ => return Err(Error::ParsingError),
     };
+    if (mode == Mode::EXPORT) != sqlite.is_some() {
+        return Err(Error::InvalidValue(String::from(
+            "export needs --sqlite, which only applies to export",
+        )));
+    }
+    if mode == Mode::WATCH && matches!(output, OutputFormat::Csv | OutputFormat::Tsv) {
+        return Err(Error::InvalidValue(String::from(
+            "Invalid --output: csv and tsv only apply to convert",
+        )));
+    }
+    if listen.is_some() && !(follow && mode == Mode::WATCH) {
+        return Err(Error::InvalidValue(String::from(
+            "--listen only applies to watch --follow",
+        )));
+    }
+    if metrics_file.is_some() && mode != Mode::WATCH {
+        return Err(Error::InvalidValue(String::from(
+            "--metrics-file only applies to watch",
+        )));
+    }
+    if statsd.is_some() && mode != Mode::WATCH {
+        return Err(Error::InvalidValue(String::from(
+            "--statsd only applies to watch",
+        )));
+    }
+    if dogstatsd && statsd.is_none() {
+        return Err(Error::InvalidValue(String::from(
+            "--dogstatsd needs --statsd",
+        )));
+    }
+    if output == OutputFormat::Influx {
+        let message = match (fields_given, header, statsd) {
+            (true, _, _) => "--fields doesn't apply to influx, which has fixed tags and fields",
+            (_, true, _) => "--header doesn't apply to influx, which has no columns",
+            (_, _, Some(_)) => "--statsd can't be used with influx, which counts by log time",
+            _ => "",
+        };
+        if !message.is_empty() {
+            return Err(Error::InvalidValue(String::from(message)));
+        }
+    }
+    if header && output == OutputFormat::Json {
+        return Err(Error::InvalidValue(String::from(
+            "--header doesn't apply to json, which names every field",
+        )));
+    }
 
-    Ok(Config { filename, mode })
+    Ok(Config {
+        filename,
+        mode,
+        query_params,
+        canonicalize,
+        depth,
+        top,
+        sort,
+        max_routes,
+        clients,
+        subnets,
+        unique,
+        filter,
+        referrers,
+        own_domains,
+        agents,
+        exclude_bots,
+        exclusions,
+        geoip,
+        asn,
+        log_format,
+        hosts,
+        real_ip,
+        fields,
+        output,
+        header,
+        follow,
+        interval,
+        listen,
+        metrics_file,
+        statsd,
+        dogstatsd,
+        sqlite,
+    })
+}
+
+// Find the trusted proxies, and check that the log format records the address they report
+fn find_real_ip_and_remove(
+    args: &mut Vec<String>,
+    log_format: Option<&LogFormat>,
+) -> Result<Option<RealIp>, Error> {
+    let trusted = find_named_and_remove(args, "--set-real-ip-from");
+    let header_name = find_named_and_remove(args, "--real-ip-header");
+    let recursive = find_flag_and_remove(args, "--real-ip-recursive").is_some();
+
+    let trusted = match trusted {
+        Some(trusted) => trusted
+            .split(',')
+            .map(|cidr| cidr.trim().parse())
+            .collect::<Result<Vec<Cidr>, Error>>()?,
+        // The other options don't mean anything without a proxy to trust
+        None if header_name.is_some() || recursive => return Err(Error::InvalidArgs),
+        None => return Ok(None),
+    };
+    let header_name = header_name.unwrap_or_else(|| String::from("http_x_forwarded_for"));
+    let header = RealIpHeader::from_str(&header_name)?;
+    if !log_format.is_some_and(|format| format.has_variable(&header_name)) {
+        return Err(Error::InvalidArgs);
+    }
+
+    Ok(Some(RealIp {
+        trusted,
+        header,
+        recursive,
+    }))
+}
+
+// Find the fields for convert, and check that the databases they need were given. By default,
+// the location columns are added for each database.
+fn find_fields_and_remove(
+    args: &mut Vec<String>,
+    geoip: bool,
+    asn: bool,
+) -> Result<Vec<Field>, Error> {
+    let fields = match find_named_and_remove(args, "--fields") {
+        Some(list) => Field::parse_list(&list)
+            .map_err(|message| Error::InvalidValue(format!("Invalid --fields: {}", message)))?,
+        None => {
+            let mut fields = DEFAULT_FIELDS.to_vec();
+            if geoip {
+                fields.push(Field::Country);
+            }
+            if asn {
+                fields.extend([Field::Asn, Field::Organization]);
+            }
+            return Ok(fields);
+        }
+    };
+    for field in &fields {
+        let needs = match field {
+            field if field.needs_country() && !geoip => "--geoip",
+            field if field.needs_asn() && !asn => "--asn",
+            _ => continue,
+        };
+        return Err(Error::InvalidValue(format!(
+            "Invalid --fields: {} needs {}",
+            field.name(),
+            needs
+        )));
+    }
+    Ok(fields)
+}
+
+// Find the exclusion rules, each of which can be given more than once
+fn find_exclusions_and_remove(args: &mut Vec<String>) -> Result<Option<Exclusions>, Error> {
+    let mut exclusions = Exclusions::default();
+    let invalid = |flag: &str, value: &str, message: String| {
+        Error::InvalidValue(format!("Invalid {} '{}': {}", flag, value, message))
+    };
+    while let Some(glob) = find_named_and_remove(args, "--exclude-path") {
+        exclusions
+            .add_glob(&glob)
+            .map_err(|message| invalid("--exclude-path", &glob, message))?;
+    }
+    while let Some(pattern) = find_named_and_remove(args, "--exclude-regex") {
+        exclusions
+            .add_regex(&pattern)
+            .map_err(|message| invalid("--exclude-regex", &pattern, message))?;
+    }
+    while let Some(extensions) = find_named_and_remove(args, "--exclude-ext") {
+        extensions
+            .split(',')
+            .for_each(|extension| exclusions.add_extension(extension.trim()));
+    }
+    while let Some(pattern) = find_named_and_remove(args, "--exclude-ua") {
+        exclusions
+            .add_agent(&pattern)
+            .map_err(|message| invalid("--exclude-ua", &pattern, message))?;
+    }
+    while let Some(presets) = find_named_and_remove(args, "--exclude-preset") {
+        for preset in presets.split(',') {
+            exclusions
+                .add_preset(preset.trim())
+                .map_err(|message| invalid("--exclude-preset", &presets, message))?;
+        }
+    }
+    Ok((!exclusions.is_empty()).then_some(exclusions))
+}
+
+// Find a network prefix length, and check that it's valid for the address family
+fn find_prefix_and_remove(
+    args: &mut Vec<String>,
+    flag: &'static str,
+    max: u8,
+) -> Result<Option<u8>, Error> {
+    match find_named_and_remove(args, flag).map(|prefix| prefix.parse::<u8>()) {
+        Some(Ok(prefix)) if prefix <= max => Ok(Some(prefix)),
+        Some(_) => Err(Error::InvalidArgs),
+        None => Ok(None),
+    }
 }
 
 fn find_flag_and_remove(args: &mut Vec<String>, flag: &'static str) -> Option<String> {
@@ -109,6 +615,499 @@ mod tests {
         assert_eq!(config.filename, Some("access.log".to_string()));
     }
 
+    #[test]
+    fn query_params_flag() {
+        let mut args = vec![
+            "dashlight".to_string(),
+            "--query-params".into(),
+            "watch".into(),
+        ];
+        let config = parse_args(&mut args).unwrap();
+        assert!(config.query_params);
+        assert_eq!(config.mode, Mode::WATCH);
+    }
+
+    #[test]
+    fn depth_is_parsed() {
+        let mut args = vec![
+            "dashlight".to_string(),
+            "watch".into(),
+            "--depth".into(),
+            "2".into(),
+        ];
+        let config = parse_args(&mut args).unwrap();
+        assert_eq!(config.depth, Some(2));
+    }
+
+    #[test]
+    fn invalid_depth_is_an_error() {
+        let mut args = vec![
+            "dashlight".to_string(),
+            "watch".into(),
+            "--depth".into(),
+            "two".into(),
+        ];
+        assert_eq!(parse_args(&mut args).unwrap_err(), Error::InvalidArgs);
+    }
+
+    #[test]
+    fn top_and_sort_defaults() {
+        let mut args = vec!["dashlight".to_string(), "watch".into()];
+        let config = parse_args(&mut args).unwrap();
+        assert_eq!(config.top, 10);
+        assert_eq!(config.sort, SortKey::Total);
+    }
+
+    #[test]
+    fn top_and_sort_are_parsed() {
+        let mut args = vec![
+            "dashlight".to_string(),
+            "--top".into(),
+            "25".into(),
+            "--sort".into(),
+            "error-rate".into(),
+            "watch".into(),
+        ];
+        let config = parse_args(&mut args).unwrap();
+        assert_eq!(config.top, 25);
+        assert_eq!(config.sort, SortKey::ErrorRate);
+    }
+
+    #[test]
+    fn max_routes_is_parsed() {
+        let mut args = vec![
+            "dashlight".to_string(),
+            "watch".into(),
+            "--max-routes".into(),
+            "1000".into(),
+        ];
+        let config = parse_args(&mut args).unwrap();
+        assert_eq!(config.max_routes, Some(1000));
+    }
+
+    #[test]
+    fn subnets_default_the_other_family() {
+        let mut args = vec![
+            "dashlight".to_string(),
+            "watch".into(),
+            "--subnet-v6".into(),
+            "48".into(),
+        ];
+        let config = parse_args(&mut args).unwrap();
+        assert_eq!(config.subnets, Some((24, 48)));
+    }
+
+    #[test]
+    fn invalid_subnet_is_an_error() {
+        let mut args = vec![
+            "dashlight".to_string(),
+            "watch".into(),
+            "--subnet-v4".into(),
+            "33".into(),
+        ];
+        assert_eq!(parse_args(&mut args).unwrap_err(), Error::InvalidArgs);
+    }
+
+    #[test]
+    fn unique_key_is_parsed() {
+        let mut args = vec![
+            "dashlight".to_string(),
+            "watch".into(),
+            "--unique".into(),
+            "ip+ua".into(),
+        ];
+        let config = parse_args(&mut args).unwrap();
+        assert_eq!(config.unique, Some(UniqueKey::IpAndAgent));
+        assert_eq!(config.max_routes, Some(DEFAULT_MAX_ROUTES));
+
+        let mut args = vec![
+            "dashlight".to_string(),
+            "watch".into(),
+            "--unique".into(),
+            "ip".into(),
+            "--max-routes".into(),
+            "50".into(),
+        ];
+        assert_eq!(parse_args(&mut args).unwrap().max_routes, Some(50));
+
+        let mut args = vec![
+            "dashlight".to_string(),
+            "watch".into(),
+            "--unique".into(),
+            "cookie".into(),
+        ];
+        assert_eq!(parse_args(&mut args).unwrap_err(), Error::InvalidArgs);
+    }
+
+    #[test]
+    fn invalid_filter_has_a_message() {
+        let mut args = vec![
+            "dashlight".to_string(),
+            "watch".into(),
+            "--where".into(),
+            "status >=".into(),
+        ];
+        assert_eq!(
+            parse_args(&mut args).unwrap_err(),
+            Error::InvalidValue(String::from(
+                "Invalid --where expression: expected a value after '>=', found end of expression"
+            ))
+        );
+    }
+
+    #[test]
+    fn own_hosts_are_normalized() {
+        let mut args = vec![
+            "dashlight".to_string(),
+            "watch".into(),
+            "--referrers".into(),
+            "--own-host".into(),
+            "www.Example.com, example.org".into(),
+        ];
+        let config = parse_args(&mut args).unwrap();
+        assert!(config.referrers);
+        assert_eq!(config.own_domains, vec!["example.com", "example.org"]);
+    }
+
+    #[test]
+    fn agent_flags() {
+        let mut args = vec![
+            "dashlight".to_string(),
+            "--exclude-bots".into(),
+            "watch".into(),
+        ];
+        let config = parse_args(&mut args).unwrap();
+        assert!(config.exclude_bots);
+        assert!(!config.agents);
+    }
+
+    #[test]
+    fn exclusion_flags_repeat() {
+        let mut args = vec![
+            "dashlight".to_string(),
+            "--exclude-path".into(),
+            "/static/**".into(),
+            "--exclude-path".into(),
+            "/favicon.ico".into(),
+            "--exclude-ext".into(),
+            "css,.js".into(),
+            "watch".into(),
+        ];
+        let config = parse_args(&mut args).unwrap();
+        assert!(config.exclusions.is_some());
+        assert_eq!(args, vec!["dashlight", "watch"]);
+
+        let mut args = vec!["dashlight".to_string(), "watch".into()];
+        assert!(parse_args(&mut args).unwrap().exclusions.is_none());
+    }
+
+    #[test]
+    fn fields_default_to_location_columns() {
+        let mut args = vec![
+            "dashlight".to_string(),
+            "convert".into(),
+            "--asn".into(),
+            "asn.mmdb".into(),
+        ];
+        let config = parse_args(&mut args).unwrap();
+        assert_eq!(
+            config.fields,
+            vec![
+                Field::Ip,
+                Field::Time,
+                Field::Method,
+                Field::Url,
+                Field::Status,
+                Field::Asn,
+                Field::Organization
+            ]
+        );
+    }
+
+    #[test]
+    fn fields_need_their_databases() {
+        let mut args = vec![
+            "dashlight".to_string(),
+            "convert".into(),
+            "--fields".into(),
+            "ip,country".into(),
+        ];
+        assert_eq!(
+            parse_args(&mut args).unwrap_err(),
+            Error::InvalidValue(String::from("Invalid --fields: country needs --geoip"))
+        );
+    }
+
+    #[test]
+    fn watch_only_writes_json() {
+        let mut args = vec![
+            "dashlight".to_string(),
+            "watch".into(),
+            "--output".into(),
+            "json".into(),
+        ];
+        assert_eq!(parse_args(&mut args).unwrap().output, OutputFormat::Json);
+
+        let mut args = vec![
+            "dashlight".to_string(),
+            "watch".into(),
+            "--output".into(),
+            "csv".into(),
+        ];
+        assert_eq!(
+            parse_args(&mut args).unwrap_err(),
+            Error::InvalidValue(String::from(
+                "Invalid --output: csv and tsv only apply to convert"
+            ))
+        );
+    }
+
+    #[test]
+    fn listen_needs_follow() {
+        let mut args = vec![
+            "dashlight".to_string(),
+            "watch".into(),
+            "--follow".into(),
+            "--listen".into(),
+            "127.0.0.1:9898".into(),
+            "--interval".into(),
+            "2".into(),
+        ];
+        let config = parse_args(&mut args).unwrap();
+        assert_eq!(config.listen, Some("127.0.0.1:9898".parse().unwrap()));
+        assert_eq!(config.interval, Duration::from_secs(2));
+        assert!(config.metrics());
+        // An exporter runs for as long as the log does, so its memory is bounded by default
+        assert_eq!(config.max_routes, Some(DEFAULT_MAX_ROUTES));
+
+        let mut args = vec![
+            "dashlight".to_string(),
+            "watch".into(),
+            "--listen".into(),
+            "127.0.0.1:9898".into(),
+        ];
+        assert_eq!(
+            parse_args(&mut args).unwrap_err(),
+            Error::InvalidValue(String::from("--listen only applies to watch --follow"))
+        );
+
+        let mut args = vec![
+            "dashlight".to_string(),
+            "watch".into(),
+            "--follow".into(),
+            "--listen".into(),
+            "localhost".into(),
+        ];
+        assert_eq!(parse_args(&mut args).unwrap_err(), Error::InvalidArgs);
+    }
+
+    #[test]
+    fn metrics_file_needs_watch() {
+        let mut args = vec![
+            "dashlight".to_string(),
+            "watch".into(),
+            "--metrics-file".into(),
+            "dashlight.prom".into(),
+        ];
+        let config = parse_args(&mut args).unwrap();
+        assert_eq!(config.metrics_file, Some(PathBuf::from("dashlight.prom")));
+        assert!(config.metrics());
+
+        let mut args = vec![
+            "dashlight".to_string(),
+            "convert".into(),
+            "--metrics-file".into(),
+            "dashlight.prom".into(),
+        ];
+        assert_eq!(
+            parse_args(&mut args).unwrap_err(),
+            Error::InvalidValue(String::from("--metrics-file only applies to watch"))
+        );
+    }
+
+    #[test]
+    fn statsd_is_parsed() {
+        let mut args = vec![
+            "dashlight".to_string(),
+            "watch".into(),
+            "--statsd".into(),
+            "127.0.0.1:8125".into(),
+            "--dogstatsd".into(),
+        ];
+        let config = parse_args(&mut args).unwrap();
+        assert_eq!(config.statsd, Some("127.0.0.1:8125".parse().unwrap()));
+        assert!(config.dogstatsd);
+
+        let mut args = vec![
+            "dashlight".to_string(),
+            "watch".into(),
+            "--dogstatsd".into(),
+        ];
+        assert_eq!(
+            parse_args(&mut args).unwrap_err(),
+            Error::InvalidValue(String::from("--dogstatsd needs --statsd"))
+        );
+
+        let mut args = vec![
+            "dashlight".to_string(),
+            "convert".into(),
+            "--statsd".into(),
+            "127.0.0.1:8125".into(),
+        ];
+        assert_eq!(
+            parse_args(&mut args).unwrap_err(),
+            Error::InvalidValue(String::from("--statsd only applies to watch"))
+        );
+    }
+
+    #[test]
+    fn influx_has_a_fixed_schema() {
+        let mut args = vec![
+            "dashlight".to_string(),
+            "watch".into(),
+            "--output".into(),
+            "influx".into(),
+        ];
+        let config = parse_args(&mut args).unwrap();
+        assert_eq!(config.output, OutputFormat::Influx);
+        assert!(config.intervals());
+
+        let mut args = vec![
+            "dashlight".to_string(),
+            "convert".into(),
+            "--output".into(),
+            "influx".into(),
+            "--fields".into(),
+            "ip".into(),
+        ];
+        assert_eq!(
+            parse_args(&mut args).unwrap_err(),
+            Error::InvalidValue(String::from(
+                "--fields doesn't apply to influx, which has fixed tags and fields"
+            ))
+        );
+    }
+
+    #[test]
+    fn export_needs_sqlite() {
+        let mut args = vec![
+            "dashlight".to_string(),
+            "export".into(),
+            "--sqlite".into(),
+            "out.db".into(),
+        ];
+        let config = parse_args(&mut args).unwrap();
+        assert_eq!(config.mode, Mode::EXPORT);
+        assert_eq!(config.sqlite, Some(PathBuf::from("out.db")));
+
+        let message = "export needs --sqlite, which only applies to export";
+        let mut args = vec!["dashlight".to_string(), "export".into()];
+        assert_eq!(
+            parse_args(&mut args).unwrap_err(),
+            Error::InvalidValue(String::from(message))
+        );
+        let mut args = vec![
+            "dashlight".to_string(),
+            "watch".into(),
+            "--sqlite".into(),
+            "out.db".into(),
+        ];
+        assert_eq!(
+            parse_args(&mut args).unwrap_err(),
+            Error::InvalidValue(String::from(message))
+        );
+    }
+
+    #[test]
+    fn invalid_exclusions_have_a_message() {
+        let mut args = vec![
+            "dashlight".to_string(),
+            "watch".into(),
+            "--exclude-preset".into(),
+            "static,fonts".into(),
+        ];
+        assert_eq!(
+            parse_args(&mut args).unwrap_err(),
+            Error::InvalidValue(String::from(
+                "Invalid --exclude-preset 'static,fonts': unknown preset 'fonts', expected static or health"
+            ))
+        );
+    }
+
+    #[test]
+    fn geoip_databases_are_parsed() {
+        let mut args = vec![
+            "dashlight".to_string(),
+            "--asn".into(),
+            "GeoLite2-ASN.mmdb".into(),
+            "convert".into(),
+        ];
+        let config = parse_args(&mut args).unwrap();
+        assert_eq!(config.geoip, None);
+        assert_eq!(config.asn, Some("GeoLite2-ASN.mmdb".to_string()));
+        assert_eq!(config.mode, Mode::CONVERT);
+    }
+
+    #[test]
+    fn log_format_is_compiled() {
+        let mut args = vec![
+            "dashlight".to_string(),
+            "watch".into(),
+            "--log-format".into(),
+            "$remote_addr \"$request\" $status".into(),
+        ];
+        let config = parse_args(&mut args).unwrap();
+        assert!(!config.hosts);
+        assert!(config.log_format.unwrap().has_variable("status"));
+
+        let mut args = vec![
+            "dashlight".to_string(),
+            "watch".into(),
+            "--log-format".into(),
+            "combined".into(),
+        ];
+        assert_eq!(parse_args(&mut args).unwrap().log_format, None);
+    }
+
+    #[test]
+    fn real_ip_is_parsed() {
+        let mut args = vec![
+            "dashlight".to_string(),
+            "watch".into(),
+            "--log-format".into(),
+            "$remote_addr \"$http_x_forwarded_for\" $status".into(),
+            "--set-real-ip-from".into(),
+            "10.0.0.0/8, 192.168.0.1".into(),
+            "--real-ip-recursive".into(),
+        ];
+        let real_ip = parse_args(&mut args).unwrap().real_ip.unwrap();
+        assert_eq!(real_ip.trusted.len(), 2);
+        assert_eq!(real_ip.header, RealIpHeader::XForwardedFor);
+        assert!(real_ip.recursive);
+    }
+
+    #[test]
+    fn real_ip_needs_header_in_log_format() {
+        let mut args = vec![
+            "dashlight".to_string(),
+            "watch".into(),
+            "--set-real-ip-from".into(),
+            "10.0.0.0/8".into(),
+        ];
+        assert_eq!(parse_args(&mut args).unwrap_err(), Error::InvalidArgs);
+    }
+
+    #[test]
+    fn invalid_sort_is_an_error() {
+        let mut args = vec![
+            "dashlight".to_string(),
+            "watch".into(),
+            "--sort".into(),
+            "name".into(),
+        ];
+        assert_eq!(parse_args(&mut args).unwrap_err(), Error::InvalidArgs);
+    }
+
     #[test]
     #[should_panic(expected = "Missing value after -f")]
     fn invalid_args_missing_filename_after_f() {
@@ -0,0 +1,182 @@
+use crate::parsers::nginx::NginxCombinedLog;
+use crate::regex::Regex;
+
+// File extensions for the "static" preset: stylesheets, scripts, images, and fonts
+const STATIC_EXTENSIONS: &[&str] = &[
+    "css", "js", "mjs", "map", "png", "jpg", "jpeg", "gif", "svg", "ico", "webp", "avif", "woff",
+    "woff2", "ttf", "otf", "eot",
+];
+// Paths and user agents for the "health" preset, covering common load balancer probes
+const HEALTH_PATHS: &[&str] = &[
+    "/health",
+    "/healthz",
+    "/healthcheck",
+    "/livez",
+    "/readyz",
+    "/ping",
+];
+const HEALTH_AGENTS: &str = "^(ELB-HealthChecker|kube-probe|GoogleHC|Consul Health Check)";
+
+// Rules for leaving requests out of the report, such as static assets and health checks.
+// A request is excluded if any one rule matches it.
+#[derive(Debug, Default)]
+pub struct Exclusions {
+    // Globs and regexes are both compiled to regexes on the path
+    paths: Vec<Regex>,
+    extensions: Vec<String>,
+    agents: Vec<Regex>,
+}
+
+impl Exclusions {
+    // Globs match the whole path: `*` matches within a segment and `**` across segments
+    pub fn add_glob(&mut self, glob: &str) -> Result<(), String> {
+        self.paths.push(Regex::new(&glob_to_regex(glob))?);
+        Ok(())
+    }
+
+    pub fn add_regex(&mut self, pattern: &str) -> Result<(), String> {
+        self.paths.push(Regex::new(pattern)?);
+        Ok(())
+    }
+
+    pub fn add_extension(&mut self, extension: &str) {
+        let extension = extension.trim_start_matches('.').to_ascii_lowercase();
+        if !extension.is_empty() {
+            self.extensions.push(extension);
+        }
+    }
+
+    pub fn add_agent(&mut self, pattern: &str) -> Result<(), String> {
+        self.agents.push(Regex::new(pattern)?);
+        Ok(())
+    }
+
+    pub fn add_preset(&mut self, preset: &str) -> Result<(), String> {
+        match preset {
+            "static" => STATIC_EXTENSIONS
+                .iter()
+                .for_each(|extension| self.add_extension(extension)),
+            "health" => {
+                for path in HEALTH_PATHS {
+                    self.add_glob(path)?;
+                }
+                self.add_agent(HEALTH_AGENTS)?;
+            }
+            _ => {
+                return Err(format!(
+                    "unknown preset '{}', expected static or health",
+                    preset
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty() && self.extensions.is_empty() && self.agents.is_empty()
+    }
+
+    pub fn matches(&self, log: &NginxCombinedLog) -> bool {
+        if let Some(path) = log.request_path {
+            if self.paths.iter().any(|regex| regex.is_match(path)) {
+                return true;
+            }
+            let extension = path
+                .rsplit('/')
+                .next()
+                .and_then(|name| name.rsplit_once('.'))
+                .map(|x| x.1);
+            if let Some(extension) = extension {
+                if self
+                    .extensions
+                    .iter()
+                    .any(|x| x.eq_ignore_ascii_case(extension))
+                {
+                    return true;
+                }
+            }
+        }
+        self.agents
+            .iter()
+            .any(|regex| regex.is_match(log.http_user_agent))
+    }
+}
+
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                regex.push_str(".*");
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            c if c.is_ascii_alphanumeric() || c == '/' || c == '_' || c == '-' => regex.push(c),
+            c => {
+                regex.push('\\');
+                regex.push(c);
+            }
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log<'a>(path: &'a str, user_agent: &'a str) -> NginxCombinedLog<'a> {
+        NginxCombinedLog {
+            request_path: Some(path),
+            http_user_agent: user_agent,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn globs_match_whole_paths() {
+        let mut exclusions = Exclusions::default();
+        exclusions.add_glob("/static/*.js").unwrap();
+        exclusions.add_glob("/assets/**").unwrap();
+        exclusions.add_glob("/favicon.ico").unwrap();
+
+        assert!(exclusions.matches(&log("/static/app.js", "")));
+        assert!(!exclusions.matches(&log("/static/js/app.js", "")));
+        assert!(!exclusions.matches(&log("/static/app.json", "")));
+        assert!(exclusions.matches(&log("/assets/img/logo.png", "")));
+        assert!(exclusions.matches(&log("/favicon.ico", "")));
+        assert!(!exclusions.matches(&log("/favicon1ico", "")));
+        assert!(!exclusions.matches(&log("/api/static/app.js", "")));
+    }
+
+    #[test]
+    fn extensions_ignore_case() {
+        let mut exclusions = Exclusions::default();
+        exclusions.add_extension(".png");
+        assert!(exclusions.matches(&log("/img/LOGO.PNG", "")));
+        assert!(!exclusions.matches(&log("/png", "")));
+        assert!(!exclusions.matches(&log("/img.png/view", "")));
+    }
+
+    #[test]
+    fn presets() {
+        let mut exclusions = Exclusions::default();
+        exclusions.add_preset("static").unwrap();
+        exclusions.add_preset("health").unwrap();
+
+        assert!(exclusions.matches(&log("/static/app.css", "")));
+        assert!(exclusions.matches(&log("/healthz", "")));
+        assert!(exclusions.matches(&log("/", "ELB-HealthChecker/2.0")));
+        assert!(!exclusions.matches(&log("/api/health/report", "")));
+        assert!(!exclusions.matches(&log("/", "curl/7.81.0")));
+        assert_eq!(
+            exclusions.add_preset("images"),
+            Err(String::from(
+                "unknown preset 'images', expected static or health"
+            ))
+        );
+    }
+}
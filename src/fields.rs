@@ -0,0 +1,492 @@
+use crate::agents::Agent;
+use crate::geoip::Location;
+use crate::influx;
+use crate::json;
+use crate::parsers::nginx::{self, NginxCombinedLog};
+use crate::parsers::url;
+use crate::Error;
+use std::fmt::{self, Write};
+use std::str::FromStr;
+
+// The columns that `convert` can write, either parsed from the log line or derived from it
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Field {
+    Ip,
+    User,
+    Time,
+    Method,
+    Url,
+    Path,
+    Query,
+    Request,
+    Status,
+    Bytes,
+    Referer,
+    UserAgent,
+    RequestTime,
+    Host,
+    // Derived from the user agent and referer
+    Agent,
+    ReferrerDomain,
+    // Looked up in the GeoIP databases
+    Country,
+    Asn,
+    Organization,
+}
+
+const FIELDS: &[(&str, Field)] = &[
+    ("ip", Field::Ip),
+    ("user", Field::User),
+    ("time", Field::Time),
+    ("method", Field::Method),
+    ("url", Field::Url),
+    ("path", Field::Path),
+    ("query", Field::Query),
+    ("request", Field::Request),
+    ("status", Field::Status),
+    ("bytes", Field::Bytes),
+    ("referer", Field::Referer),
+    ("ua", Field::UserAgent),
+    ("request_time", Field::RequestTime),
+    ("host", Field::Host),
+    ("agent", Field::Agent),
+    ("referrer_domain", Field::ReferrerDomain),
+    ("country", Field::Country),
+    ("asn", Field::Asn),
+    ("org", Field::Organization),
+];
+
+// What `convert` writes when no fields are chosen
+pub const DEFAULT_FIELDS: &[Field] = &[
+    Field::Ip,
+    Field::Time,
+    Field::Method,
+    Field::Url,
+    Field::Status,
+];
+
+impl FromStr for Field {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        FIELDS
+            .iter()
+            .find(|(name, _)| *name == s)
+            .map(|(_, field)| *field)
+            .ok_or_else(|| {
+                let names: Vec<&str> = FIELDS.iter().map(|(name, _)| *name).collect();
+                format!(
+                    "unknown field '{}', expected one of {}",
+                    s,
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+impl Field {
+    // Parse a comma-separated list of fields, such as "ip,time,status"
+    pub fn parse_list(list: &str) -> Result<Vec<Field>, String> {
+        list.split(',').map(|name| name.trim().parse()).collect()
+    }
+
+    pub fn name(&self) -> &'static str {
+        FIELDS
+            .iter()
+            .find(|(_, field)| field == self)
+            .map(|(name, _)| *name)
+            .unwrap()
+    }
+
+    pub fn needs_country(&self) -> bool {
+        *self == Field::Country
+    }
+
+    pub fn needs_asn(&self) -> bool {
+        matches!(self, Field::Asn | Field::Organization)
+    }
+
+    // Write the value of this field, leaving it empty if the line didn't have one. Addresses
+    // that couldn't be looked up are written as "-".
+    pub fn write(
+        &self,
+        f: &mut impl fmt::Write,
+        log: &NginxCombinedLog,
+        location: &Location,
+    ) -> fmt::Result {
+        match self {
+            Field::Ip => f.write_str(log.remote_addr),
+            Field::User => f.write_str(log.remote_user),
+            Field::Time => f.write_str(log.time_local),
+            Field::Method => f.write_str(log.method.map_or("", |method| method.as_str())),
+            Field::Url => f.write_str(log.request_url.unwrap_or("")),
+            Field::Path => f.write_str(log.request_path.unwrap_or("")),
+            Field::Query => f.write_str(log.request_query.unwrap_or("")),
+            Field::Request => f.write_str(log.request),
+            Field::Status => write!(f, "{}", log.status),
+            Field::Bytes => write!(f, "{}", log.body_bytes_sent),
+            Field::Referer => f.write_str(log.http_referer),
+            Field::UserAgent => f.write_str(log.http_user_agent),
+            Field::RequestTime => match log.request_time {
+                Some(time) => write!(f, "{}", time),
+                None => Ok(()),
+            },
+            Field::Host => f.write_str(log.host),
+            Field::Agent => f.write_str(Agent::classify(log.http_user_agent).name()),
+            Field::ReferrerDomain => match url::referrer_domain(log.http_referer) {
+                Some(domain) => f.write_str(&domain),
+                None => Ok(()),
+            },
+            Field::Country => f.write_str(location.country.unwrap_or("-")),
+            Field::Asn => match &location.asn {
+                Some(asn) => write!(f, "{}", asn.number),
+                None => f.write_str("-"),
+            },
+            Field::Organization => {
+                f.write_str(location.asn.as_ref().map_or("-", |x| x.organization))
+            }
+        }
+    }
+
+    // Write the value of this field as JSON. Numbers are typed, times are ISO 8601, nginx's
+    // "\xHH" escapes are decoded, and anything missing (including nginx's "-") is null.
+    pub fn write_json(&self, out: &mut String, log: &NginxCombinedLog, location: &Location) {
+        let text = match self {
+            Field::Ip => Some(log.remote_addr),
+            Field::User => Some(log.remote_user),
+            Field::Method => log.method.map(|method| method.as_str()),
+            Field::Url => log.request_url,
+            Field::Path => log.request_path,
+            Field::Query => log.request_query,
+            Field::Request => Some(log.request),
+            Field::Referer => Some(log.http_referer),
+            Field::UserAgent => Some(log.http_user_agent),
+            Field::Host => Some(log.host),
+            Field::Agent => Some(Agent::classify(log.http_user_agent).name()),
+            Field::Country => location.country,
+            Field::Organization => location.asn.as_ref().map(|asn| asn.organization),
+            Field::Time => {
+                match nginx::iso_8601(log.time_local) {
+                    Some(time) => json::push_string(out, &time),
+                    None => out.push_str("null"),
+                }
+                return;
+            }
+            Field::ReferrerDomain => {
+                match url::referrer_domain(log.http_referer) {
+                    Some(domain) => json::push_string(out, &domain),
+                    None => out.push_str("null"),
+                }
+                return;
+            }
+            Field::Status => {
+                write!(out, "{}", log.status).unwrap();
+                return;
+            }
+            Field::Bytes => {
+                write!(out, "{}", log.body_bytes_sent).unwrap();
+                return;
+            }
+            Field::RequestTime => {
+                match log.request_time {
+                    Some(time) => json::push_f64(out, time),
+                    None => out.push_str("null"),
+                }
+                return;
+            }
+            Field::Asn => {
+                match &location.asn {
+                    Some(asn) => write!(out, "{}", asn.number).unwrap(),
+                    None => out.push_str("null"),
+                }
+                return;
+            }
+        };
+        match text {
+            Some("" | "-") | None => out.push_str("null"),
+            Some(text) => json::push_string(out, &nginx::unescape(text)),
+        }
+    }
+}
+
+// How convert separates and quotes fields
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum OutputFormat {
+    // Separated by `"`, which nginx always escapes, so fields never need quoting
+    Quoted,
+    Csv,
+    Tsv,
+    // One JSON object per line (NDJSON)
+    Json,
+    // InfluxDB line protocol, one point per line
+    Influx,
+}
+
+impl FromStr for OutputFormat {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "quoted" => Ok(OutputFormat::Quoted),
+            "csv" => Ok(OutputFormat::Csv),
+            "tsv" => Ok(OutputFormat::Tsv),
+            "json" => Ok(OutputFormat::Json),
+            "influx" => Ok(OutputFormat::Influx),
+            _ => Err(Error::InvalidArgs),
+        }
+    }
+}
+
+impl OutputFormat {
+    fn delimiter(&self) -> char {
+        match self {
+            OutputFormat::Quoted => '"',
+            OutputFormat::Csv => ',',
+            OutputFormat::Tsv => '\t',
+            // Only used between the members of each object
+            OutputFormat::Json => ',',
+            // Points have no columns, so this isn't used
+            OutputFormat::Influx => ' ',
+        }
+    }
+}
+
+// Formats log lines as records of the chosen fields, reusing its buffers between lines
+pub struct Converter<'a> {
+    fields: &'a [Field],
+    format: OutputFormat,
+    line: String,
+    value: String,
+}
+
+impl<'a> Converter<'a> {
+    pub fn new(fields: &'a [Field], format: OutputFormat) -> Converter<'a> {
+        Converter {
+            fields,
+            format,
+            line: String::new(),
+            value: String::new(),
+        }
+    }
+
+    // A row of field names
+    pub fn header(&mut self) -> &str {
+        self.line.clear();
+        for (index, field) in self.fields.iter().enumerate() {
+            if index > 0 {
+                self.line.push(self.format.delimiter());
+            }
+            self.line.push_str(field.name());
+        }
+        &self.line
+    }
+
+    pub fn record(&mut self, log: &NginxCombinedLog, location: &Location) -> &str {
+        self.line.clear();
+        if self.format == OutputFormat::Influx {
+            influx::push_request(&mut self.line, log, location);
+            return &self.line;
+        }
+        if self.format == OutputFormat::Json {
+            self.line.push('{');
+            for (index, field) in self.fields.iter().enumerate() {
+                if index > 0 {
+                    self.line.push(self.format.delimiter());
+                }
+                json::push_string(&mut self.line, field.name());
+                self.line.push(':');
+                field.write_json(&mut self.line, log, location);
+            }
+            self.line.push('}');
+            return &self.line;
+        }
+        for (index, field) in self.fields.iter().enumerate() {
+            if index > 0 {
+                self.line.push(self.format.delimiter());
+            }
+            if self.format == OutputFormat::Quoted {
+                // Writing to a String can't fail
+                field.write(&mut self.line, log, location).unwrap();
+                continue;
+            }
+            self.value.clear();
+            field.write(&mut self.value, log, location).unwrap();
+            push_escaped(&mut self.line, &self.value, self.format.delimiter());
+        }
+        &self.line
+    }
+}
+
+// Quote a value if it contains the delimiter, a quote or a line break, doubling any quotes
+// inside it (RFC 4180). Tab-separated values are quoted the same way.
+fn push_escaped(line: &mut String, value: &str, delimiter: char) {
+    if !value.contains([delimiter, '"', '\r', '\n']) {
+        line.push_str(value);
+        return;
+    }
+    line.push('"');
+    for c in value.chars() {
+        if c == '"' {
+            line.push('"');
+        }
+        line.push(c);
+    }
+    line.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geoip::Asn;
+    use crate::parsers::nginx;
+
+    const LINE: &str = r#"203.0.113.9 - alice [09/May/2022:00:00:01 +0000] "GET /search?q=rust HTTP/1.1" 200 512 "https://www.Example.com/" "curl/7.81.0""#;
+
+    #[test]
+    fn parses_lists() {
+        assert_eq!(
+            Field::parse_list("ip, status,ua"),
+            Ok(vec![Field::Ip, Field::Status, Field::UserAgent])
+        );
+        assert!(Field::parse_list("ip,,status").is_err());
+        assert!(Field::parse_list("ip,cookie")
+            .unwrap_err()
+            .starts_with("unknown field 'cookie', expected one of ip, user, time"));
+        assert_eq!(Field::Organization.name(), "org");
+    }
+
+    // Splits delimited text into rows of fields, undoing RFC 4180 quoting
+    fn read_delimited(text: &str, delimiter: char) -> Vec<Vec<String>> {
+        let mut rows = vec![];
+        let mut row = vec![];
+        let mut field = String::new();
+        let mut quoted = false;
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' if quoted && chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' => quoted = !quoted,
+                c if c == delimiter && !quoted => row.push(std::mem::take(&mut field)),
+                '\n' if !quoted => {
+                    row.push(std::mem::take(&mut field));
+                    rows.push(std::mem::take(&mut row));
+                }
+                c => field.push(c),
+            }
+        }
+        rows
+    }
+
+    fn convert(fields: &[Field], format: OutputFormat, lines: &[&str]) -> String {
+        let mut converter = Converter::new(fields, format);
+        let mut output = format!("{}\n", converter.header());
+        for line in lines {
+            let log = nginx::get_log_from_logline(line).unwrap();
+            output.push_str(converter.record(&log, &Location::default()));
+            output.push('\n');
+        }
+        output
+    }
+
+    #[test]
+    fn writes_chosen_fields_in_order() {
+        let fields =
+            Field::parse_list("status,path,query,user,bytes,agent,referrer_domain,request_time")
+                .unwrap();
+        assert_eq!(
+            convert(&fields, OutputFormat::Quoted, &[LINE]),
+            "status\"path\"query\"user\"bytes\"agent\"referrer_domain\"request_time\n\
+             200\"/search\"q=rust\"alice\"512\"curl\"example.com\"\n"
+        );
+    }
+
+    #[test]
+    fn csv_and_tsv_round_trip() {
+        // Commas are common in user agents, and tabs can appear with `escape=none`
+        let lines = [
+            LINE,
+            "198.51.100.7 - - [09/May/2022:00:00:02 +0000] \"GET /a,b?x=1\t2 HTTP/1.1\" 404 0 \"-\" \"Mozilla/5.0 (X11; Linux x86_64) \\x22quoted\\x22\"",
+        ];
+        let fields = Field::parse_list("ip,time,url,status,ua").unwrap();
+        for format in [OutputFormat::Csv, OutputFormat::Tsv] {
+            let rows = read_delimited(&convert(&fields, format, &lines), format.delimiter());
+            assert_eq!(rows[0], vec!["ip", "time", "url", "status", "ua"]);
+            assert_eq!(rows.len(), lines.len() + 1);
+            for (row, line) in rows[1..].iter().zip(lines) {
+                let log = nginx::get_log_from_logline(line).unwrap();
+                let status = log.status.to_string();
+                let expected = vec![
+                    log.remote_addr,
+                    log.time_local,
+                    log.request_url.unwrap(),
+                    &status,
+                    log.http_user_agent,
+                ];
+                assert_eq!(row, &expected);
+            }
+        }
+    }
+
+    #[test]
+    fn quotes_only_when_needed() {
+        let mut line = String::new();
+        push_escaped(&mut line, "plain value", ',');
+        line.push(',');
+        push_escaped(&mut line, "a, \"b\"", ',');
+        line.push(',');
+        push_escaped(&mut line, "tab\there", ',');
+        assert_eq!(line, "plain value,\"a, \"\"b\"\"\",tab\there");
+
+        line.clear();
+        push_escaped(&mut line, "tab\there", '\t');
+        assert_eq!(line, "\"tab\there\"");
+    }
+
+    #[test]
+    fn writes_json() {
+        let line = r#"203.0.113.9 - - [09/May/2022:00:00:01 +0000] "GET /caf\xC3\xA9?q=\x22x\x22 HTTP/1.1" 200 512 "-" "Mozilla/5.0 \x5C o/" 0.125"#;
+        let log = nginx::get_log_from_logline(line).unwrap();
+        let fields =
+            Field::parse_list("ip,time,method,path,query,status,bytes,referer,ua,request_time,asn")
+                .unwrap();
+        let mut converter = Converter::new(&fields, OutputFormat::Json);
+        assert_eq!(
+            converter.record(&log, &Location::default()),
+            r#"{"ip":"203.0.113.9","time":"2022-05-09T00:00:01+00:00","method":"GET","path":"/café","query":"q=\"x\"","status":200,"bytes":512,"referer":null,"ua":"Mozilla/5.0 \\ o/","request_time":0.125,"asn":null}"#
+        );
+
+        let log = nginx::get_log_from_logline(
+            r#"203.0.113.9 - - [09/May/2022:00:00:01 +0000] "\x16\x03\x01" 400 0 "-" "-""#,
+        )
+        .unwrap();
+        let fields = Field::parse_list("method,url,request").unwrap();
+        let mut converter = Converter::new(&fields, OutputFormat::Json);
+        assert_eq!(
+            converter.record(&log, &Location::default()),
+            r#"{"method":null,"url":null,"request":"\u0016\u0003\u0001"}"#
+        );
+    }
+
+    #[test]
+    fn writes_locations() {
+        let log = nginx::get_log_from_logline(LINE).unwrap();
+        let fields = Field::parse_list("ip,country,asn,org").unwrap();
+        let location = Location {
+            country: Some("NL"),
+            asn: Some(Asn {
+                number: 64500,
+                organization: "Example Hosting",
+            }),
+        };
+        let mut converter = Converter::new(&fields, OutputFormat::Csv);
+        assert_eq!(
+            converter.record(&log, &location),
+            "203.0.113.9,NL,64500,Example Hosting"
+        );
+        assert_eq!(
+            converter.record(&log, &Location::default()),
+            "203.0.113.9,-,-,-"
+        );
+    }
+}
@@ -0,0 +1,518 @@
+use crate::net::{self, Cidr};
+use crate::parsers::nginx::NginxCombinedLog;
+use crate::regex::Regex;
+
+// A `--where` expression, such as `status >= 500 && path ~ "^/api" && ip in 10.0.0.0/8`.
+// It's parsed and type-checked once up front, so each line only has to walk the compiled tree.
+//
+//   expr       := and ("||" and)*
+//   and        := unary ("&&" unary)*
+//   unary      := "!" unary | "(" expr ")" | field op value
+//   op         := "==" | "!=" | "<" | "<=" | ">" | ">=" | "~" | "!~" | "in"
+//   value      := number | bareword | "quoted string"
+#[derive(Debug)]
+pub struct Filter {
+    expr: Expr,
+}
+
+#[derive(Debug)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Number(NumberField, Comparison, f64),
+    Equals(TextField, String),
+    Matches(TextField, Regex),
+    In(Vec<Cidr>),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum NumberField {
+    Status,
+    Bytes,
+    RequestTime,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum TextField {
+    Ip,
+    Method,
+    Path,
+    Query,
+    Host,
+    Referer,
+    UserAgent,
+    User,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+enum Field {
+    Number(NumberField),
+    Text(TextField),
+}
+
+impl Field {
+    fn from_name(name: &str) -> Option<Field> {
+        match name {
+            "status" => Some(Field::Number(NumberField::Status)),
+            "bytes" => Some(Field::Number(NumberField::Bytes)),
+            "request_time" => Some(Field::Number(NumberField::RequestTime)),
+            "ip" => Some(Field::Text(TextField::Ip)),
+            "method" => Some(Field::Text(TextField::Method)),
+            "path" => Some(Field::Text(TextField::Path)),
+            "query" => Some(Field::Text(TextField::Query)),
+            "host" => Some(Field::Text(TextField::Host)),
+            "referer" => Some(Field::Text(TextField::Referer)),
+            "ua" => Some(Field::Text(TextField::UserAgent)),
+            "user" => Some(Field::Text(TextField::User)),
+            _ => None,
+        }
+    }
+}
+
+const FIELDS: &str =
+    "status, bytes, request_time, ip, method, path, query, host, referer, ua, user";
+
+impl Filter {
+    pub fn new(expression: &str) -> Result<Filter, String> {
+        let tokens = tokenize(expression)?;
+        let mut parser = Parser {
+            tokens,
+            pos: 0,
+            end: expression.chars().count() + 1,
+        };
+        let expr = parser.or()?;
+        if let Some(token) = parser.tokens.get(parser.pos) {
+            return Err(format!(
+                "unexpected {} at column {}, expected '&&' or '||'",
+                token.kind, token.column
+            ));
+        }
+        Ok(Filter { expr })
+    }
+
+    pub fn matches(&self, log: &NginxCombinedLog) -> bool {
+        self.expr.matches(log)
+    }
+}
+
+impl Expr {
+    fn matches(&self, log: &NginxCombinedLog) -> bool {
+        match self {
+            Expr::And(a, b) => a.matches(log) && b.matches(log),
+            Expr::Or(a, b) => a.matches(log) || b.matches(log),
+            Expr::Not(a) => !a.matches(log),
+            Expr::Number(field, comparison, expected) => {
+                let value = match field {
+                    NumberField::Status => log.status as f64,
+                    NumberField::Bytes => log.body_bytes_sent as f64,
+                    // Lines without a request time never match a comparison against it
+                    NumberField::RequestTime => match log.request_time {
+                        Some(time) => time,
+                        None => return false,
+                    },
+                };
+                match comparison {
+                    Comparison::Eq => value == *expected,
+                    Comparison::Ne => value != *expected,
+                    Comparison::Lt => value < *expected,
+                    Comparison::Le => value <= *expected,
+                    Comparison::Gt => value > *expected,
+                    Comparison::Ge => value >= *expected,
+                }
+            }
+            Expr::Equals(field, expected) => text(*field, log) == expected,
+            Expr::Matches(field, regex) => regex.is_match(text(*field, log)),
+            Expr::In(cidrs) => match net::parse_addr(log.remote_addr) {
+                Some(addr) => cidrs.iter().any(|cidr| cidr.contains(addr)),
+                None => false,
+            },
+        }
+    }
+}
+
+fn text<'a>(field: TextField, log: &NginxCombinedLog<'a>) -> &'a str {
+    match field {
+        TextField::Ip => log.remote_addr,
+        // Methods we don't recognize have no value, so they only match != comparisons
+        TextField::Method => match log.method {
+            Some(method) => method.as_str(),
+            None => "",
+        },
+        TextField::Path => log.request_path.unwrap_or(""),
+        TextField::Query => log.request_query.unwrap_or(""),
+        TextField::Host => log.host,
+        TextField::Referer => log.http_referer,
+        TextField::UserAgent => log.http_user_agent,
+        TextField::User => log.remote_user,
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum TokenKind {
+    Word(String),
+    Quoted(String),
+    Operator(&'static str),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+impl std::fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TokenKind::Word(word) => write!(f, "'{}'", word),
+            TokenKind::Quoted(string) => write!(f, "\"{}\"", string),
+            TokenKind::Operator(op) => write!(f, "'{}'", op),
+            TokenKind::And => write!(f, "'&&'"),
+            TokenKind::Or => write!(f, "'||'"),
+            TokenKind::Not => write!(f, "'!'"),
+            TokenKind::Open => write!(f, "'('"),
+            TokenKind::Close => write!(f, "')'"),
+        }
+    }
+}
+
+struct Token {
+    kind: TokenKind,
+    // 1-based, so it can be shown in error messages
+    column: usize,
+}
+
+// Operators, longest first so that "<=" isn't read as "<" followed by "="
+const OPERATORS: [&str; 8] = ["==", "!=", "<=", ">=", "!~", "<", ">", "~"];
+
+fn tokenize(expression: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = expression.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let column = i + 1;
+        let rest: String = chars[i..chars.len().min(i + 2)].iter().collect();
+        let (kind, len) = match chars[i] {
+            c if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            '(' => (TokenKind::Open, 1),
+            ')' => (TokenKind::Close, 1),
+            _ if rest == "&&" => (TokenKind::And, 2),
+            _ if rest == "||" => (TokenKind::Or, 2),
+            '"' => {
+                // Backslashes escape the next character, so that quotes can appear in strings
+                let mut value = String::new();
+                let mut j = i + 1;
+                loop {
+                    match chars.get(j) {
+                        Some('"') => break,
+                        Some('\\') if j + 1 < chars.len() => {
+                            // Keep escapes that mean something to a regex, like \d or \.
+                            if chars[j + 1] != '"' && chars[j + 1] != '\\' {
+                                value.push('\\');
+                            }
+                            value.push(chars[j + 1]);
+                            j += 2;
+                        }
+                        Some(&c) => {
+                            value.push(c);
+                            j += 1;
+                        }
+                        None => return Err(format!("unclosed quote at column {}", column)),
+                    }
+                }
+                (TokenKind::Quoted(value), j + 1 - i)
+            }
+            _ => match OPERATORS.iter().find(|op| rest.starts_with(**op)) {
+                Some(op) => (TokenKind::Operator(op), op.len()),
+                None if chars[i] == '!' => (TokenKind::Not, 1),
+                None if "&|=".contains(chars[i]) => {
+                    return Err(format!("unexpected '{}' at column {}", chars[i], column))
+                }
+                None => {
+                    let len = chars[i..]
+                        .iter()
+                        .position(|&c| c.is_whitespace() || "()\"!=<>~&|".contains(c))
+                        .unwrap_or(chars.len() - i);
+                    let word: String = chars[i..i + len].iter().collect();
+                    (TokenKind::Word(word), len)
+                }
+            },
+        };
+        tokens.push(Token { kind, column });
+        i += len;
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    // Column just past the end of the expression, for errors about missing tokens
+    end: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&TokenKind> {
+        self.tokens.get(self.pos).map(|token| &token.kind)
+    }
+
+    fn column(&self) -> usize {
+        self.tokens
+            .get(self.pos)
+            .map_or(self.end, |token| token.column)
+    }
+
+    // Describe what was found where something else was expected
+    fn found(&self) -> String {
+        match self.tokens.get(self.pos) {
+            Some(token) => format!("{} at column {}", token.kind, token.column),
+            None => String::from("end of expression"),
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut expr = self.and()?;
+        while self.peek() == Some(&TokenKind::Or) {
+            self.pos += 1;
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut expr = self.unary()?;
+        while self.peek() == Some(&TokenKind::And) {
+            self.pos += 1;
+            expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        match self.peek() {
+            Some(TokenKind::Not) => {
+                self.pos += 1;
+                Ok(Expr::Not(Box::new(self.unary()?)))
+            }
+            Some(TokenKind::Open) => {
+                let column = self.column();
+                self.pos += 1;
+                let expr = self.or()?;
+                if self.peek() != Some(&TokenKind::Close) {
+                    return Err(format!(
+                        "expected ')' to close the '(' at column {}, found {}",
+                        column,
+                        self.found()
+                    ));
+                }
+                self.pos += 1;
+                Ok(expr)
+            }
+            _ => self.comparison(),
+        }
+    }
+
+    fn comparison(&mut self) -> Result<Expr, String> {
+        let name = match self.peek() {
+            Some(TokenKind::Word(name)) => name.clone(),
+            _ => return Err(format!("expected a field, found {}", self.found())),
+        };
+        let field = Field::from_name(&name).ok_or_else(|| {
+            format!(
+                "unknown field '{}' at column {}, expected one of {}",
+                name,
+                self.column(),
+                FIELDS
+            )
+        })?;
+        self.pos += 1;
+
+        let (op, op_column) = match self.peek() {
+            Some(TokenKind::Operator(op)) => (*op, self.column()),
+            Some(TokenKind::Word(word)) if word == "in" => ("in", self.column()),
+            _ => {
+                return Err(format!(
+                    "expected an operator after '{}', found {}",
+                    name,
+                    self.found()
+                ))
+            }
+        };
+        self.pos += 1;
+
+        let (value, value_column) = match self.peek() {
+            Some(TokenKind::Word(value)) | Some(TokenKind::Quoted(value)) => {
+                (value.clone(), self.column())
+            }
+            _ => {
+                return Err(format!(
+                    "expected a value after '{}', found {}",
+                    op,
+                    self.found()
+                ))
+            }
+        };
+        self.pos += 1;
+
+        let unsupported = || {
+            format!(
+                "'{}' can't be used with '{}' at column {}",
+                op, name, op_column
+            )
+        };
+        match field {
+            Field::Number(field) => {
+                let comparison = match op {
+                    "==" => Comparison::Eq,
+                    "!=" => Comparison::Ne,
+                    "<" => Comparison::Lt,
+                    "<=" => Comparison::Le,
+                    ">" => Comparison::Gt,
+                    ">=" => Comparison::Ge,
+                    _ => return Err(unsupported()),
+                };
+                let number = value.parse().map_err(|_| {
+                    format!(
+                        "expected a number for '{}', found '{}' at column {}",
+                        name, value, value_column
+                    )
+                })?;
+                Ok(Expr::Number(field, comparison, number))
+            }
+            Field::Text(field) => match op {
+                "==" => Ok(Expr::Equals(field, value)),
+                "!=" => Ok(Expr::Not(Box::new(Expr::Equals(field, value)))),
+                "~" | "!~" => {
+                    let regex = Regex::new(&value).map_err(|e| {
+                        format!("invalid pattern at column {}: {}", value_column, e)
+                    })?;
+                    let expr = Expr::Matches(field, regex);
+                    match op {
+                        "~" => Ok(expr),
+                        _ => Ok(Expr::Not(Box::new(expr))),
+                    }
+                }
+                "in" if matches!(field, TextField::Ip) => {
+                    let cidrs = value
+                        .split(',')
+                        .map(|cidr| cidr.parse())
+                        .collect::<Result<Vec<Cidr>, _>>()
+                        .map_err(|_| {
+                            format!(
+                                "expected a network like 10.0.0.0/8, found '{}' at column {}",
+                                value, value_column
+                            )
+                        })?;
+                    Ok(Expr::In(cidrs))
+                }
+                _ => Err(unsupported()),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsers::nginx;
+
+    const LOGLINE: &str = r#"10.1.2.3 - - [09/May/2022:00:00:07 +0000] "POST /api/users?page=2 HTTP/1.1" 503 512 "-" "curl/7.81.0""#;
+
+    fn matches(expression: &str) -> bool {
+        let log = nginx::get_log_from_logline(LOGLINE).unwrap();
+        Filter::new(expression).unwrap().matches(&log)
+    }
+
+    #[test]
+    fn evaluates_comparisons() {
+        assert!(matches("status >= 500"));
+        assert!(!matches("status < 500"));
+        assert!(matches("bytes == 512"));
+        assert!(matches("method == POST"));
+        assert!(matches("method != GET"));
+        assert!(matches(r#"path ~ "^/api""#));
+        assert!(matches(r#"path !~ "\.js$""#));
+        assert!(matches(r#"query ~ "page=""#));
+        assert!(matches("ip in 10.0.0.0/8"));
+        assert!(!matches("ip in 192.168.0.0/16,172.16.0.0/12"));
+        assert!(matches(r#"ua == "curl/7.81.0""#));
+        // There's no request time in the combined format
+        assert!(!matches("request_time > 0"));
+        assert!(!matches("request_time <= 0"));
+    }
+
+    #[test]
+    fn evaluates_boolean_logic() {
+        assert!(matches(
+            r#"status >= 500 && path ~ "^/api" && method == POST && ip in 10.0.0.0/8"#
+        ));
+        assert!(matches("status == 200 || status == 503"));
+        assert!(!matches("!(status == 503)"));
+        assert!(matches("!status == 200"));
+        // && binds tighter than ||
+        assert!(matches("status == 503 || status == 200 && method == GET"));
+        assert!(!matches(
+            "(status == 503 || status == 200) && method == GET"
+        ));
+    }
+
+    fn error(expression: &str) -> String {
+        Filter::new(expression).unwrap_err()
+    }
+
+    #[test]
+    fn reports_bad_expressions() {
+        assert_eq!(
+            error("code >= 500"),
+            format!(
+                "unknown field 'code' at column 1, expected one of {}",
+                FIELDS
+            )
+        );
+        assert_eq!(
+            error("status 500"),
+            "expected an operator after 'status', found '500' at column 8"
+        );
+        assert_eq!(
+            error("status >="),
+            "expected a value after '>=', found end of expression"
+        );
+        assert_eq!(
+            error("status >= 5xx"),
+            "expected a number for 'status', found '5xx' at column 11"
+        );
+        assert_eq!(
+            error("path > /api"),
+            "'>' can't be used with 'path' at column 6"
+        );
+        assert_eq!(
+            error("ip in 10.0.0.0/33"),
+            "expected a network like 10.0.0.0/8, found '10.0.0.0/33' at column 7"
+        );
+        assert_eq!(
+            error(r#"path ~ "(api""#),
+            "invalid pattern at column 8: unclosed '(' at position 1"
+        );
+        assert_eq!(
+            error("(status == 200"),
+            "expected ')' to close the '(' at column 1, found end of expression"
+        );
+        assert_eq!(
+            error("status == 200 method == GET"),
+            "unexpected 'method' at column 15, expected '&&' or '||'"
+        );
+        assert_eq!(error("status = 200"), "unexpected '=' at column 8");
+        assert_eq!(error(r#"path == "/api"#), "unclosed quote at column 9");
+    }
+}
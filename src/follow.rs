@@ -0,0 +1,143 @@
+use std::fs::{self, File, Metadata};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+// How long to wait for more lines once we've caught up with the end of the file
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+// Reads a file that's still being written to, like `tail -f`. Instead of reaching the end of
+// the file, reads wait for more to be appended, so a partial line is finished before it's used.
+// If the file is truncated, as by logrotate's copytruncate, reading starts over from the top.
+// If it's renamed and a new one created in its place, as by logrotate's default create mode,
+// the new one is opened once the old one has been read to the end.
+pub struct Follow {
+    file: File,
+    path: PathBuf,
+    position: u64,
+}
+
+impl Follow {
+    pub fn new(file: File, path: &Path) -> Follow {
+        Follow {
+            file,
+            path: path.to_path_buf(),
+            position: 0,
+        }
+    }
+
+    // The file now at our path, if it's a different one from the file we're reading. Between
+    // the rename and the new file being created there's nothing there, so we keep waiting.
+    fn replacement(&self) -> io::Result<Option<File>> {
+        let current = match fs::metadata(&self.path) {
+            Ok(metadata) => metadata,
+            Err(_) => return Ok(None),
+        };
+        match same_file(&self.file.metadata()?, &current) {
+            true => Ok(None),
+            false => Ok(File::open(&self.path).ok()),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn same_file(a: &Metadata, b: &Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    (a.dev(), a.ino()) == (b.dev(), b.ino())
+}
+
+// Without inodes to compare, a new file is told apart by being smaller than the old one
+#[cfg(not(unix))]
+fn same_file(a: &Metadata, b: &Metadata) -> bool {
+    b.len() >= a.len()
+}
+
+impl Read for Follow {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.file.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                self.position += read as u64;
+                return Ok(read);
+            }
+            thread::sleep(POLL_INTERVAL);
+            if self.file.metadata()?.len() < self.position {
+                self.position = self.file.seek(SeekFrom::Start(0))?;
+            } else if let Some(file) = self.replacement()? {
+                self.file = file;
+                self.position = 0;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs::{self, OpenOptions};
+    use std::io::{BufRead, BufReader, Write};
+
+    #[test]
+    fn waits_for_lines_to_be_finished() {
+        let path = env::temp_dir().join(format!("dashlight-follow-{}", std::process::id()));
+        fs::write(&path, "first\nsec").unwrap();
+
+        let writer_path = path.clone();
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            let mut file = OpenOptions::new().append(true).open(&writer_path).unwrap();
+            file.write_all(b"ond\n").unwrap();
+            thread::sleep(Duration::from_millis(300));
+            // Truncate and start again, as logrotate's copytruncate does
+            fs::write(&writer_path, "").unwrap();
+            thread::sleep(Duration::from_millis(300));
+            fs::write(&writer_path, "third\n").unwrap();
+        });
+
+        let mut reader = BufReader::new(Follow::new(File::open(&path).unwrap(), &path));
+        let mut line = String::new();
+        for expected in ["first\n", "second\n", "third\n"] {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+            assert_eq!(line, expected);
+        }
+        writer.join().unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reopens_files_that_are_replaced() {
+        let path = env::temp_dir().join(format!("dashlight-rotate-{}", std::process::id()));
+        let rotated = path.with_extension("1");
+        fs::write(&path, "first\n").unwrap();
+
+        let writer_path = path.clone();
+        let writer_rotated = rotated.clone();
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            // Rename and create a new file, as logrotate does by default. Lines written to the
+            // old file before the writer reopens the path still count.
+            fs::rename(&writer_path, &writer_rotated).unwrap();
+            let mut old = OpenOptions::new()
+                .append(true)
+                .open(&writer_rotated)
+                .unwrap();
+            old.write_all(b"second\n").unwrap();
+            thread::sleep(Duration::from_millis(300));
+            fs::write(&writer_path, "third\n").unwrap();
+        });
+
+        let mut reader = BufReader::new(Follow::new(File::open(&path).unwrap(), &path));
+        let mut line = String::new();
+        for expected in ["first\n", "second\n", "third\n"] {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+            assert_eq!(line, expected);
+        }
+        writer.join().unwrap();
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&rotated).unwrap();
+    }
+}
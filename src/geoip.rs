@@ -0,0 +1,540 @@
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+
+// Marks the start of the metadata section, which is found by searching back from the end
+const METADATA_MARKER: &[u8] = b"\xAB\xCD\xEFMaxMind.com";
+// The tree and the data section are separated by 16 zero bytes
+const DATA_SEPARATOR: usize = 16;
+
+// Data section types, from the MaxMind DB format spec
+const POINTER: u8 = 1;
+const STRING: u8 = 2;
+const UINT16: u8 = 5;
+const UINT32: u8 = 6;
+const MAP: u8 = 7;
+const UINT64: u8 = 9;
+const UINT128: u8 = 10;
+const ARRAY: u8 = 11;
+const BOOLEAN: u8 = 14;
+
+// A MaxMind DB file (such as GeoLite2-Country.mmdb), read entirely into memory.
+// The file is a binary search tree keyed on the bits of an address, whose leaves point into a
+// data section of JSON-like values. Lookups walk the tree and then decode only the fields we
+// ask for, borrowing strings from the file, so they don't allocate.
+#[derive(Debug)]
+pub struct Database {
+    buf: Vec<u8>,
+    node_count: usize,
+    record_size: usize,
+    ip_version: u16,
+    data_start: usize,
+    metadata_start: usize,
+    // IPv4 addresses live under ::/96 in IPv6 databases, so we skip the first 96 bits up front
+    ipv4_start: usize,
+}
+
+impl Database {
+    pub fn open(path: &str) -> io::Result<Database> {
+        Database::from_bytes(fs::read(path)?).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is not a MaxMind DB file", path),
+            )
+        })
+    }
+
+    fn from_bytes(buf: Vec<u8>) -> Option<Database> {
+        let marker = buf
+            .windows(METADATA_MARKER.len())
+            .rposition(|window| window == METADATA_MARKER)?;
+        let metadata_start = marker + METADATA_MARKER.len();
+        let metadata = Decoder(&buf[metadata_start..]);
+        let field = |name| metadata.map_get(0, name).and_then(|x| metadata.uint(x));
+
+        let node_count = field("node_count")? as usize;
+        let record_size = field("record_size")? as usize;
+        let ip_version = field("ip_version")? as u16;
+        if ![24, 28, 32].contains(&record_size) || ![4, 6].contains(&ip_version) {
+            return None;
+        }
+        let data_start = node_count * record_size / 4 + DATA_SEPARATOR;
+        if data_start > marker {
+            return None;
+        }
+
+        let mut database = Database {
+            buf,
+            node_count,
+            record_size,
+            ip_version,
+            data_start,
+            metadata_start,
+            ipv4_start: 0,
+        };
+        if ip_version == 6 {
+            let mut node = 0;
+            for _ in 0..96 {
+                if node >= node_count {
+                    break;
+                }
+                node = database.record(node, 0)?;
+            }
+            database.ipv4_start = node;
+        }
+        Some(database)
+    }
+
+    // Read the left (0) or right (1) record of a node in the search tree
+    fn record(&self, node: usize, bit: usize) -> Option<usize> {
+        let bytes = |start: usize, len: usize| {
+            let bytes = self.buf.get(start..start + len)?;
+            Some(bytes.iter().fold(0, |value, &b| value << 8 | b as usize))
+        };
+        match self.record_size {
+            24 => bytes(node * 6 + bit * 3, 3),
+            // The middle byte holds the high nibble of each record
+            28 => {
+                let base = node * 7;
+                let middle = *self.buf.get(base + 3)? as usize;
+                match bit {
+                    0 => Some((middle & 0xF0) << 20 | bytes(base, 3)?),
+                    _ => Some((middle & 0x0F) << 24 | bytes(base + 4, 3)?),
+                }
+            }
+            _ => bytes(node * 8 + bit * 4, 4),
+        }
+    }
+
+    // Offset of the data for the network containing this address, within the data section
+    fn find(&self, addr: IpAddr) -> Option<usize> {
+        let (bits, len, mut node) = match addr {
+            IpAddr::V4(v4) => ((u32::from(v4) as u128) << 96, 32, self.ipv4_start),
+            IpAddr::V6(_) if self.ip_version == 4 => return None,
+            IpAddr::V6(v6) => (u128::from(v6), 128, 0),
+        };
+        for i in 0..len {
+            if node >= self.node_count {
+                break;
+            }
+            node = self.record(node, (bits >> (127 - i) & 1) as usize)?;
+        }
+        // Equal to the node count means there's no data for this address
+        match node > self.node_count {
+            true => Some(node - self.node_count - DATA_SEPARATOR),
+            false => None,
+        }
+    }
+
+    fn data(&self) -> Decoder<'_> {
+        Decoder(&self.buf[self.data_start..self.metadata_start])
+    }
+
+    // Look up a string nested in the data for an address, such as country -> iso_code
+    fn string(&self, addr: IpAddr, path: &[&str]) -> Option<&str> {
+        let data = self.data();
+        data.string(data.path(self.find(addr)?, path)?)
+    }
+
+    fn uint(&self, addr: IpAddr, path: &[&str]) -> Option<u64> {
+        let data = self.data();
+        data.uint(data.path(self.find(addr)?, path)?)
+    }
+}
+
+// Decodes values from a section of the file, where pointers are relative to its start
+#[derive(Clone, Copy)]
+struct Decoder<'a>(&'a [u8]);
+
+impl<'a> Decoder<'a> {
+    // Read a control byte, returning the value's type, its size, and where its payload starts
+    fn control(&self, offset: usize) -> Option<(u8, usize, usize)> {
+        let byte = *self.0.get(offset)?;
+        let mut offset = offset + 1;
+        let mut kind = byte >> 5;
+        if kind == 0 {
+            kind = 7 + *self.0.get(offset)?;
+            offset += 1;
+        }
+        let size = (byte & 0x1F) as usize;
+        if kind == POINTER {
+            return Some((kind, size, offset));
+        }
+        // Sizes above 28 spill over into the following bytes
+        let (size, extra) = match size {
+            29 => (29, 1),
+            30 => (285, 2),
+            31 => (65_821, 3),
+            _ => return Some((kind, size, offset)),
+        };
+        let bytes = self.0.get(offset..offset + extra)?;
+        let size = size + bytes.iter().fold(0, |value, &b| value << 8 | b as usize);
+        Some((kind, size, offset + extra))
+    }
+
+    // Follow a pointer, if there is one, returning the type, size and payload of the value
+    fn resolve(&self, offset: usize) -> Option<(u8, usize, usize)> {
+        let (kind, size, payload) = self.control(offset)?;
+        match kind {
+            POINTER => self.control(self.pointer(size, payload)?.0),
+            _ => Some((kind, size, payload)),
+        }
+    }
+
+    // Decode a pointer's target, along with the offset just past the pointer itself
+    fn pointer(&self, size: usize, payload: usize) -> Option<(usize, usize)> {
+        let extra = (size >> 3) & 0x3;
+        let bytes = self.0.get(payload..payload + extra + 1)?;
+        let value = bytes.iter().fold(0, |value, &b| value << 8 | b as usize);
+        let target = match extra {
+            0 => (size & 0x7) << 8 | value,
+            1 => ((size & 0x7) << 16 | value) + 2048,
+            2 => ((size & 0x7) << 24 | value) + 526_336,
+            _ => value,
+        };
+        Some((target, payload + extra + 1))
+    }
+
+    // The offset just past the value at this offset
+    fn skip(&self, offset: usize) -> Option<usize> {
+        let (kind, size, payload) = self.control(offset)?;
+        match kind {
+            POINTER => Some(self.pointer(size, payload)?.1),
+            MAP => (0..size * 2).try_fold(payload, |offset, _| self.skip(offset)),
+            ARRAY => (0..size).try_fold(payload, |offset, _| self.skip(offset)),
+            // Booleans keep their value in the size, with no payload
+            BOOLEAN => Some(payload),
+            _ => Some(payload + size),
+        }
+    }
+
+    fn string(&self, offset: usize) -> Option<&'a str> {
+        match self.resolve(offset)? {
+            (STRING, size, payload) => {
+                std::str::from_utf8(self.0.get(payload..payload + size)?).ok()
+            }
+            _ => None,
+        }
+    }
+
+    fn uint(&self, offset: usize) -> Option<u64> {
+        match self.resolve(offset)? {
+            (UINT16 | UINT32 | UINT64 | UINT128, size, payload) if size <= 8 => {
+                let bytes = self.0.get(payload..payload + size)?;
+                Some(bytes.iter().fold(0, |value, &b| value << 8 | b as u64))
+            }
+            _ => None,
+        }
+    }
+
+    // Find the value stored under a key in the map at this offset
+    fn map_get(&self, offset: usize, key: &str) -> Option<usize> {
+        let (kind, size, mut offset) = self.resolve(offset)?;
+        if kind != MAP {
+            return None;
+        }
+        for _ in 0..size {
+            let value = self.skip(offset)?;
+            if self.string(offset)? == key {
+                return Some(value);
+            }
+            offset = self.skip(value)?;
+        }
+        None
+    }
+
+    fn path(&self, offset: usize, keys: &[&str]) -> Option<usize> {
+        keys.iter()
+            .try_fold(offset, |offset, key| self.map_get(offset, key))
+    }
+}
+
+// An autonomous system, i.e. the network that an address belongs to
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Asn<'a> {
+    pub number: u64,
+    pub organization: &'a str,
+}
+
+// What we know about where a client address is
+#[derive(Debug, Default, PartialEq)]
+pub struct Location<'a> {
+    // ISO 3166 country code, e.g. "NL"
+    pub country: Option<&'a str>,
+    pub asn: Option<Asn<'a>>,
+}
+
+// The country and ASN databases, either of which can be left out
+#[derive(Debug)]
+pub struct GeoIp {
+    country: Option<Database>,
+    asn: Option<Database>,
+}
+
+impl GeoIp {
+    pub fn open(country: Option<&str>, asn: Option<&str>) -> io::Result<GeoIp> {
+        Ok(GeoIp {
+            country: country.map(Database::open).transpose()?,
+            asn: asn.map(Database::open).transpose()?,
+        })
+    }
+
+    pub fn locate(&self, addr: &str) -> Location<'_> {
+        let addr = match addr.parse() {
+            Ok(addr) => addr,
+            Err(_) => return Location::default(),
+        };
+        // Anycast and satellite addresses have no country, so fall back to where they're registered
+        let country = self.country.as_ref().and_then(|db| {
+            db.string(addr, &["country", "iso_code"])
+                .or_else(|| db.string(addr, &["registered_country", "iso_code"]))
+        });
+        let asn = self.asn.as_ref().and_then(|db| {
+            Some(Asn {
+                number: db.uint(addr, &["autonomous_system_number"])?,
+                organization: db
+                    .string(addr, &["autonomous_system_organization"])
+                    .unwrap_or("-"),
+            })
+        });
+        Location { country, asn }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::Cidr;
+
+    // Encodes values in the data section format, for building test databases
+    enum Value {
+        String(&'static str),
+        Uint(u64),
+        Map(Vec<(&'static str, Value)>),
+    }
+
+    fn write_control(out: &mut Vec<u8>, kind: u8, size: usize) {
+        assert!(size < 285);
+        let (size, extra) = match size {
+            0..=28 => (size as u8, None),
+            _ => (29, Some((size - 29) as u8)),
+        };
+        match kind {
+            0..=7 => out.push(kind << 5 | size),
+            _ => out.extend([size, kind - 7]),
+        }
+        out.extend(extra);
+    }
+
+    fn write_value(out: &mut Vec<u8>, value: &Value) {
+        match value {
+            Value::String(s) => {
+                write_control(out, STRING, s.len());
+                out.extend(s.as_bytes());
+            }
+            Value::Uint(n) => {
+                let bytes: Vec<u8> = n
+                    .to_be_bytes()
+                    .into_iter()
+                    .skip_while(|&b| b == 0)
+                    .collect();
+                write_control(out, UINT32, bytes.len());
+                out.extend(bytes);
+            }
+            Value::Map(entries) => {
+                write_control(out, MAP, entries.len());
+                for (key, value) in entries {
+                    write_value(out, &Value::String(key));
+                    write_value(out, value);
+                }
+            }
+        }
+    }
+
+    // Build an IPv6 database with 24-bit records, mapping each network to a value
+    fn build(database_type: &'static str, networks: Vec<(&str, Value)>) -> Vec<u8> {
+        #[derive(Clone, Copy)]
+        enum Record {
+            Empty,
+            Node(usize),
+            Data(usize),
+        }
+        let mut nodes: Vec<[Record; 2]> = vec![[Record::Empty, Record::Empty]];
+        let mut data = Vec::new();
+
+        for (network, value) in networks {
+            let cidr: Cidr = network.parse().unwrap();
+            let (bits, prefix) = match cidr.network {
+                // IPv4 networks go under ::/96
+                IpAddr::V4(v4) => (u32::from(v4) as u128, cidr.prefix as usize + 96),
+                IpAddr::V6(v6) => (u128::from(v6), cidr.prefix as usize),
+            };
+            let mut node = 0;
+            for i in 0..prefix - 1 {
+                let bit = (bits >> (127 - i) & 1) as usize;
+                node = match nodes[node][bit] {
+                    Record::Node(next) => next,
+                    // A more specific network splits the one it's in, which keeps its data
+                    record => {
+                        nodes.push([record, record]);
+                        nodes[node][bit] = Record::Node(nodes.len() - 1);
+                        nodes.len() - 1
+                    }
+                };
+            }
+            nodes[node][(bits >> (128 - prefix) & 1) as usize] = Record::Data(data.len());
+            write_value(&mut data, &value);
+        }
+
+        let node_count = nodes.len();
+        let mut out = Vec::new();
+        for record in nodes.iter().flatten() {
+            let value = match record {
+                Record::Empty => node_count,
+                Record::Node(next) => *next,
+                Record::Data(offset) => node_count + DATA_SEPARATOR + offset,
+            };
+            out.extend(&(value as u32).to_be_bytes()[1..]);
+        }
+        out.extend([0; DATA_SEPARATOR]);
+        out.extend(data);
+        out.extend(METADATA_MARKER);
+        write_value(
+            &mut out,
+            &Value::Map(vec![
+                ("binary_format_major_version", Value::Uint(2)),
+                ("binary_format_minor_version", Value::Uint(0)),
+                ("database_type", Value::String(database_type)),
+                ("ip_version", Value::Uint(6)),
+                ("node_count", Value::Uint(node_count as u64)),
+                ("record_size", Value::Uint(24)),
+            ]),
+        );
+        out
+    }
+
+    fn country(code: &'static str) -> Value {
+        Value::Map(vec![(
+            "country",
+            Value::Map(vec![("iso_code", Value::String(code))]),
+        )])
+    }
+
+    fn asn(number: u64, organization: &'static str) -> Value {
+        Value::Map(vec![
+            ("autonomous_system_number", Value::Uint(number)),
+            (
+                "autonomous_system_organization",
+                Value::String(organization),
+            ),
+        ])
+    }
+
+    // The fixtures in tests/data, using documentation address ranges
+    fn fixtures() -> [(&'static str, Vec<u8>); 2] {
+        [
+            (
+                "tests/data/country.mmdb",
+                build(
+                    "GeoLite2-Country",
+                    vec![
+                        ("203.0.113.0/24", country("NL")),
+                        ("198.51.100.0/24", country("US")),
+                        ("2001:db8::/32", country("DE")),
+                    ],
+                ),
+            ),
+            (
+                "tests/data/asn.mmdb",
+                build(
+                    "GeoLite2-ASN",
+                    vec![
+                        ("203.0.113.0/24", asn(64500, "Example Hosting")),
+                        ("198.51.100.0/24", asn(64501, "Example Broadband")),
+                        ("2001:db8::/32", asn(64502, "Example Transit")),
+                    ],
+                ),
+            ),
+        ]
+    }
+
+    #[test]
+    fn fixtures_match_generator() {
+        // Run with REGENERATE_FIXTURES=1 to rewrite them after changing the generator
+        for (path, expected) in fixtures() {
+            if std::env::var_os("REGENERATE_FIXTURES").is_some() {
+                fs::write(path, &expected).unwrap();
+            }
+            assert_eq!(fs::read(path).unwrap(), expected, "{} is out of date", path);
+        }
+    }
+
+    fn database(networks: Vec<(&str, Value)>) -> Database {
+        Database::from_bytes(build("Test", networks)).unwrap()
+    }
+
+    #[test]
+    fn finds_most_specific_network() {
+        let db = database(vec![
+            ("10.0.0.0/8", country("AA")),
+            ("10.1.0.0/16", country("BB")),
+            ("2001:db8::/32", country("CC")),
+        ]);
+        let iso_code = |addr: &str| db.string(addr.parse().unwrap(), &["country", "iso_code"]);
+
+        assert_eq!(iso_code("10.2.3.4"), Some("AA"));
+        assert_eq!(iso_code("10.1.3.4"), Some("BB"));
+        assert_eq!(iso_code("2001:db8:1::1"), Some("CC"));
+        assert_eq!(iso_code("11.0.0.1"), None);
+        assert_eq!(iso_code("2001:db9::1"), None);
+    }
+
+    #[test]
+    fn locates_country_and_asn() {
+        let geoip = GeoIp {
+            country: Some(database(vec![("203.0.113.0/24", country("NL"))])),
+            asn: Some(database(vec![(
+                "203.0.113.0/24",
+                asn(64500, "Example Hosting"),
+            )])),
+        };
+        assert_eq!(
+            geoip.locate("203.0.113.9"),
+            Location {
+                country: Some("NL"),
+                asn: Some(Asn {
+                    number: 64500,
+                    organization: "Example Hosting"
+                }),
+            }
+        );
+        assert_eq!(geoip.locate("198.51.100.7"), Location::default());
+        assert_eq!(geoip.locate("unix:"), Location::default());
+    }
+
+    #[test]
+    fn decodes_pointers_and_extended_sizes() {
+        // A map whose value is a pointer back to a string at the start of the section
+        let mut data = Vec::new();
+        write_value(&mut data, &Value::String("shared"));
+        let map = data.len();
+        write_control(&mut data, MAP, 1);
+        write_value(&mut data, &Value::String("key"));
+        data.extend([POINTER << 5, 0]);
+        // A string long enough to need an extra size byte
+        let long = data.len();
+        data.extend([STRING << 5 | 29, 1]);
+        data.extend([b'x'; 30]);
+
+        let decoder = Decoder(&data);
+        let value = decoder.map_get(map, "key").unwrap();
+        assert_eq!(decoder.string(value), Some("shared"));
+        assert_eq!(decoder.skip(map), Some(long));
+        assert_eq!(decoder.string(long).map(str::len), Some(30));
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(Database::from_bytes(b"not a database".to_vec()).is_none());
+    }
+}
@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+
+// Tracks the most frequent keys in a stream using a fixed number of slots (the Space-Saving
+// algorithm, Metwally et al. 2005). When every slot is taken, a new key replaces the least
+// frequent one and inherits its count, so keys that are truly frequent can't be starved out by
+// a flood of one-off keys. Any key seen more than total / capacity times is guaranteed a slot.
+//
+// The evicted key's value is handed back to the caller, so it can be folded into a catch-all.
+#[derive(Debug)]
+pub struct HeavyHitters<V> {
+    capacity: usize,
+    // Min-heap on count, so the root is always the next slot to be evicted
+    slots: Vec<Slot<V>>,
+    positions: HashMap<String, usize>,
+    evictions: u64,
+}
+
+#[derive(Debug)]
+struct Slot<V> {
+    key: String,
+    // Estimated count, which can overestimate (but never underestimate) the true count
+    count: u64,
+    value: V,
+}
+
+impl<V: Default> HeavyHitters<V> {
+    pub fn new(capacity: usize) -> HeavyHitters<V> {
+        HeavyHitters {
+            capacity: capacity.max(1),
+            slots: Vec::new(),
+            positions: HashMap::new(),
+            evictions: 0,
+        }
+    }
+
+    // Count an occurence of the key, returning its value along with anything that was evicted
+    // to make room for it
+    pub fn increment(&mut self, key: &str) -> (&mut V, Option<(String, V)>) {
+        if let Some(&index) = self.positions.get(key) {
+            self.slots[index].count += 1;
+            let index = self.sift_down(index);
+            return (&mut self.slots[index].value, None);
+        }
+
+        if self.slots.len() < self.capacity {
+            self.slots.push(Slot {
+                key: String::from(key),
+                count: 1,
+                value: V::default(),
+            });
+            self.positions
+                .insert(String::from(key), self.slots.len() - 1);
+            // A count of 1 is the lowest possible, so the new slot can only need to move up
+            let index = self.sift_up(self.slots.len() - 1);
+            return (&mut self.slots[index].value, None);
+        }
+
+        // Replace the least frequent key, which is at the root of the heap
+        let min_count = self.slots[0].count;
+        let evicted = std::mem::replace(
+            &mut self.slots[0],
+            Slot {
+                key: String::from(key),
+                count: min_count + 1,
+                value: V::default(),
+            },
+        );
+        self.positions.remove(&evicted.key);
+        self.positions.insert(String::from(key), 0);
+        self.evictions += 1;
+        let index = self.sift_down(0);
+        (
+            &mut self.slots[index].value,
+            Some((evicted.key, evicted.value)),
+        )
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &V)> {
+        self.slots
+            .iter()
+            .map(|slot| (slot.key.as_str(), &slot.value))
+    }
+
+    // Number of keys that have been pushed out of the table to make room for new ones
+    pub fn evictions(&self) -> u64 {
+        self.evictions
+    }
+
+    // Estimated count for a key, if it currently has a slot
+    #[cfg(test)]
+    fn estimate(&self, key: &str) -> Option<u64> {
+        Some(self.slots[*self.positions.get(key)?].count)
+    }
+
+    fn swap(&mut self, a: usize, b: usize) {
+        self.slots.swap(a, b);
+        *self.positions.get_mut(&self.slots[a].key).unwrap() = a;
+        *self.positions.get_mut(&self.slots[b].key).unwrap() = b;
+    }
+
+    fn sift_up(&mut self, mut index: usize) -> usize {
+        while index > 0 {
+            let parent = (index - 1) / 2;
+            if self.slots[parent].count <= self.slots[index].count {
+                break;
+            }
+            self.swap(index, parent);
+            index = parent;
+        }
+        index
+    }
+
+    fn sift_down(&mut self, mut index: usize) -> usize {
+        loop {
+            let mut lowest = index;
+            for child in [2 * index + 1, 2 * index + 2] {
+                if child < self.slots.len() && self.slots[child].count < self.slots[lowest].count {
+                    lowest = child;
+                }
+            }
+            if lowest == index {
+                return index;
+            }
+            self.swap(index, lowest);
+            index = lowest;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_rng::Rng;
+
+    #[test]
+    fn exact_while_under_capacity() {
+        let mut hitters: HeavyHitters<u64> = HeavyHitters::new(3);
+        for key in ["a", "b", "a", "c", "a", "b"] {
+            *hitters.increment(key).0 += 1;
+        }
+        assert_eq!(hitters.evictions(), 0);
+        assert_eq!(hitters.estimate("a"), Some(3));
+        assert_eq!(hitters.estimate("b"), Some(2));
+        assert_eq!(hitters.estimate("c"), Some(1));
+    }
+
+    #[test]
+    fn evicts_least_frequent_key() {
+        let mut hitters: HeavyHitters<u64> = HeavyHitters::new(2);
+        hitters.increment("a");
+        hitters.increment("a");
+        hitters.increment("b");
+        let (_, evicted) = hitters.increment("c");
+
+        assert_eq!(evicted.map(|x| x.0), Some(String::from("b")));
+        assert_eq!(hitters.estimate("c"), Some(2));
+        assert_eq!(hitters.estimate("b"), None);
+        assert_eq!(hitters.evictions(), 1);
+    }
+
+    #[test]
+    fn guarantees_hold_on_random_streams() {
+        for seed in 1..=30 {
+            let mut rng = Rng(seed);
+            let capacity = rng.below(50) as usize + 1;
+            let lines = rng.below(5_000) + 1;
+            let keys = rng.below(1_000) + 1;
+
+            let mut hitters: HeavyHitters<u64> = HeavyHitters::new(capacity);
+            let mut exact: HashMap<String, u64> = HashMap::new();
+            let mut evicted_total = 0;
+            for _ in 0..lines {
+                // Skew the stream so that low keys are much more common
+                let busiest = rng.below(keys) + 1;
+                let key = rng.below(busiest).to_string();
+                *exact.entry(key.clone()).or_default() += 1;
+                let (value, evicted) = hitters.increment(&key);
+                *value += 1;
+                evicted_total += evicted.map_or(0, |x| x.1);
+            }
+
+            // Nothing is lost: what's still held plus what was evicted adds up to the stream
+            let held: u64 = hitters.iter().map(|x| *x.1).sum();
+            assert_eq!(held + evicted_total, lines, "seed {}", seed);
+
+            for (key, &count) in &exact {
+                match hitters.estimate(key) {
+                    Some(estimate) => assert!(estimate >= count, "seed {} key {}", seed, key),
+                    None => assert!(
+                        count <= lines / capacity as u64,
+                        "seed {} dropped frequent key {}",
+                        seed,
+                        key
+                    ),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+// Estimates the number of distinct items in a stream using a fixed 2^precision bytes
+// (Flajolet et al. 2007). Each item is hashed; the first `precision` bits of the hash pick a
+// register, which remembers the longest run of leading zeros seen in the rest of the hash.
+// Long runs are rare, so the runs across all registers reveal roughly how many distinct hashes
+// there have been.
+//
+// The standard error of the estimate is 1.04 / sqrt(2^precision), e.g. 3.25% for a precision
+// of 10 and 0.81% for 14. Small counts are estimated by linear counting, which is near-exact.
+#[derive(Debug, Clone)]
+pub struct HyperLogLog {
+    precision: u8,
+    registers: Vec<u8>,
+}
+
+impl HyperLogLog {
+    pub fn new(precision: u8) -> HyperLogLog {
+        HyperLogLog {
+            precision,
+            registers: vec![0; 1 << precision],
+        }
+    }
+
+    // Hash an item once, so that it can be inserted into several estimators
+    pub fn hash<T: Hash + ?Sized>(item: &T) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        item.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    pub fn insert_hash(&mut self, hash: u64) {
+        let index = (hash >> (64 - self.precision)) as usize;
+        // Set a sentinel bit so that the run of zeros can't go past the end of the hash
+        let rest = hash << self.precision | 1 << (self.precision - 1);
+        let rank = rest.leading_zeros() as u8 + 1;
+        if rank > self.registers[index] {
+            self.registers[index] = rank;
+        }
+    }
+
+    pub fn estimate(&self) -> u64 {
+        let m = self.registers.len() as f64;
+        let alpha = match self.registers.len() {
+            16 => 0.673,
+            32 => 0.697,
+            64 => 0.709,
+            _ => 0.7213 / (1.0 + 1.079 / m),
+        };
+        let sum: f64 = self.registers.iter().map(|&r| (-(r as f64)).exp2()).sum();
+        let estimate = alpha * m * m / sum;
+
+        // The raw estimate is biased for small counts, where empty registers are a better guide
+        let zeros = self.registers.iter().filter(|&&r| r == 0).count();
+        if estimate <= 2.5 * m && zeros > 0 {
+            return (m * (m / zeros as f64).ln()).round() as u64;
+        }
+        estimate.round() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn estimate_of(precision: u8, items: impl Iterator<Item = String>) -> u64 {
+        let mut hll = HyperLogLog::new(precision);
+        items.for_each(|item| hll.insert_hash(HyperLogLog::hash(&item)));
+        hll.estimate()
+    }
+
+    #[test]
+    fn empty_is_zero() {
+        assert_eq!(HyperLogLog::new(10).estimate(), 0);
+    }
+
+    #[test]
+    fn duplicates_are_not_counted() {
+        let items = (0..10_000).map(|i| format!("10.0.0.{}", i % 50));
+        assert_eq!(estimate_of(10, items), 50);
+    }
+
+    #[test]
+    fn within_documented_error_of_exact_counts() {
+        for precision in [10, 14] {
+            let standard_error = 1.04 / ((1 << precision) as f64).sqrt();
+            for exact in [100, 1_000, 10_000, 100_000] {
+                // Every address appears a few times, like clients making several requests
+                let items = (0..exact * 3).map(|i| {
+                    let i = i % exact;
+                    format!(
+                        "{}.{}.{}.{}",
+                        i >> 24,
+                        (i >> 16) & 0xFF,
+                        (i >> 8) & 0xFF,
+                        i & 0xFF
+                    )
+                });
+                let estimate = estimate_of(precision, items) as f64;
+                let error = (estimate - exact as f64).abs() / exact as f64;
+                // Three standard errors should hold for all but 0.3% of inputs
+                assert!(
+                    error < 3.0 * standard_error,
+                    "precision {} exact {} estimate {}",
+                    precision,
+                    exact,
+                    estimate
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,357 @@
+use crate::config::SortKey;
+use crate::geoip::Location;
+use crate::parsers::nginx::{self, NginxCombinedLog};
+use crate::stats::RequestStats;
+use crate::view::View;
+use std::fmt::Write;
+use std::time::Duration;
+
+const NANOS_PER_SECOND: i64 = 1_000_000_000;
+// Request time percentiles written for each window, in seconds
+const PERCENTILES: [(&str, f64); 3] = [
+    ("request_time_p50", 50.0),
+    ("request_time_p90", 90.0),
+    ("request_time_p99", 99.0),
+];
+
+enum Value<'a> {
+    Integer(u64),
+    Float(f64),
+    String(&'a str),
+}
+
+// Write one point in InfluxDB line protocol, without a trailing newline. Tags with empty values
+// are left out, since line protocol doesn't allow them, as are floats that aren't finite.
+fn push_point(
+    out: &mut String,
+    measurement: &str,
+    tags: &[(&str, &str)],
+    fields: &[(&str, Value)],
+    timestamp: Option<i64>,
+) {
+    out.push_str(measurement);
+    for (key, value) in tags.iter().filter(|(_, value)| !value.is_empty()) {
+        write!(out, ",{}=", key).unwrap();
+        push_escaped(out, value, &[',', '=', ' ']);
+    }
+    let fields = fields.iter().filter(|(_, value)| match value {
+        Value::Float(value) => value.is_finite(),
+        _ => true,
+    });
+    for (index, (key, value)) in fields.enumerate() {
+        out.push(if index == 0 { ' ' } else { ',' });
+        write!(out, "{}=", key).unwrap();
+        match value {
+            Value::Integer(value) => write!(out, "{}i", value).unwrap(),
+            Value::Float(value) => write!(out, "{}", value).unwrap(),
+            Value::String(value) => {
+                out.push('"');
+                push_escaped(out, value, &['"']);
+                out.push('"');
+            }
+        }
+    }
+    if let Some(timestamp) = timestamp {
+        write!(out, " {}", timestamp).unwrap();
+    }
+}
+
+// Escape backslashes and the given characters with a backslash. Line protocol has no way to
+// write a newline in a value, so control characters are written as nginx would, e.g. \x0A.
+fn push_escaped(out: &mut String, value: &str, special: &[char]) {
+    for c in value.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            c if special.contains(&c) => {
+                out.push('\\');
+                out.push(c);
+            }
+            c if c.is_control() => write!(out, "\\x{:02X}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+}
+
+fn status_class(status: u32) -> &'static str {
+    match status {
+        200..=299 => "2xx",
+        300..=399 => "3xx",
+        400..=499 => "4xx",
+        500..=599 => "5xx",
+        _ => "",
+    }
+}
+
+// One point for a request, timestamped with the time it was logged. The values that are good
+// for grouping by are tags, and the rest are fields.
+pub fn push_request(out: &mut String, log: &NginxCombinedLog, location: &Location) {
+    // Text is decoded from nginx's escapes, and "-" means there was no value
+    let text = |value: &str| match value {
+        "-" => String::new(),
+        value => nginx::unescape(value).into_owned(),
+    };
+    let asn = location.asn.as_ref().map(|asn| asn.number.to_string());
+    let tags = [
+        ("host", text(log.host)),
+        (
+            "method",
+            String::from(log.method.map_or("", |method| method.as_str())),
+        ),
+        ("path", log.request_path.map_or(String::new(), text)),
+        ("status_class", String::from(status_class(log.status))),
+        ("country", String::from(location.country.unwrap_or(""))),
+        ("asn", asn.unwrap_or_default()),
+    ];
+    let tags: Vec<(&str, &str)> = tags
+        .iter()
+        .map(|(key, value)| (*key, value.as_str()))
+        .collect();
+
+    let strings = [
+        ("ip", text(log.remote_addr)),
+        ("user", text(log.remote_user)),
+        ("query", log.request_query.map_or(String::new(), text)),
+        ("referer", text(log.http_referer)),
+        ("ua", text(log.http_user_agent)),
+    ];
+    let mut fields = vec![
+        ("status", Value::Integer(log.status as u64)),
+        ("bytes", Value::Integer(log.body_bytes_sent as u64)),
+    ];
+    if let Some(request_time) = log.request_time {
+        fields.push(("request_time", Value::Float(request_time)));
+    }
+    for (key, value) in strings.iter().filter(|(_, value)| !value.is_empty()) {
+        fields.push((key, Value::String(value)));
+    }
+
+    let timestamp = nginx::unix_time(log.time_local).map(|seconds| seconds * NANOS_PER_SECOND);
+    push_point(out, "dashlight_request", &tags, &fields, timestamp);
+}
+
+// Groups a watch's requests into fixed windows of log time, so that a whole log can be turned
+// into points at once. Each window's points are written when a line from a later one is read,
+// or when following, once the window's time has passed.
+pub struct Windows {
+    width: i64,
+    start: Option<i64>,
+    // Where the next window can start, so that a window written because its time had passed
+    // isn't written again for lines that arrive late
+    next: i64,
+    top: usize,
+    sort: SortKey,
+}
+
+impl Windows {
+    pub fn new(width: Duration, top: usize, sort: SortKey) -> Windows {
+        Windows {
+            width: width.as_secs() as i64,
+            start: None,
+            next: i64::MIN,
+            top,
+            sort,
+        }
+    }
+
+    // Call before counting a request in the view. If it's from a later window than the current
+    // one, that window is finished, and its points are returned. Requests from an earlier window,
+    // or without a time, are counted in the current one.
+    pub fn advance(&mut self, log: &NginxCombinedLog, view: &mut View) -> Option<String> {
+        let time = nginx::unix_time(log.time_local)?;
+        let window = (time - time.rem_euclid(self.width)).max(self.next);
+        match self.start {
+            Some(start) if window <= start => None,
+            Some(_) => {
+                let points = self.finish(view);
+                self.start = Some(window);
+                points
+            }
+            None => {
+                self.start = Some(window);
+                None
+            }
+        }
+    }
+
+    // The points of the current window if it ended by the given Unix time. While following a
+    // quiet log, no later line comes along to end it, so this is called on every interval.
+    // Lines from it that arrive afterwards are counted in the next window.
+    pub fn finish_ended(&mut self, now: i64, view: &mut View) -> Option<String> {
+        let start = self.start?;
+        if now < start + self.width {
+            return None;
+        }
+        let points = self.finish(view);
+        self.start = None;
+        self.next = start + self.width;
+        points
+    }
+
+    // The points of the current window, once there are no more requests
+    pub fn finish(&mut self, view: &mut View) -> Option<String> {
+        let start = self.start?;
+        let interval = view.take_interval()?;
+        let timestamp = start * NANOS_PER_SECOND;
+        let mut out = String::new();
+        push_window_stats(&mut out, None, &interval.global, timestamp);
+        for (route, stats) in interval.routes.top(self.top, self.sort) {
+            push_window_stats(&mut out, Some(route), stats, timestamp);
+        }
+        Some(out)
+    }
+}
+
+// A point for the requests with each status class, and one for the bytes and request times
+fn push_window_stats(out: &mut String, route: Option<&str>, stats: &RequestStats, timestamp: i64) {
+    let route = route.map(|route| ("route", route));
+    let codes = stats.codes;
+    let classes = [
+        ("2xx", codes.x2),
+        ("3xx", codes.x3),
+        ("4xx", codes.x4),
+        ("5xx", codes.x5),
+    ];
+    for (class, count) in classes {
+        // Overall counts are always written, so that they read as zero rather than missing
+        if count > 0 || route.is_none() {
+            let tags: Vec<(&str, &str)> =
+                route.into_iter().chain([("status_class", class)]).collect();
+            let fields = [("requests", Value::Integer(count as u64))];
+            push_point(out, "dashlight_requests", &tags, &fields, Some(timestamp));
+            out.push('\n');
+        }
+    }
+
+    let latency = &stats.latency;
+    let mut fields = vec![("bytes", Value::Integer(stats.bytes))];
+    if latency.count > 0 {
+        let mean = latency.sum / latency.count as f64;
+        fields.push(("request_time_mean", Value::Float(mean)));
+        for (name, percentile) in PERCENTILES {
+            // Percentiles in the overflow bucket are infinite, and left out by push_point
+            if let Some(seconds) = latency.percentile(percentile) {
+                fields.push((name, Value::Float(seconds)));
+            }
+        }
+    }
+    let tags: Vec<(&str, &str)> = route.into_iter().collect();
+    push_point(out, "dashlight_responses", &tags, &fields, Some(timestamp));
+    out.push('\n');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn escapes_tags_and_strings() {
+        let mut out = String::new();
+        push_point(
+            &mut out,
+            "requests",
+            &[("path", "/a b,c=d\\"), ("host", "")],
+            &[
+                ("ua", Value::String("say \"hi\"\n")),
+                ("time", Value::Float(f64::INFINITY)),
+                ("bytes", Value::Integer(10)),
+            ],
+            Some(1),
+        );
+        assert_eq!(
+            out,
+            r#"requests,path=/a\ b\,c\=d\\ ua="say \"hi\"\x0A",bytes=10i 1"#
+        );
+    }
+
+    #[test]
+    fn requests_are_points() {
+        let line = r#"10.0.0.1 - - [09/May/2022:00:00:07 +0000] "GET /my%20files/a,b?x=1 HTTP/1.1" 200 2048 "-" "curl/7.79.1" 0.002"#;
+        let log = nginx::get_log_from_logline(line).unwrap();
+        let mut out = String::new();
+        push_request(&mut out, &log, &Location::default());
+        assert_eq!(
+            out,
+            "dashlight_request,method=GET,path=/my%20files/a\\,b,status_class=2xx \
+             status=200i,bytes=2048i,request_time=0.002,ip=\"10.0.0.1\",query=\"x=1\",\
+             ua=\"curl/7.79.1\" 1652054407000000000"
+        );
+    }
+
+    #[test]
+    fn windows_are_written_when_they_end() {
+        let args = vec![
+            "dashlight".to_string(),
+            "watch".into(),
+            "--output".into(),
+            "influx".into(),
+            "--top".into(),
+            "1".into(),
+        ];
+        let config = Config::new(args);
+        let mut view = View::new(&config);
+        let mut windows = Windows::new(Duration::from_secs(60), config.top, config.sort);
+        let lines = [
+            r#"10.0.0.1 - - [09/May/2022:00:00:07 +0000] "GET / HTTP/1.1" 200 10 "-" "curl""#,
+            r#"10.0.0.1 - - [09/May/2022:00:00:59 +0000] "GET /api HTTP/1.1" 503 20 "-" "curl""#,
+            r#"10.0.0.1 - - [09/May/2022:00:00:58 +0000] "GET /api HTTP/1.1" 200 30 "-" "curl""#,
+            r#"10.0.0.1 - - [09/May/2022:00:01:00 +0000] "GET / HTTP/1.1" 200 40 "-" "curl""#,
+        ];
+        let mut points = Vec::new();
+        for line in lines {
+            let log = nginx::get_log_from_logline(line).unwrap();
+            points.extend(windows.advance(&log, &mut view));
+            view.update(log, &Location::default());
+        }
+        points.extend(windows.finish(&mut view));
+
+        assert_eq!(points.len(), 2);
+        assert_eq!(
+            points[0],
+            "dashlight_requests,status_class=2xx requests=2i 1652054400000000000\n\
+             dashlight_requests,status_class=3xx requests=0i 1652054400000000000\n\
+             dashlight_requests,status_class=4xx requests=0i 1652054400000000000\n\
+             dashlight_requests,status_class=5xx requests=1i 1652054400000000000\n\
+             dashlight_responses bytes=60i 1652054400000000000\n\
+             dashlight_requests,route=/api,status_class=2xx requests=1i 1652054400000000000\n\
+             dashlight_requests,route=/api,status_class=5xx requests=1i 1652054400000000000\n\
+             dashlight_responses,route=/api bytes=50i 1652054400000000000\n"
+        );
+        assert!(points[1]
+            .starts_with("dashlight_requests,status_class=2xx requests=1i 1652054460000000000\n"));
+    }
+
+    #[test]
+    fn windows_are_written_once_their_time_has_passed() {
+        let args = vec![
+            "dashlight".to_string(),
+            "watch".into(),
+            "--output".into(),
+            "influx".into(),
+        ];
+        let config = Config::new(args);
+        let mut view = View::new(&config);
+        let mut windows = Windows::new(Duration::from_secs(60), config.top, config.sort);
+        let update = |line: &str, view: &mut View, windows: &mut Windows| {
+            let log = nginx::get_log_from_logline(line).unwrap();
+            let points = windows.advance(&log, view);
+            view.update(log, &Location::default());
+            points
+        };
+        let line =
+            r#"10.0.0.1 - - [09/May/2022:00:00:07 +0000] "GET / HTTP/1.1" 200 10 "-" "curl""#;
+        assert_eq!(update(line, &mut view, &mut windows), None);
+
+        assert_eq!(windows.finish_ended(1652054459, &mut view), None);
+        let points = windows.finish_ended(1652054460, &mut view).unwrap();
+        assert!(points
+            .starts_with("dashlight_requests,status_class=2xx requests=1i 1652054400000000000\n"));
+        assert_eq!(windows.finish_ended(1652054520, &mut view), None);
+
+        // A line from the window that was written is counted in the next one instead
+        assert_eq!(update(line, &mut view, &mut windows), None);
+        let points = windows.finish(&mut view).unwrap();
+        assert!(points
+            .starts_with("dashlight_requests,status_class=2xx requests=1i 1652054460000000000\n"));
+    }
+}
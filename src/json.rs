@@ -0,0 +1,49 @@
+use std::fmt::Write;
+
+// Append a string as a quoted JSON string, escaping quotes, backslashes and control characters
+pub fn push_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c < ' ' => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+// Append a number, or null for infinities and NaN, which JSON can't represent
+pub fn push_f64(out: &mut String, n: f64) {
+    match n.is_finite() {
+        true => write!(out, "{}", n).unwrap(),
+        false => out.push_str("null"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_strings() {
+        let mut out = String::new();
+        push_string(&mut out, "say \"hi\"\\\n\t\u{1}é");
+        assert_eq!(out, r#""say \"hi\"\\\n\t\u0001é""#);
+    }
+
+    #[test]
+    fn numbers() {
+        let mut out = String::new();
+        push_f64(&mut out, 0.125);
+        out.push(',');
+        push_f64(&mut out, 2.0);
+        out.push(',');
+        push_f64(&mut out, f64::NAN);
+        assert_eq!(out, "0.125,2,null");
+    }
+}
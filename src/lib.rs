@@ -1,44 +1,178 @@
 use std::fs::File;
 use std::io;
 use std::io::{BufRead, BufReader};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use agents::Agent;
 use config::Config;
+use fields::{Converter, OutputFormat};
+use follow::Follow;
+use geoip::{GeoIp, Location};
+use influx::Windows;
 use parsers::nginx;
+use sqlite::Export;
+use statsd::Statsd;
 use view::View;
 
 pub mod config;
 
+mod agents;
+mod exclude;
+mod fields;
+mod filter;
+mod follow;
+mod geoip;
+mod heavy_hitters;
+mod hyperloglog;
+mod influx;
+mod json;
+mod metrics;
+mod net;
 mod parsers;
+mod regex;
+mod route_tree;
+mod sqlite;
 mod stats;
+mod stats_table;
+mod statsd;
+#[cfg(test)]
+mod test_rng;
+mod top_k;
 mod view;
 
 #[derive(Debug, PartialEq)]
 pub enum Error {
     InvalidArgs,
+    // An option value that couldn't be compiled, such as a --where expression or an exclusion
+    // pattern, with a message saying why
+    InvalidValue(String),
     ParsingError,
 }
 
 pub fn run(config: Config) -> Result<(), io::Error> {
+    // Shared with the thread that reports on an interval while following
+    let config = Arc::new(config);
     // Attempt to open the file if on was provided, STDIN otherwise
-    let mut reader: Box<dyn BufRead> = match config.filename {
+    let mut reader: Box<dyn BufRead> = match &config.filename {
         Some(filename) => {
             let file = File::open(filename)?;
-            Box::new(BufReader::new(file))
+            match config.follow {
+                true => Box::new(BufReader::new(Follow::new(file, filename.as_ref()))),
+                false => Box::new(BufReader::new(file)),
+            }
         }
         None => Box::new(BufReader::new(io::stdin())),
     };
 
-    let mut view = View::new();
-    let mut line = String::new();
+    let geoip = match (&config.geoip, &config.asn) {
+        (None, None) => None,
+        (country, asn) => Some(GeoIp::open(country.as_deref(), asn.as_deref())?),
+    };
+    // Shared with the threads that report on it while following
+    let view = Arc::new(Mutex::new(View::new(&config)));
+    if let Some(addr) = config.listen {
+        let addr = metrics::serve(addr, Arc::clone(&view))?;
+        eprintln!("Serving metrics at http://{}/metrics", addr);
+    }
+    let statsd = match config.statsd {
+        Some(addr) => Some(Arc::new(Statsd::new(
+            addr,
+            config.dogstatsd,
+            config.top,
+            config.sort,
+        )?)),
+        None => None,
+    };
+    // Shared with the reporting thread, which writes windows that end without a later line. It's
+    // always locked after the view.
+    let windows = (config.mode == config::Mode::WATCH && config.output == OutputFormat::Influx)
+        .then(|| {
+            let windows = Windows::new(config.interval, config.top, config.sort);
+            Arc::new(Mutex::new(windows))
+        });
+    if config.follow && config.mode == config::Mode::WATCH {
+        let view = Arc::clone(&view);
+        let config = Arc::clone(&config);
+        let statsd = statsd.clone();
+        let windows = windows.clone();
+        thread::spawn(move || loop {
+            thread::sleep(config.interval);
+            let mut view = view.lock().unwrap();
+            if let Some(windows) = &windows {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |now| now.as_secs() as i64);
+                if let Some(points) = windows.lock().unwrap().finish_ended(now, &mut view) {
+                    print!("{}", points);
+                }
+            }
+            // Keep following even if the metrics can't be written or sent this time
+            if let Err(err) = report(&mut view, &config, statsd.as_deref()) {
+                eprintln!("Couldn't export metrics: {}", err);
+            }
+        });
+    }
+    let mut export = config.sqlite.as_deref().map(Export::create).transpose()?;
+    let mut converter = Converter::new(&config.fields, config.output);
+    if config.mode == config::Mode::CONVERT && config.header {
+        println!("{}", converter.header());
+    }
+    let mut line = Vec::new();
 
     // Keep reading lines until we reach a line with 0 bytes
-    while reader.read_line(&mut line).unwrap() > 0 {
-        let logline = &line;
-        let log = nginx::get_log_from_logline(&logline).unwrap();
+    while reader.read_until(b'\n', &mut line).unwrap() > 0 {
+        // Lines that aren't UTF-8 or don't match the format are skipped and counted, rather than
+        // ending the run
+        let parsed = match (std::str::from_utf8(&line), &config.log_format) {
+            (Ok(logline), Some(format)) => format.parse(logline),
+            (Ok(logline), None) => nginx::get_log_from_logline(logline),
+            (Err(_), _) => Err(Error::ParsingError),
+        };
+        let Ok(mut log) = parsed else {
+            view.lock().unwrap().unparsed();
+            line.clear();
+            continue;
+        };
+        if let Some(real_ip) = &config.real_ip {
+            log.remote_addr = real_ip.resolve(&log);
+        }
+        if let Some(filter) = &config.filter {
+            if !filter.matches(&log) {
+                line.clear();
+                continue;
+            }
+        }
+        if let Some(exclusions) = &config.exclusions {
+            if exclusions.matches(&log) {
+                view.lock().unwrap().exclude_by_rule();
+                line.clear();
+                continue;
+            }
+        }
+        if config.exclude_bots && Agent::classify(log.http_user_agent).is_bot() {
+            view.lock().unwrap().exclude_bot();
+            line.clear();
+            continue;
+        }
+
+        let location = match &geoip {
+            Some(geoip) => geoip.locate(log.remote_addr),
+            None => Location::default(),
+        };
         if config.mode == config::Mode::WATCH {
-            view.update(log);
+            let mut view = view.lock().unwrap();
+            if let Some(windows) = &windows {
+                if let Some(points) = windows.lock().unwrap().advance(&log, &mut view) {
+                    print!("{}", points);
+                }
+            }
+            view.update(log, &location);
+        } else if let Some(export) = &mut export {
+            export.insert(&log)?;
         } else {
-            println!("{}", log);
+            println!("{}", converter.record(&log, &location));
         }
 
         line.clear();
@@ -46,8 +180,40 @@ pub fn run(config: Config) -> Result<(), io::Error> {
 
     // Finish by printing the parsing results
     if config.mode == config::Mode::WATCH {
-        println!("{}", view);
+        let mut view = view.lock().unwrap();
+        if let Some(points) = windows
+            .as_ref()
+            .and_then(|windows| windows.lock().unwrap().finish(&mut view))
+        {
+            print!("{}", points);
+        }
+        report(&mut view, &config, statsd.as_deref())?;
     };
+    if let (Some(export), Some(path)) = (export, &config.sqlite) {
+        let rows = export.finish()?;
+        eprintln!("Exported {} requests to {}", rows, path.display());
+    }
+
+    Ok(())
+}
 
+// Print the summary, write the metrics file if there is one, and send the counts since the last
+// report to StatsD
+fn report(view: &mut View, config: &Config, statsd: Option<&Statsd>) -> io::Result<()> {
+    match config.output {
+        OutputFormat::Json => println!("{}", view.to_json()),
+        // Influx points are written as each window of the log ends
+        OutputFormat::Influx => {}
+        _ => println!("{}", view),
+    }
+    if let Some(path) = &config.metrics_file {
+        metrics::write_file(path, view)?;
+    }
+    // Only taken for StatsD, since influx output takes the interval as each window ends
+    if let Some(statsd) = statsd {
+        if let Some(interval) = view.take_interval() {
+            statsd.send(&interval)?;
+        }
+    }
     Ok(())
 }
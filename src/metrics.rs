@@ -0,0 +1,201 @@
+use crate::stats::{LatencyHistogram, LATENCY_BOUNDS};
+use crate::view::View;
+use std::fmt::{self, Write};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// The Prometheus text format (version 0.0.4), which OpenMetrics scrapers also accept
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+// How long a client has to send its request, so that a slow one can't hold up the next scrape
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+// Most headers a request can have before we stop reading them
+const MAX_HEADERS: usize = 100;
+
+// Builds a page of metrics in the text exposition format
+pub struct Exposition {
+    out: String,
+}
+
+impl Exposition {
+    pub fn new() -> Exposition {
+        Exposition { out: String::new() }
+    }
+
+    // Start a metric family; its samples must follow before the next one starts
+    pub fn family(&mut self, name: &str, kind: &str, help: &str) {
+        writeln!(self.out, "# HELP {} {}", name, help).unwrap();
+        writeln!(self.out, "# TYPE {} {}", name, kind).unwrap();
+    }
+
+    pub fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl fmt::Display) {
+        self.out.push_str(name);
+        push_labels(&mut self.out, labels, None);
+        writeln!(self.out, " {}", value).unwrap();
+    }
+
+    // A histogram's cumulative buckets, sum, and count, for a family of type "histogram"
+    pub fn histogram(&mut self, name: &str, labels: &[(&str, &str)], latency: &LatencyHistogram) {
+        let mut cumulative = 0;
+        for (index, count) in latency.buckets.iter().enumerate() {
+            cumulative += count;
+            write!(self.out, "{}_bucket", name).unwrap();
+            let le = match LATENCY_BOUNDS.get(index) {
+                Some(bound) => bound.to_string(),
+                None => String::from("+Inf"),
+            };
+            push_labels(&mut self.out, labels, Some(&le));
+            writeln!(self.out, " {}", cumulative).unwrap();
+        }
+        self.sample(&format!("{}_sum", name), labels, latency.sum);
+        self.sample(&format!("{}_count", name), labels, latency.count);
+    }
+
+    pub fn finish(self) -> String {
+        self.out
+    }
+}
+
+// Write a label set, escaping backslashes, quotes and newlines in the values
+fn push_labels(out: &mut String, labels: &[(&str, &str)], le: Option<&str>) {
+    if labels.is_empty() && le.is_none() {
+        return;
+    }
+    out.push('{');
+    let le = le.map(|le| ("le", le));
+    for (index, (name, value)) in labels.iter().chain(le.iter()).enumerate() {
+        if index > 0 {
+            out.push(',');
+        }
+        write!(out, "{}=\"", name).unwrap();
+        for c in value.chars() {
+            match c {
+                '\\' => out.push_str("\\\\"),
+                '"' => out.push_str("\\\""),
+                '\n' => out.push_str("\\n"),
+                c => out.push(c),
+            }
+        }
+        out.push('"');
+    }
+    out.push('}');
+}
+
+// Serve the view's metrics at /metrics from a background thread, returning the bound address
+pub fn serve(addr: SocketAddr, view: Arc<Mutex<View>>) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(addr)?;
+    let local_addr = listener.local_addr()?;
+    thread::spawn(move || {
+        // Scrapes are infrequent, so connections are answered one at a time
+        for stream in listener.incoming().flatten() {
+            // A client that hangs up early doesn't affect anyone else
+            let _ = respond(stream, &view);
+        }
+    });
+    Ok(local_addr)
+}
+
+fn respond(mut stream: TcpStream, view: &Mutex<View>) -> io::Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // The request has no body, so once the headers are read it can be answered
+    let mut header = String::new();
+    for _ in 0..MAX_HEADERS {
+        header.clear();
+        if reader.read_line(&mut header)? == 0 || header.trim_end().is_empty() {
+            break;
+        }
+    }
+
+    let mut parts = request_line.split_ascii_whitespace();
+    let (method, target) = (parts.next(), parts.next());
+    let path = target.map(|target| target.split('?').next().unwrap_or(target));
+    let (status, body) = match (method, path) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", view.lock().unwrap().to_metrics()),
+        (Some("GET"), _) => ("404 Not Found", String::from("Not found, try /metrics\n")),
+        _ => ("405 Method Not Allowed", String::new()),
+    };
+    let content_type = match status {
+        "200 OK" => CONTENT_TYPE,
+        _ => "text/plain; charset=utf-8",
+    };
+    io::Write::write_all(
+        &mut stream,
+        format!(
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            content_type,
+            body.len(),
+            body
+        )
+        .as_bytes(),
+    )
+}
+
+// Replace a file with the view's metrics, for node_exporter's textfile collector. The metrics
+// are written to a temporary file next to it first, and then renamed over it, so a scrape never
+// sees a partly written file. The collector only reads *.prom files, so it skips the temporary one.
+pub fn write_file(path: &Path, view: &View) -> io::Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let mut file = File::create(&temporary)?;
+    io::Write::write_all(&mut file, view.to_metrics().as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&temporary, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_label_values() {
+        let mut exposition = Exposition::new();
+        exposition.family("requests_total", "counter", "Requests.");
+        exposition.sample("requests_total", &[("route", "/a\"b\\c\nd")], 3);
+        exposition.sample("requests_total", &[], 4);
+        assert_eq!(
+            exposition.finish(),
+            "# HELP requests_total Requests.\n\
+             # TYPE requests_total counter\n\
+             requests_total{route=\"/a\\\"b\\\\c\\nd\"} 3\n\
+             requests_total 4\n"
+        );
+    }
+
+    #[test]
+    fn histograms_are_cumulative() {
+        let mut latency = LatencyHistogram::new();
+        latency.record(0.001);
+        latency.record(0.0015);
+        latency.record(120.0);
+        let mut exposition = Exposition::new();
+        exposition.histogram("duration_seconds", &[("route", "/")], &latency);
+        let text = exposition.finish();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(
+            lines[0],
+            "duration_seconds_bucket{route=\"/\",le=\"0.001\"} 1"
+        );
+        assert_eq!(
+            lines[1],
+            "duration_seconds_bucket{route=\"/\",le=\"0.002\"} 2"
+        );
+        assert_eq!(
+            lines[25],
+            "duration_seconds_bucket{route=\"/\",le=\"60\"} 2"
+        );
+        assert_eq!(
+            lines[26],
+            "duration_seconds_bucket{route=\"/\",le=\"+Inf\"} 3"
+        );
+        assert_eq!(lines[27], "duration_seconds_sum{route=\"/\"} 120.0025");
+        assert_eq!(lines[28], "duration_seconds_count{route=\"/\"} 3");
+    }
+}
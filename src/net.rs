@@ -0,0 +1,249 @@
+use crate::parsers::nginx::NginxCombinedLog;
+use crate::Error;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+// A block of addresses, such as 10.0.0.0/8 or 2001:db8::/32
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Cidr {
+    pub network: IpAddr,
+    pub prefix: u8,
+}
+
+impl Cidr {
+    // The block of the given size that contains this address
+    pub fn containing(addr: IpAddr, prefix: u8) -> Cidr {
+        let network = match addr {
+            IpAddr::V4(v4) => IpAddr::V4(Ipv4Addr::from(u32::from(v4) & v4_mask(prefix))),
+            IpAddr::V6(v6) => IpAddr::V6(Ipv6Addr::from(u128::from(v6) & v6_mask(prefix))),
+        };
+        Cidr { network, prefix }
+    }
+
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.network, addr) {
+            (IpAddr::V4(_), IpAddr::V4(_)) | (IpAddr::V6(_), IpAddr::V6(_)) => {
+                Cidr::containing(addr, self.prefix).network == self.network
+            }
+            // Dual-stack listeners log IPv4 clients as ::ffff:a.b.c.d
+            (IpAddr::V4(_), IpAddr::V6(v6)) => match v6.to_ipv4_mapped() {
+                Some(v4) => self.contains(IpAddr::V4(v4)),
+                None => false,
+            },
+            (IpAddr::V6(_), IpAddr::V4(_)) => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = Error;
+    // A bare address is treated as a block containing only that address
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| Error::InvalidArgs)?;
+        let max_prefix = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().map_err(|_| Error::InvalidArgs)?,
+            None => max_prefix,
+        };
+        if prefix > max_prefix {
+            return Err(Error::InvalidArgs);
+        }
+        Ok(Cidr::containing(addr, prefix))
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+// The field that a trusted proxy reports the real client address in
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum RealIpHeader {
+    XForwardedFor,
+    RealipRemoteAddr,
+}
+
+impl FromStr for RealIpHeader {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "http_x_forwarded_for" => Ok(RealIpHeader::XForwardedFor),
+            "realip_remote_addr" => Ok(RealIpHeader::RealipRemoteAddr),
+            _ => Err(Error::InvalidArgs),
+        }
+    }
+}
+
+// Replaces the address of a trusted proxy with the client address it reports, following the
+// same rules as nginx's set_real_ip_from, real_ip_header and real_ip_recursive directives
+#[derive(Debug, PartialEq)]
+pub struct RealIp {
+    pub trusted: Vec<Cidr>,
+    pub header: RealIpHeader,
+    pub recursive: bool,
+}
+
+impl RealIp {
+    pub fn resolve<'a>(&self, log: &NginxCombinedLog<'a>) -> &'a str {
+        let remote_addr = log.remote_addr;
+        if !self.is_trusted(remote_addr) {
+            return remote_addr;
+        }
+        let header = match self.header {
+            RealIpHeader::XForwardedFor => log.http_x_forwarded_for,
+            RealIpHeader::RealipRemoteAddr => log.realip_remote_addr,
+        };
+
+        // Proxies append to the header, so the closest hop is on the right. Without recursion
+        // we take the last address; with it, we skip past every proxy we trust.
+        let mut client = remote_addr;
+        for hop in header.rsplit(',').map(str::trim) {
+            if hop.is_empty() || hop == "-" || parse_addr(hop).is_none() {
+                // Like nginx, a header we can't make sense of leaves the address alone
+                return remote_addr;
+            }
+            client = hop;
+            if !self.recursive || !self.is_trusted(hop) {
+                break;
+            }
+        }
+        client
+    }
+
+    fn is_trusted(&self, addr: &str) -> bool {
+        match parse_addr(addr) {
+            Some(addr) => self.trusted.iter().any(|cidr| cidr.contains(addr)),
+            None => false,
+        }
+    }
+}
+
+// Parse a client address from a log, ignoring anything that isn't an IP (like "unix:")
+pub fn parse_addr(addr: &str) -> Option<IpAddr> {
+    addr.parse().ok()
+}
+
+fn v4_mask(prefix: u8) -> u32 {
+    u32::MAX
+        .checked_shl(32 - prefix.min(32) as u32)
+        .unwrap_or(0)
+}
+
+fn v6_mask(prefix: u8) -> u128 {
+    u128::MAX
+        .checked_shl(128 - prefix.min(128) as u32)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn containing_masks_host_bits() {
+        assert_eq!(
+            Cidr::containing(addr("203.0.113.9"), 24).to_string(),
+            "203.0.113.0/24"
+        );
+        assert_eq!(
+            Cidr::containing(addr("2001:db8:1:2:3::4"), 64).to_string(),
+            "2001:db8:1:2::/64"
+        );
+        assert_eq!(
+            Cidr::containing(addr("10.1.2.3"), 0).to_string(),
+            "0.0.0.0/0"
+        );
+        assert_eq!(
+            Cidr::containing(addr("10.1.2.3"), 32).to_string(),
+            "10.1.2.3/32"
+        );
+    }
+
+    #[test]
+    fn parse_cidr() {
+        let cidr: Cidr = "10.1.2.3/8".parse().unwrap();
+        assert_eq!(cidr.to_string(), "10.0.0.0/8");
+        assert_eq!("10.0.0.1".parse::<Cidr>().unwrap().prefix, 32);
+        assert_eq!("::1".parse::<Cidr>().unwrap().prefix, 128);
+        assert_eq!("10.0.0.0/33".parse::<Cidr>(), Err(Error::InvalidArgs));
+        assert_eq!("example.com/8".parse::<Cidr>(), Err(Error::InvalidArgs));
+    }
+
+    #[test]
+    fn contains_addresses_in_block() {
+        let v4: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert!(v4.contains(addr("10.20.30.40")));
+        assert!(!v4.contains(addr("11.0.0.1")));
+        assert!(v4.contains(addr("::ffff:10.0.0.1")));
+        assert!(!v4.contains(addr("2001:db8::1")));
+
+        let v6: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(v6.contains(addr("2001:db8:ffff::1")));
+        assert!(!v6.contains(addr("10.0.0.1")));
+    }
+
+    fn real_ip(recursive: bool) -> RealIp {
+        RealIp {
+            trusted: vec!["10.0.0.0/8".parse().unwrap()],
+            header: RealIpHeader::XForwardedFor,
+            recursive,
+        }
+    }
+
+    fn resolve<'a>(real_ip: &RealIp, remote_addr: &'a str, xff: &'a str) -> &'a str {
+        real_ip.resolve(&NginxCombinedLog {
+            remote_addr,
+            http_x_forwarded_for: xff,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn untrusted_remote_addr_is_kept() {
+        assert_eq!(
+            resolve(&real_ip(true), "198.51.100.7", "203.0.113.9"),
+            "198.51.100.7"
+        );
+    }
+
+    #[test]
+    fn takes_last_hop_without_recursion() {
+        let chain = "203.0.113.9, 198.51.100.7, 10.0.0.3";
+        assert_eq!(resolve(&real_ip(false), "10.0.0.2", chain), "10.0.0.3");
+    }
+
+    #[test]
+    fn skips_trusted_hops_with_recursion() {
+        let chain = "203.0.113.9, 198.51.100.7, 10.0.0.3";
+        assert_eq!(resolve(&real_ip(true), "10.0.0.2", chain), "198.51.100.7");
+        // If every hop is trusted, the leftmost one is the best we have
+        assert_eq!(
+            resolve(&real_ip(true), "10.0.0.2", "10.0.0.4,10.0.0.3"),
+            "10.0.0.4"
+        );
+    }
+
+    #[test]
+    fn missing_or_invalid_header_is_ignored() {
+        assert_eq!(resolve(&real_ip(true), "10.0.0.2", "-"), "10.0.0.2");
+        assert_eq!(resolve(&real_ip(true), "10.0.0.2", ""), "10.0.0.2");
+        assert_eq!(
+            resolve(&real_ip(true), "10.0.0.2", "203.0.113.9, unknown"),
+            "10.0.0.2"
+        );
+    }
+}
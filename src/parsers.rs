@@ -1 +1,3 @@
+pub mod format;
 pub mod nginx;
+pub mod url;
@@ -0,0 +1,239 @@
+use crate::parsers::nginx::{self, NginxCombinedLog};
+use crate::Error;
+
+// A custom nginx log_format, compiled into the literal text and variables that make up a line
+// Every variable has to be followed by some literal text (or be the last thing on the line),
+// which is how we know where its value stops. nginx escapes quotes and control characters in
+// values, so the usual `"` delimiters can't show up inside them.
+#[derive(Debug, PartialEq)]
+pub struct LogFormat {
+    tokens: Vec<Token>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Literal(String),
+    Variable(Variable),
+}
+
+// The variables we know how to use; anything else is matched but ignored
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Variable {
+    RemoteAddr,
+    RemoteUser,
+    TimeLocal,
+    Request,
+    Status,
+    BodyBytesSent,
+    HttpReferer,
+    HttpUserAgent,
+    RequestTime,
+    HttpXForwardedFor,
+    RealipRemoteAddr,
+    Host,
+    Ignored,
+}
+
+impl Variable {
+    fn from_name(name: &str) -> Variable {
+        match name {
+            "remote_addr" => Variable::RemoteAddr,
+            "remote_user" => Variable::RemoteUser,
+            "time_local" => Variable::TimeLocal,
+            "request" => Variable::Request,
+            "status" => Variable::Status,
+            "body_bytes_sent" => Variable::BodyBytesSent,
+            "http_referer" => Variable::HttpReferer,
+            "http_user_agent" => Variable::HttpUserAgent,
+            "request_time" => Variable::RequestTime,
+            "http_x_forwarded_for" => Variable::HttpXForwardedFor,
+            "realip_remote_addr" => Variable::RealipRemoteAddr,
+            "host" => Variable::Host,
+            _ => Variable::Ignored,
+        }
+    }
+}
+
+impl LogFormat {
+    // Compile a format written the same way as nginx's log_format directive, e.g.
+    // '$remote_addr - $remote_user [$time_local] "$request" $status'
+    pub fn new(format: &str) -> Result<LogFormat, Error> {
+        let mut tokens: Vec<Token> = Vec::new();
+        let mut rest = format;
+        while !rest.is_empty() {
+            let token = match rest.strip_prefix('$') {
+                Some(after) => {
+                    // Variables are either $name or ${name}
+                    let (name, after) = match after.strip_prefix('{') {
+                        Some(braced) => braced.split_once('}').ok_or(Error::InvalidArgs)?,
+                        None => {
+                            let end = after
+                                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                                .unwrap_or(after.len());
+                            (&after[..end], &after[end..])
+                        }
+                    };
+                    if name.is_empty() {
+                        return Err(Error::InvalidArgs);
+                    }
+                    rest = after;
+                    Token::Variable(Variable::from_name(name))
+                }
+                None => {
+                    let end = rest.find('$').unwrap_or(rest.len());
+                    let literal = &rest[..end];
+                    rest = &rest[end..];
+                    Token::Literal(String::from(literal))
+                }
+            };
+
+            // Two variables in a row would leave us no way to tell where one ends
+            if let (Some(Token::Variable(_)), Token::Variable(_)) = (tokens.last(), &token) {
+                return Err(Error::InvalidArgs);
+            }
+            tokens.push(token);
+        }
+
+        Ok(LogFormat { tokens })
+    }
+
+    // Whether lines in this format include a variable that we know how to use
+    pub fn has_variable(&self, name: &str) -> bool {
+        let variable = Variable::from_name(name);
+        variable != Variable::Ignored && self.tokens.contains(&Token::Variable(variable))
+    }
+
+    pub fn parse<'a>(&self, logline: &'a str) -> Result<NginxCombinedLog<'a>, Error> {
+        let mut log = NginxCombinedLog::default();
+        let mut rest = logline.trim_end_matches(['\r', '\n']);
+
+        for (index, token) in self.tokens.iter().enumerate() {
+            match token {
+                Token::Literal(literal) => {
+                    rest = rest
+                        .strip_prefix(literal.as_str())
+                        .ok_or(Error::ParsingError)?;
+                }
+                Token::Variable(variable) => {
+                    // The value runs up to wherever the next literal starts
+                    let end = match self.tokens.get(index + 1) {
+                        Some(Token::Literal(literal)) => {
+                            rest.find(literal.as_str()).ok_or(Error::ParsingError)?
+                        }
+                        _ => rest.len(),
+                    };
+                    set_variable(&mut log, *variable, &rest[..end])?;
+                    rest = &rest[end..];
+                }
+            }
+        }
+
+        Ok(log)
+    }
+}
+
+fn set_variable<'a>(
+    log: &mut NginxCombinedLog<'a>,
+    variable: Variable,
+    value: &'a str,
+) -> Result<(), Error> {
+    match variable {
+        Variable::RemoteAddr => log.remote_addr = value,
+        Variable::RemoteUser => log.remote_user = value,
+        Variable::TimeLocal => log.time_local = value,
+        Variable::Request => {
+            let (method, request_url, request_path, request_query) = nginx::parse_request(value);
+            log.request = value;
+            log.method = method;
+            log.request_url = request_url;
+            log.request_path = request_path;
+            log.request_query = request_query;
+        }
+        Variable::Status => log.status = value.parse().map_err(|_| Error::ParsingError)?,
+        Variable::BodyBytesSent => {
+            log.body_bytes_sent = value.parse().map_err(|_| Error::ParsingError)?
+        }
+        Variable::HttpReferer => log.http_referer = value,
+        Variable::HttpUserAgent => log.http_user_agent = value,
+        // nginx logs "-" for the request time if there wasn't one, e.g. for upstream times
+        Variable::RequestTime => log.request_time = value.parse().ok(),
+        Variable::HttpXForwardedFor => log.http_x_forwarded_for = value,
+        Variable::RealipRemoteAddr => log.realip_remote_addr = value,
+        Variable::Host => log.host = value,
+        Variable::Ignored => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COMBINED: &str = r#"$remote_addr - $remote_user [$time_local] "$request" $status $body_bytes_sent "$http_referer" "$http_user_agent""#;
+
+    #[test]
+    fn combined_format_matches_builtin_parser() {
+        let logline = r#"192.167.1.100 - - [09/May/2022:00:00:07 +0000] "GET /search?q=a HTTP/1.1" 304 7030 "-" "Mozilla/5.0 (Windows NT 10.0; Win64; x64)""#;
+        let format = LogFormat::new(COMBINED).unwrap();
+        let log = format.parse(logline).unwrap();
+        let builtin = nginx::get_log_from_logline(logline).unwrap();
+
+        assert_eq!(log.remote_addr, builtin.remote_addr);
+        assert_eq!(log.remote_user, builtin.remote_user);
+        assert_eq!(log.time_local, builtin.time_local);
+        assert_eq!(log.request, builtin.request);
+        assert_eq!(log.request_path, builtin.request_path);
+        assert_eq!(log.request_query, builtin.request_query);
+        assert_eq!(log.status, builtin.status);
+        assert_eq!(log.body_bytes_sent, builtin.body_bytes_sent);
+        assert_eq!(log.http_referer, builtin.http_referer);
+        assert_eq!(log.http_user_agent, builtin.http_user_agent);
+    }
+
+    #[test]
+    fn parses_extra_variables() {
+        let format = LogFormat::new(
+            r#"$remote_addr [$time_local] "$request" $status ${body_bytes_sent} "$http_x_forwarded_for" $upstream_addr $request_time"#,
+        )
+        .unwrap();
+        let logline = "10.0.0.2 [09/May/2022:00:00:07 +0000] \"GET / HTTP/1.1\" 200 512 \"203.0.113.9, 10.0.0.3\" 10.0.1.1:80 0.042\n";
+        let log = format.parse(logline).unwrap();
+
+        assert_eq!(log.remote_addr, "10.0.0.2");
+        assert_eq!(log.request_path, Some("/"));
+        assert_eq!(log.body_bytes_sent, 512);
+        assert_eq!(log.http_x_forwarded_for, "203.0.113.9, 10.0.0.3");
+        assert_eq!(log.request_time, Some(0.042));
+        assert!(format.has_variable("http_x_forwarded_for"));
+        assert!(!format.has_variable("http_user_agent"));
+        assert!(!format.has_variable("upstream_addr"));
+    }
+
+    #[test]
+    fn parses_host() {
+        let format = LogFormat::new(r#"$host $remote_addr "$request" $status"#).unwrap();
+        let log = format
+            .parse(r#"shop.example.com 10.0.0.2 "GET /cart HTTP/1.1" 200"#)
+            .unwrap();
+        assert_eq!(log.host, "shop.example.com");
+        assert_eq!(log.request_path, Some("/cart"));
+    }
+
+    #[test]
+    fn rejects_adjacent_variables() {
+        assert_eq!(
+            LogFormat::new("$remote_addr$status"),
+            Err(Error::InvalidArgs)
+        );
+        assert_eq!(LogFormat::new("${status"), Err(Error::InvalidArgs));
+    }
+
+    #[test]
+    fn mismatched_line_is_a_parsing_error() {
+        let format = LogFormat::new(COMBINED).unwrap();
+        assert_eq!(
+            format.parse("not an access log").unwrap_err(),
+            Error::ParsingError
+        );
+    }
+}
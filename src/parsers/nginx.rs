@@ -1,5 +1,6 @@
+use crate::parsers::url;
 use crate::Error;
-use std::{fmt, str::FromStr};
+use std::{borrow::Cow, fmt, str::FromStr};
 
 #[derive(Debug, Default)]
 pub struct NginxCombinedLog<'a> {
@@ -8,14 +9,22 @@ pub struct NginxCombinedLog<'a> {
     pub time_local: &'a str,
     pub method: Option<HttpMethod>,
     pub request_url: Option<&'a str>,
+    pub request_path: Option<&'a str>,
+    pub request_query: Option<&'a str>,
     pub request: &'a str,
     pub status: u32,
     pub body_bytes_sent: u32,
     pub http_referer: &'a str,
     pub http_user_agent: &'a str,
+    pub request_time: Option<f64>,
+    // Only available with a custom log format
+    pub http_x_forwarded_for: &'a str,
+    pub realip_remote_addr: &'a str,
+    pub host: &'a str,
 }
 
 #[derive(Debug, Clone, Copy)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum HttpMethod {
     GET,
     HEAD,
@@ -28,6 +37,35 @@ pub enum HttpMethod {
     PATCH,
 }
 
+impl HttpMethod {
+    // In declaration order, so that `method as usize` is its index
+    pub const ALL: [HttpMethod; 9] = [
+        HttpMethod::GET,
+        HttpMethod::HEAD,
+        HttpMethod::POST,
+        HttpMethod::PUT,
+        HttpMethod::DELETE,
+        HttpMethod::CONNECT,
+        HttpMethod::OPTIONS,
+        HttpMethod::TRACE,
+        HttpMethod::PATCH,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HttpMethod::GET => "GET",
+            HttpMethod::HEAD => "HEAD",
+            HttpMethod::POST => "POST",
+            HttpMethod::PUT => "PUT",
+            HttpMethod::DELETE => "DELETE",
+            HttpMethod::CONNECT => "CONNECT",
+            HttpMethod::OPTIONS => "OPTIONS",
+            HttpMethod::TRACE => "TRACE",
+            HttpMethod::PATCH => "PATCH",
+        }
+    }
+}
+
 impl fmt::Display for HttpMethod {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{:?}", self)
@@ -52,52 +90,35 @@ impl FromStr for HttpMethod {
     }
 }
 
-impl<'a> fmt::Display for NginxCombinedLog<'a> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let method = self.method.map(|x| x.to_string()).unwrap_or("".to_string());
-        let url = self
-            .request_url
-            .map(|x| x.to_string())
-            .unwrap_or("".to_string());
-        write!(
-            f,
-            r#"{}"{}"{}"{}"{}"#,
-            self.remote_addr, self.time_local, method, url, self.status,
-        )
-    }
-}
-
-pub fn get_log_from_logline(logline: &str) -> Result<NginxCombinedLog, Error> {
+pub fn get_log_from_logline(logline: &str) -> Result<NginxCombinedLog<'_>, Error> {
     // Break each field into its own slice of the original logline
-    let (remote_addr, rest) = split_at_whitespace(&logline)?;
-    let (dash, rest) = split_at_whitespace(&rest[1..])?;
-    assert_char_eq(b'-', dash.as_bytes()[0])?;
-    let (remote_user, rest) = split_at_whitespace(&rest[1..])?;
-    assert_char_eq(b'[', rest.as_bytes()[1])?;
-    let (time_local, rest) = split_at_ascii_char(b']', &rest[2..])?;
-    let (request, rest) = split_at_ascii_char(b'"', &rest[3..])?;
-    let (status_str, rest) = split_at_whitespace(&rest[2..])?;
-    let (body_bytes_sent_str, rest) = split_at_whitespace(&rest[1..])?;
-    let (http_referer, rest) = split_at_ascii_char(b'"', &rest[2..])?;
-    let http_user_agent = split_at_ascii_char(b'"', &rest[3..])?.0;
-
-    // These are provided by nginx, so a properly formatted log will always have them
-    let status: u32 = status_str
-        .trim()
-        .parse()
-        .expect("Status code is not a valid integer.");
+    // Lines can be cut short or mangled, so every offset is checked rather than indexed
+    let (remote_addr, rest) = split_at_whitespace(logline)?;
+    let (dash, rest) = split_at_whitespace(skip(rest, 1)?)?;
+    assert_char_eq(b'-', dash.as_bytes().first())?;
+    let (remote_user, rest) = split_at_whitespace(skip(rest, 1)?)?;
+    assert_char_eq(b'[', rest.as_bytes().get(1))?;
+    let (time_local, rest) = split_at_ascii_char(b']', skip(rest, 2)?)?;
+    let (request, rest) = split_at_ascii_char(b'"', skip(rest, 3)?)?;
+    let (status_str, rest) = split_at_whitespace(skip(rest, 2)?)?;
+    let (body_bytes_sent_str, rest) = split_at_whitespace(skip(rest, 1)?)?;
+    let (http_referer, rest) = split_at_ascii_char(b'"', skip(rest, 2)?)?;
+    let (http_user_agent, rest) = split_at_ascii_char(b'"', skip(rest, 3)?)?;
+
+    // A common extension of the combined format appends $request_time, so use it if it's there
+    let request_time = skip(rest, 1)?
+        .split_ascii_whitespace()
+        .next()
+        .and_then(|x| x.parse().ok());
+
+    // These are provided by nginx, so a line where they aren't numbers is in another format
+    let status: u32 = status_str.trim().parse().map_err(|_| Error::ParsingError)?;
     let body_bytes_sent: u32 = body_bytes_sent_str
         .trim()
         .parse()
-        .expect("Bytes sent is not a valid integer.");
+        .map_err(|_| Error::ParsingError)?;
 
-    // Attempt to parse the HTTP method and request URL
-    // These come from the internet, and therefore might be malformed
-    let (method, request_url) = split_at_whitespace(&request).map_or((None, None), |tup| {
-        let method = HttpMethod::from_str(tup.0).ok();
-        let request_url = split_at_whitespace(&tup.1[1..]).map(|x| x.0).ok();
-        (method, request_url)
-    });
+    let (method, request_url, request_path, request_query) = parse_request(request);
 
     Ok(NginxCombinedLog {
         remote_addr,
@@ -105,22 +126,141 @@ pub fn get_log_from_logline(logline: &str) -> Result<NginxCombinedLog, Error> {
         time_local,
         method,
         request_url,
+        request_path,
+        request_query,
         request,
         status,
         body_bytes_sent,
         http_referer,
         http_user_agent,
+        request_time,
+        ..Default::default()
     })
 }
 
-fn assert_char_eq(expected: u8, actual: u8) -> Result<(), Error> {
+// Split the request line into the method, the URL, and the path and query string of that URL
+pub fn parse_request(
+    request: &str,
+) -> (Option<HttpMethod>, Option<&str>, Option<&str>, Option<&str>) {
+    // Attempt to parse the HTTP method and request URL
+    // These come from the internet, and therefore might be malformed
+    let (method, request_url) = split_at_whitespace(request).map_or((None, None), |tup| {
+        let method = HttpMethod::from_str(tup.0).ok();
+        let request_url = split_at_whitespace(&tup.1[1..]).map(|x| x.0).ok();
+        (method, request_url)
+    });
+
+    // Routes are aggregated by path, so keep the query string separate
+    let (request_path, request_query) = match request_url.map(url::split_query) {
+        Some((path, query)) => (Some(path), query),
+        None => (None, None),
+    };
+
+    (method, request_url, request_path, request_query)
+}
+
+// Undo nginx's escaping of quotes, backslashes, and non-printable bytes as "\xHH". Bytes that
+// don't form valid UTF-8 once decoded are replaced with U+FFFD.
+pub fn unescape(s: &str) -> Cow<'_, str> {
+    if !s.contains("\\x") {
+        return Cow::Borrowed(s);
+    }
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        let escape = match bytes.get(index..index + 2) {
+            Some(b"\\x") => url::decode_hex_pair(bytes.get(index + 2..index + 4)),
+            _ => None,
+        };
+        match escape {
+            Some(byte) => {
+                decoded.push(byte);
+                index += 4;
+            }
+            None => {
+                decoded.push(bytes[index]);
+                index += 1;
+            }
+        }
+    }
+    Cow::Owned(String::from_utf8_lossy(&decoded).into_owned())
+}
+
+// Convert a $time_local timestamp, e.g. "09/May/2022:00:00:07 +0000", to ISO 8601, e.g.
+// "2022-05-09T00:00:07+00:00"
+pub fn iso_8601(time_local: &str) -> Option<String> {
+    let (year, month, day, time, offset) = split_time_local(time_local)?;
+    Some(format!(
+        "{}-{:02}-{}T{}{}:{}",
+        year,
+        month,
+        day,
+        time,
+        &offset[..3],
+        &offset[3..]
+    ))
+}
+
+// Seconds since the Unix epoch of a $time_local timestamp
+pub fn unix_time(time_local: &str) -> Option<i64> {
+    let (year, month, day, time, offset) = split_time_local(time_local)?;
+    // Every part was checked to be digits, so they all parse
+    let number = |digits: &str| digits.parse::<i64>().unwrap();
+    let (year, month, day) = (number(year), month as i64, number(day));
+    let seconds = time
+        .split(':')
+        .fold(0, |seconds, part| seconds * 60 + number(part));
+    let offset_seconds = (number(&offset[1..3]) * 60 + number(&offset[3..])) * 60;
+    let offset_seconds = match &offset[..1] {
+        "-" => -offset_seconds,
+        _ => offset_seconds,
+    };
+
+    // Days since 1970-01-01, counting years from March so that leap days come last
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+    Some(days * 86400 + seconds - offset_seconds)
+}
+
+// The year, month (1-12), day, time and UTC offset of a valid $time_local timestamp
+fn split_time_local(time_local: &str) -> Option<(&str, usize, &str, &str, &str)> {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let (date, rest) = time_local.split_once(':')?;
+    let (time, offset) = rest.split_once(' ')?;
+    let mut date = date.split('/');
+    let (day, month, year) = (date.next()?, date.next()?, date.next()?);
+    let month = MONTHS.iter().position(|&name| name == month)? + 1;
+    let is_digits = |s: &str, len: usize| s.len() == len && s.bytes().all(|c| c.is_ascii_digit());
+    let valid = is_digits(day, 2)
+        && is_digits(year, 4)
+        && time.len() == 8
+        && time.split(':').all(|part| is_digits(part, 2))
+        && offset.len() == 5
+        && matches!(offset.as_bytes()[0], b'+' | b'-')
+        && is_digits(&offset[1..], 4);
+    valid.then_some((year, month, day, time, offset))
+}
+
+fn assert_char_eq(expected: u8, actual: Option<&u8>) -> Result<(), Error> {
     // TODO: Add message explaining what character was missing
-    match expected == actual {
+    match actual == Some(&expected) {
         true => Ok(()),
         false => Err(Error::ParsingError),
     }
 }
 
+// The rest of a string after its first n bytes, if it's that long and they end on a character
+fn skip(s: &str, n: usize) -> Result<&str, Error> {
+    s.get(n..).ok_or(Error::ParsingError)
+}
+
 fn split_at_ascii_char(stop_char: u8, s: &str) -> Result<(&str, &str), Error> {
     s.as_bytes()
         .iter()
@@ -132,7 +272,7 @@ fn split_at_ascii_char(stop_char: u8, s: &str) -> Result<(&str, &str), Error> {
 fn split_at_whitespace(s: &str) -> Result<(&str, &str), Error> {
     s.as_bytes()
         .iter()
-        .position(|item| u8::is_ascii_whitespace(item))
+        .position(u8::is_ascii_whitespace)
         .ok_or(Error::ParsingError)
         .map(|index| (&s[..index], &s[index..]))
 }
@@ -141,6 +281,38 @@ fn split_at_whitespace(s: &str) -> Result<(&str, &str), Error> {
 mod tests {
     use super::*;
 
+    #[test]
+    fn unescapes_nginx_escapes() {
+        assert!(matches!(unescape("curl/7.79.1"), Cow::Borrowed(_)));
+        assert_eq!(unescape(r"say \x22hi\x22 \x5Cx41"), r#"say "hi" \x41"#);
+        assert_eq!(unescape(r"caf\xC3\xA9"), "café");
+        assert_eq!(unescape(r"\xFF \xZZ \x4"), "\u{FFFD} \\xZZ \\x4");
+    }
+
+    #[test]
+    fn converts_times_to_iso_8601() {
+        assert_eq!(
+            iso_8601("09/May/2022:00:00:07 +0000"),
+            Some(String::from("2022-05-09T00:00:07+00:00"))
+        );
+        assert_eq!(
+            iso_8601("31/Dec/2021:23:59:59 -0530"),
+            Some(String::from("2021-12-31T23:59:59-05:30"))
+        );
+        assert_eq!(iso_8601("09/Mai/2022:00:00:07 +0000"), None);
+        assert_eq!(iso_8601("9/May/2022:00:00:07 +0000"), None);
+        assert_eq!(iso_8601("09/May/2022:00:00:07"), None);
+    }
+
+    #[test]
+    fn converts_times_to_unix_time() {
+        assert_eq!(unix_time("09/May/2022:00:00:07 +0000"), Some(1652054407));
+        assert_eq!(unix_time("31/Dec/2021:23:59:59 -0530"), Some(1641014999));
+        assert_eq!(unix_time("29/Feb/2024:14:00:00 +0200"), Some(1709208000));
+        assert_eq!(unix_time("01/Jan/1970:00:00:00 +0000"), Some(0));
+        assert_eq!(unix_time("09/May/2022:00:00:07"), None);
+    }
+
     #[test]
     fn parse_logline() {
         let logline = r#"192.167.1.100 - - [09/May/2022:00:00:07 +0000] "GET / HTTP/1.1" 304 7030 "-" "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/101.0.4951.54 Safari/537.36""#;
@@ -150,10 +322,32 @@ mod tests {
         assert_eq!(log.remote_user, "-");
         assert_eq!(log.time_local, "09/May/2022:00:00:07 +0000");
         assert_eq!(log.request, "GET / HTTP/1.1");
+        assert_eq!(log.request_path, Some("/"));
+        assert_eq!(log.request_query, None);
         assert_eq!(log.status, 304);
         assert_eq!(log.body_bytes_sent, 7030);
         assert_eq!(log.http_referer, "-");
         assert_eq!(log.http_user_agent, "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/101.0.4951.54 Safari/537.36");
+        assert_eq!(log.request_time, None);
+    }
+
+    #[test]
+    fn parse_logline_with_request_time() {
+        let logline = r#"192.167.1.100 - - [09/May/2022:00:00:07 +0000] "GET / HTTP/1.1" 200 7030 "-" "curl/7.79.1" 0.125"#;
+        let log = get_log_from_logline(logline).unwrap();
+
+        assert_eq!(log.http_user_agent, "curl/7.79.1");
+        assert_eq!(log.request_time, Some(0.125));
+    }
+
+    #[test]
+    fn parse_logline_with_query() {
+        let logline = r#"192.167.1.100 - - [09/May/2022:00:00:07 +0000] "GET /search?q=a&page=2 HTTP/1.1" 200 7030 "-" "curl/7.79.1""#;
+        let log = get_log_from_logline(logline).unwrap();
+
+        assert_eq!(log.request_url, Some("/search?q=a&page=2"));
+        assert_eq!(log.request_path, Some("/search"));
+        assert_eq!(log.request_query, Some("q=a&page=2"));
     }
 
     #[test]
@@ -165,6 +359,35 @@ mod tests {
         );
     }
 
+    #[test]
+    fn parse_logline_truncated() {
+        let logline = r#"192.167.1.100 - - [09/May/2022:00:00:07 +0000] "GET / HTTP/1.1" 200 7030 "-" "curl/7.79.1""#;
+        // Every prefix of the line is either rejected or parsed, and none of them panic
+        for end in 0..logline.len() {
+            let _ = get_log_from_logline(&logline[..end]);
+        }
+        for logline in ["a - b", "a - b ", "a -", "a - - [", "a - - [é"] {
+            assert_eq!(
+                get_log_from_logline(logline).unwrap_err(),
+                Error::ParsingError
+            );
+        }
+    }
+
+    #[test]
+    fn parse_logline_non_numeric_status_and_bytes() {
+        let lines = [
+            r#"192.167.1.100 - - [09/May/2022:00:00:07 +0000] "GET / HTTP/1.1" OK 7030 "-" "curl""#,
+            r#"192.167.1.100 - - [09/May/2022:00:00:07 +0000] "GET / HTTP/1.1" 200 - "-" "curl""#,
+        ];
+        for logline in lines {
+            assert_eq!(
+                get_log_from_logline(logline).unwrap_err(),
+                Error::ParsingError
+            );
+        }
+    }
+
     #[test]
     fn parse_logline_invalid_time() {
         let logline = r#"192.167.1.100 x x 09/May/2022:00:00:07 +0000 "GET / HTTP/1.1" 304 7030 "-" "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/101.0.4951.54 Safari/537.36""#;
@@ -0,0 +1,246 @@
+use std::borrow::Cow;
+
+// Split a request URL into its path and (optional) query string
+// The query string does not include the leading "?"
+pub fn split_query(url: &str) -> (&str, Option<&str>) {
+    match url.as_bytes().iter().position(|&c| c == b'?') {
+        Some(index) => (&url[..index], Some(&url[index + 1..])),
+        None => (url, None),
+    }
+}
+
+// Iterate over the parameter names in a query string, skipping any empty ones
+// Values are never returned, so that they can't accidentally end up in a report
+pub fn query_param_names(query: &str) -> impl Iterator<Item = &str> {
+    query
+        .split('&')
+        .map(|pair| pair.split('=').next().unwrap_or(""))
+        .filter(|name| !name.is_empty())
+}
+
+// Canonicalize a request path, so that different spellings of the same path count as one route
+// Escaped unreserved characters are decoded, the remaining escapes are uppercased, duplicate
+// slashes are collapsed, and "." and ".." segments are removed (RFC 3986, section 6.2.2)
+// The original path is borrowed if it was already canonical
+pub fn canonicalize_path(path: &str) -> Cow<'_, str> {
+    // Absolute-form ("http://...") and asterisk-form ("*") targets are left alone
+    if !path.starts_with('/') || is_canonical(path) {
+        return Cow::Borrowed(path);
+    }
+
+    let canonical = remove_dot_segments(&normalize_percent_encoding(path));
+    match canonical == path {
+        true => Cow::Borrowed(path),
+        false => Cow::Owned(canonical),
+    }
+}
+
+// The domain a referer points to, e.g. "example.com" for "https://www.Example.com:443/page"
+// Hosts are lowercased and a leading "www." is dropped, so that variants group together
+pub fn referrer_domain(referer: &str) -> Option<Cow<'_, str>> {
+    let (_, rest) = referer.split_once("://")?;
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let host = authority.rsplit_once('@').map_or(authority, |x| x.1);
+    let host = match host.strip_prefix('[') {
+        // IPv6 addresses are bracketed, since they contain colons themselves
+        Some(v6) => v6.split_once(']')?.0,
+        None => host.split(':').next()?,
+    };
+    let host = match host.get(..4) {
+        Some(www) if www.eq_ignore_ascii_case("www.") => &host[4..],
+        _ => host,
+    };
+    if host.is_empty() {
+        return None;
+    }
+    match host.bytes().any(|b| b.is_ascii_uppercase()) {
+        true => Some(Cow::Owned(host.to_ascii_lowercase())),
+        false => Some(Cow::Borrowed(host)),
+    }
+}
+
+// Whether a domain is one of ours, or a subdomain of one of ours
+pub fn is_own_domain(domain: &str, own_domains: &[String]) -> bool {
+    own_domains.iter().any(|own| {
+        domain
+            .strip_suffix(own.as_str())
+            .is_some_and(|rest| rest.is_empty() || rest.ends_with('.'))
+    })
+}
+
+// Quick check for paths that can't change, so that the common case doesn't allocate
+fn is_canonical(path: &str) -> bool {
+    !path.contains('%')
+        && !path.contains("//")
+        && !path.ends_with("/.")
+        && !path.ends_with("/..")
+        && !path.contains("/./")
+        && !path.contains("/../")
+}
+
+fn normalize_percent_encoding(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut normalized = String::with_capacity(path.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        let escape = match bytes[index] {
+            b'%' => decode_hex_pair(bytes.get(index + 1..index + 3)),
+            _ => None,
+        };
+        match escape {
+            Some(byte) if is_unreserved(byte) => normalized.push(byte as char),
+            Some(byte) => {
+                normalized.push('%');
+                normalized.push_str(&format!("{:02X}", byte));
+            }
+            None => {
+                // Copy everything up to the next escape as-is
+                let next = bytes[index + 1..]
+                    .iter()
+                    .position(|&c| c == b'%')
+                    .map_or(bytes.len(), |offset| index + 1 + offset);
+                normalized.push_str(&path[index..next]);
+                index = next;
+                continue;
+            }
+        }
+        index += 3;
+    }
+    normalized
+}
+
+pub fn decode_hex_pair(pair: Option<&[u8]>) -> Option<u8> {
+    let pair = std::str::from_utf8(pair?).ok()?;
+    match pair.bytes().all(|c| c.is_ascii_hexdigit()) {
+        true => u8::from_str_radix(pair, 16).ok(),
+        false => None,
+    }
+}
+
+fn is_unreserved(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~')
+}
+
+// Resolve "." and ".." segments and drop empty segments (which collapses duplicate slashes)
+// A trailing slash is kept, since "/api/" and "/api" are often handled differently
+fn remove_dot_segments(path: &str) -> String {
+    let mut segments: Vec<&str> = Vec::new();
+    let mut trailing_slash = false;
+    for segment in path.split('/').skip(1) {
+        trailing_slash = true;
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            _ => {
+                segments.push(segment);
+                trailing_slash = false;
+            }
+        }
+    }
+
+    let mut canonical = String::with_capacity(path.len());
+    for segment in &segments {
+        canonical.push('/');
+        canonical.push_str(segment);
+    }
+    if trailing_slash || segments.is_empty() {
+        canonical.push('/');
+    }
+    canonical
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_query_without_query() {
+        assert_eq!(split_query("/api/user"), ("/api/user", None));
+    }
+
+    #[test]
+    fn split_query_with_query() {
+        assert_eq!(
+            split_query("/search?q=a&page=2"),
+            ("/search", Some("q=a&page=2"))
+        );
+    }
+
+    #[test]
+    fn split_query_with_empty_query() {
+        assert_eq!(split_query("/search?"), ("/search", Some("")));
+    }
+
+    #[test]
+    fn canonicalize_borrows_canonical_paths() {
+        assert!(matches!(canonicalize_path("/api/user"), Cow::Borrowed(_)));
+        assert!(matches!(canonicalize_path("/api/%2F"), Cow::Borrowed(_)));
+        assert!(matches!(canonicalize_path("*"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn canonicalize_decodes_unreserved_characters() {
+        assert_eq!(canonicalize_path("/%61pi"), "/api");
+        assert_eq!(canonicalize_path("/a%2Db%7e"), "/a-b~");
+    }
+
+    #[test]
+    fn canonicalize_uppercases_reserved_escapes() {
+        assert_eq!(canonicalize_path("/a%2fb"), "/a%2Fb");
+        assert_eq!(canonicalize_path("/a%zz"), "/a%zz");
+        assert_eq!(canonicalize_path("/a%2"), "/a%2");
+    }
+
+    #[test]
+    fn canonicalize_collapses_slashes() {
+        assert_eq!(canonicalize_path("/api//user"), "/api/user");
+        assert_eq!(canonicalize_path("//"), "/");
+        assert_eq!(canonicalize_path("/api//"), "/api/");
+    }
+
+    #[test]
+    fn canonicalize_removes_dot_segments() {
+        assert_eq!(canonicalize_path("/api/./user"), "/api/user");
+        assert_eq!(canonicalize_path("/api/v1/../user"), "/api/user");
+        assert_eq!(canonicalize_path("/../../etc/passwd"), "/etc/passwd");
+        assert_eq!(canonicalize_path("/api/."), "/api/");
+        assert_eq!(canonicalize_path("/%2e%2E/api"), "/api");
+    }
+
+    #[test]
+    fn referrer_domain_groups_hosts() {
+        let domain = |referer| referrer_domain(referer).map(Cow::into_owned);
+        assert_eq!(domain("https://www.google.com/"), Some("google.com".into()));
+        assert_eq!(
+            domain("https://News.Example.com:8443/a?b#c"),
+            Some("news.example.com".into())
+        );
+        assert_eq!(
+            domain("http://user@example.com"),
+            Some("example.com".into())
+        );
+        assert_eq!(
+            domain("http://[2001:db8::1]:8080/"),
+            Some("2001:db8::1".into())
+        );
+        assert_eq!(domain("-"), None);
+        assert_eq!(domain("android-app://"), None);
+    }
+
+    #[test]
+    fn own_domains_include_subdomains() {
+        let own = vec![String::from("example.com")];
+        assert!(is_own_domain("example.com", &own));
+        assert!(is_own_domain("shop.example.com", &own));
+        assert!(!is_own_domain("badexample.com", &own));
+        assert!(!is_own_domain("example.com.evil.net", &own));
+    }
+
+    #[test]
+    fn query_param_names_drops_values() {
+        let names: Vec<&str> = query_param_names("q=secret&page=2&&flag").collect();
+        assert_eq!(names, vec!["q", "page", "flag"]);
+    }
+}
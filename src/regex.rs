@@ -0,0 +1,526 @@
+// A small regular expression engine, for matching paths and user agents in filters.
+// Patterns are compiled to a program for a Pike VM (Thompson's construction), which steps
+// through every possible match in lockstep. Matching takes time linear in the length of the
+// input and the size of the program, which is capped, so no pattern can blow up on a hostile
+// path the way a backtracking engine can.
+//
+// Supported: literals, `.`, classes like `[a-z_]` and `[^/]`, the escapes `\d`, `\w`, `\s` (and
+// their negations), anchors `^` and `$`, groups, alternation with `|`, and the quantifiers
+// `*`, `+`, `?` and `{n}`, `{n,}`, `{n,m}`. Like grep, a pattern matches anywhere in the input
+// unless it's anchored.
+
+// Quantifiers expand their pattern, so cap them to keep programs small
+const MAX_REPEAT: u32 = 100;
+// Nested quantifiers multiply, so the whole program is capped as well
+const MAX_PROGRAM: usize = 10_000;
+
+#[derive(Debug, Clone)]
+pub struct Regex {
+    program: Vec<Inst>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Inst {
+    Char(char),
+    Any,
+    Class(Class),
+    Start,
+    End,
+    Split(usize, usize),
+    Jump(usize),
+    Match,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Class {
+    ranges: Vec<(char, char)>,
+    negated: bool,
+}
+
+impl Class {
+    fn matches(&self, c: char) -> bool {
+        self.ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi) != self.negated
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Char(char),
+    Any,
+    Class(Class),
+    Start,
+    End,
+    Concat(Vec<Node>),
+    Alternate(Vec<Node>),
+    Repeat(Box<Node>, u32, Option<u32>),
+}
+
+impl Regex {
+    pub fn new(pattern: &str) -> Result<Regex, String> {
+        let mut parser = Parser {
+            chars: pattern.chars().collect(),
+            pos: 0,
+        };
+        let node = parser.alternation()?;
+        if parser.pos < parser.chars.len() {
+            return Err(format!("unmatched ')' at position {}", parser.pos + 1));
+        }
+        if size(&node) > MAX_PROGRAM {
+            return Err(format!(
+                "pattern is too large, needing over {} instructions",
+                MAX_PROGRAM
+            ));
+        }
+        let mut program = Vec::new();
+        compile(&node, &mut program);
+        program.push(Inst::Match);
+        Ok(Regex { program })
+    }
+
+    pub fn is_match(&self, input: &str) -> bool {
+        let mut current = Threads::new(self.program.len());
+        let mut next = Threads::new(self.program.len());
+        let mut chars = input.chars().peekable();
+        let mut at_start = true;
+        loop {
+            // Start a new attempt at every position, which makes the search unanchored
+            let at_end = chars.peek().is_none();
+            if self.add(&mut current, 0, at_start, at_end) {
+                return true;
+            }
+            let c = match chars.next() {
+                Some(c) => c,
+                None => return false,
+            };
+            let at_end = chars.peek().is_none();
+            next.clear();
+            for i in 0..current.len {
+                let pc = current.dense[i];
+                let matched = match &self.program[pc] {
+                    Inst::Char(expected) => *expected == c,
+                    Inst::Any => true,
+                    Inst::Class(class) => class.matches(c),
+                    _ => false,
+                };
+                if matched && self.add(&mut next, pc + 1, false, at_end) {
+                    return true;
+                }
+            }
+            std::mem::swap(&mut current, &mut next);
+            at_start = false;
+        }
+    }
+
+    // Follow jumps and splits from an instruction, adding the threads that wait on a character.
+    // Returns true if any of them reaches a match. Long chains of splits would overflow the call
+    // stack if followed recursively, so the instructions still to visit are kept on the heap.
+    fn add(&self, threads: &mut Threads, pc: usize, at_start: bool, at_end: bool) -> bool {
+        let mut pending = std::mem::take(&mut threads.pending);
+        pending.push(pc);
+        let mut matched = false;
+        while let Some(pc) = pending.pop() {
+            if threads.contains(pc) {
+                continue;
+            }
+            threads.insert(pc);
+            match self.program[pc] {
+                Inst::Match => {
+                    matched = true;
+                    break;
+                }
+                Inst::Jump(to) => pending.push(to),
+                Inst::Split(a, b) => pending.extend([b, a]),
+                Inst::Start if at_start => pending.push(pc + 1),
+                Inst::End if at_end => pending.push(pc + 1),
+                _ => {}
+            }
+        }
+        pending.clear();
+        threads.pending = pending;
+        matched
+    }
+}
+
+// A set of instruction indexes that can be cleared in constant time
+struct Threads {
+    dense: Vec<usize>,
+    sparse: Vec<usize>,
+    len: usize,
+    // Reused by Regex::add for the instructions it has yet to follow
+    pending: Vec<usize>,
+}
+
+impl Threads {
+    fn new(size: usize) -> Threads {
+        Threads {
+            dense: vec![0; size],
+            sparse: vec![0; size],
+            len: 0,
+            pending: Vec::new(),
+        }
+    }
+
+    fn contains(&self, pc: usize) -> bool {
+        let i = self.sparse[pc];
+        i < self.len && self.dense[i] == pc
+    }
+
+    fn insert(&mut self, pc: usize) {
+        self.dense[self.len] = pc;
+        self.sparse[pc] = self.len;
+        self.len += 1;
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+}
+
+// An upper bound on the instructions a node compiles to, counting every node as at least one so
+// that empty groups repeated many times are rejected too
+fn size(node: &Node) -> usize {
+    let size = match node {
+        Node::Char(_) | Node::Any | Node::Class(_) | Node::Start | Node::End => 1,
+        Node::Concat(nodes) => nodes.iter().map(size).fold(0, usize::saturating_add),
+        Node::Alternate(nodes) => nodes
+            .iter()
+            .map(|node| size(node).saturating_add(2))
+            .fold(0, usize::saturating_add),
+        Node::Repeat(node, min, max) => {
+            let size = size(node);
+            let optional = match max {
+                None => size.saturating_add(2),
+                Some(max) => size.saturating_add(1).saturating_mul((max - min) as usize),
+            };
+            size.saturating_mul(*min as usize).saturating_add(optional)
+        }
+    };
+    size.max(1)
+}
+
+fn compile(node: &Node, program: &mut Vec<Inst>) {
+    match node {
+        Node::Char(c) => program.push(Inst::Char(*c)),
+        Node::Any => program.push(Inst::Any),
+        Node::Class(class) => program.push(Inst::Class(class.clone())),
+        Node::Start => program.push(Inst::Start),
+        Node::End => program.push(Inst::End),
+        Node::Concat(nodes) => nodes.iter().for_each(|node| compile(node, program)),
+        Node::Alternate(nodes) => {
+            // Each branch but the last splits off to try the rest, and jumps to the end after
+            let mut jumps = Vec::new();
+            for (i, node) in nodes.iter().enumerate() {
+                if i + 1 < nodes.len() {
+                    let split = program.len();
+                    program.push(Inst::Split(split + 1, 0));
+                    compile(node, program);
+                    jumps.push(program.len());
+                    program.push(Inst::Jump(0));
+                    program[split] = Inst::Split(split + 1, program.len());
+                } else {
+                    compile(node, program);
+                }
+            }
+            for jump in jumps {
+                program[jump] = Inst::Jump(program.len());
+            }
+        }
+        Node::Repeat(node, min, max) => {
+            for _ in 0..*min {
+                compile(node, program);
+            }
+            match max {
+                // Unbounded: loop back to try another one for as long as it keeps matching
+                None => {
+                    let split = program.len();
+                    program.push(Inst::Split(split + 1, 0));
+                    compile(node, program);
+                    program.push(Inst::Jump(split));
+                    program[split] = Inst::Split(split + 1, program.len());
+                }
+                Some(max) => {
+                    let mut splits = Vec::new();
+                    for _ in *min..*max {
+                        splits.push(program.len());
+                        program.push(Inst::Split(program.len() + 1, 0));
+                        compile(node, program);
+                    }
+                    for split in splits {
+                        program[split] = Inst::Split(split + 1, program.len());
+                    }
+                }
+            }
+        }
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek();
+        self.pos += 1;
+        c
+    }
+
+    fn alternation(&mut self) -> Result<Node, String> {
+        let mut branches = vec![self.concat()?];
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            branches.push(self.concat()?);
+        }
+        Ok(match branches.len() {
+            1 => branches.pop().unwrap(),
+            _ => Node::Alternate(branches),
+        })
+    }
+
+    fn concat(&mut self) -> Result<Node, String> {
+        let mut nodes = Vec::new();
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            nodes.push(self.repeat()?);
+        }
+        Ok(Node::Concat(nodes))
+    }
+
+    fn repeat(&mut self) -> Result<Node, String> {
+        let position = self.pos + 1;
+        let mut node = self.atom()?;
+        loop {
+            let (min, max) = match self.peek() {
+                Some('*') => self.quantifier(0, None),
+                Some('+') => self.quantifier(1, None),
+                Some('?') => self.quantifier(0, Some(1)),
+                Some('{') => self.bounds()?,
+                _ => return Ok(node),
+            };
+            if let Node::Start | Node::End = node {
+                return Err(format!("nothing to repeat at position {}", position));
+            }
+            node = Node::Repeat(Box::new(node), min, max);
+        }
+    }
+
+    fn quantifier(&mut self, min: u32, max: Option<u32>) -> (u32, Option<u32>) {
+        self.pos += 1;
+        (min, max)
+    }
+
+    // Parse {n}, {n,} or {n,m}
+    fn bounds(&mut self) -> Result<(u32, Option<u32>), String> {
+        let position = self.pos + 1;
+        let end = self.chars[self.pos..]
+            .iter()
+            .position(|&c| c == '}')
+            .ok_or(format!("unclosed '{{' at position {}", position))?;
+        let inner: String = self.chars[self.pos + 1..self.pos + end].iter().collect();
+        let invalid = || {
+            format!(
+                "invalid repetition '{{{}}}' at position {}",
+                inner, position
+            )
+        };
+        let number = |s: &str| s.trim().parse::<u32>().map_err(|_| invalid());
+        let (min, max) = match inner.split_once(',') {
+            Some((min, "")) => (number(min)?, None),
+            Some((min, max)) => (number(min)?, Some(number(max)?)),
+            None => (number(&inner)?, Some(number(&inner)?)),
+        };
+        if max.is_some_and(|max| max < min) || min.max(max.unwrap_or(0)) > MAX_REPEAT {
+            return Err(invalid());
+        }
+        self.pos += end + 1;
+        Ok((min, max))
+    }
+
+    fn atom(&mut self) -> Result<Node, String> {
+        let position = self.pos + 1;
+        match self.next() {
+            Some('(') => {
+                let node = self.alternation()?;
+                match self.next() {
+                    Some(')') => Ok(node),
+                    _ => Err(format!("unclosed '(' at position {}", position)),
+                }
+            }
+            Some('[') => self.class(position),
+            Some('.') => Ok(Node::Any),
+            Some('^') => Ok(Node::Start),
+            Some('$') => Ok(Node::End),
+            Some('\\') => self.escape(position),
+            Some(c @ ('*' | '+' | '?' | '{')) => Err(format!(
+                "nothing to repeat before '{}' at position {}",
+                c, position
+            )),
+            Some(c) => Ok(Node::Char(c)),
+            None => Err(String::from("unexpected end of pattern")),
+        }
+    }
+
+    fn escape(&mut self, position: usize) -> Result<Node, String> {
+        let class = |ranges: &[(char, char)], negated| {
+            Node::Class(Class {
+                ranges: ranges.to_vec(),
+                negated,
+            })
+        };
+        const DIGIT: &[(char, char)] = &[('0', '9')];
+        const WORD: &[(char, char)] = &[('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')];
+        const SPACE: &[(char, char)] = &[('\t', '\r'), (' ', ' ')];
+        match self.next() {
+            Some('d') => Ok(class(DIGIT, false)),
+            Some('D') => Ok(class(DIGIT, true)),
+            Some('w') => Ok(class(WORD, false)),
+            Some('W') => Ok(class(WORD, true)),
+            Some('s') => Ok(class(SPACE, false)),
+            Some('S') => Ok(class(SPACE, true)),
+            Some('t') => Ok(Node::Char('\t')),
+            Some('n') => Ok(Node::Char('\n')),
+            Some(c) if !c.is_ascii_alphanumeric() => Ok(Node::Char(c)),
+            Some(c) => Err(format!("unknown escape '\\{}' at position {}", c, position)),
+            None => Err(String::from("pattern ends with '\\'")),
+        }
+    }
+
+    fn class(&mut self, position: usize) -> Result<Node, String> {
+        let negated = self.peek() == Some('^');
+        if negated {
+            self.pos += 1;
+        }
+        let mut ranges = Vec::new();
+        let mut first = true;
+        loop {
+            let c = match self.next() {
+                // A "]" straight after the opening bracket is taken literally
+                Some(']') if !first => break,
+                Some('\\') => match self.next() {
+                    Some(c) => c,
+                    None => return Err(String::from("pattern ends with '\\'")),
+                },
+                Some(c) => c,
+                None => return Err(format!("unclosed '[' at position {}", position)),
+            };
+            first = false;
+            if self.peek() == Some('-') && self.chars.get(self.pos + 1).is_some_and(|&c| c != ']') {
+                self.pos += 1;
+                let hi = self.next().unwrap();
+                if hi < c {
+                    return Err(format!(
+                        "invalid range '{}-{}' at position {}",
+                        c, hi, position
+                    ));
+                }
+                ranges.push((c, hi));
+            } else {
+                ranges.push((c, c));
+            }
+        }
+        Ok(Node::Class(Class { ranges, negated }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_match(pattern: &str, input: &str) -> bool {
+        Regex::new(pattern).unwrap().is_match(input)
+    }
+
+    #[test]
+    fn matches_anywhere_unless_anchored() {
+        assert!(is_match("api", "/v1/api/users"));
+        assert!(is_match("^/api", "/api/users"));
+        assert!(!is_match("^/api", "/v1/api"));
+        assert!(is_match("\\.js$", "/static/app.js"));
+        assert!(!is_match("\\.js$", "/static/app.json"));
+        assert!(is_match("^$", ""));
+        assert!(is_match("", "anything"));
+    }
+
+    #[test]
+    fn classes_and_escapes() {
+        assert!(is_match("^/users/\\d+$", "/users/42"));
+        assert!(!is_match("^/users/\\d+$", "/users/me"));
+        assert!(is_match("^/[a-z_]+/[^/]*$", "/static_files/app.css"));
+        assert!(!is_match("^[^/]", "/root"));
+        assert!(is_match("[]x]", "]"));
+        assert!(is_match("[a-]", "-"));
+        assert!(is_match("\\w\\s\\W", "a !"));
+    }
+
+    #[test]
+    fn alternation_groups_and_quantifiers() {
+        assert!(is_match("^/(api|admin)/", "/admin/login"));
+        assert!(!is_match("^/(api|admin)/", "/assets/"));
+        assert!(is_match("^colou?r$", "color"));
+        assert!(is_match("^colou?r$", "colour"));
+        assert!(is_match("^(ab)+$", "ababab"));
+        assert!(!is_match("^(ab)+$", "aba"));
+        assert!(is_match("^a{2,3}$", "aaa"));
+        assert!(!is_match("^a{2,3}$", "aaaa"));
+        assert!(is_match("^a{2,}$", "aaaaa"));
+        assert!(is_match("^a{2}b$", "aab"));
+        assert!(is_match("^(a*)*b$", "aaab"));
+    }
+
+    #[test]
+    fn no_catastrophic_backtracking() {
+        // This takes exponential time in a backtracking engine
+        let input = "a".repeat(10_000);
+        assert!(!is_match("^(a+)+b$", &input));
+        assert!(!is_match("(a|aa)*c", &input));
+    }
+
+    #[test]
+    fn nested_repeats_are_capped() {
+        let too_large = "pattern is too large, needing over 10000 instructions";
+        assert_eq!(
+            Regex::new("(((a?){100}){100}){100}").unwrap_err(),
+            too_large
+        );
+        assert_eq!(Regex::new("(((){100}){100}){100}").unwrap_err(), too_large);
+        // Just under the cap, this is a chain of 9000 splits that's followed without recursing
+        let regex = Regex::new("^((a?){90}){50}b$").unwrap();
+        assert!(regex.is_match("aaab"));
+        assert!(!regex.is_match("aaac"));
+    }
+
+    #[test]
+    fn reports_bad_patterns() {
+        assert_eq!(
+            Regex::new("(api").unwrap_err(),
+            "unclosed '(' at position 1"
+        );
+        assert_eq!(
+            Regex::new("api)").unwrap_err(),
+            "unmatched ')' at position 4"
+        );
+        assert_eq!(
+            Regex::new("/[a-").unwrap_err(),
+            "unclosed '[' at position 2"
+        );
+        assert_eq!(
+            Regex::new("*.js").unwrap_err(),
+            "nothing to repeat before '*' at position 1"
+        );
+        assert_eq!(
+            Regex::new("\\q").unwrap_err(),
+            "unknown escape '\\q' at position 1"
+        );
+        assert_eq!(
+            Regex::new("a{3,1}").unwrap_err(),
+            "invalid repetition '{3,1}' at position 2"
+        );
+    }
+}
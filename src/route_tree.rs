@@ -0,0 +1,211 @@
+use crate::config::SortKey;
+use crate::heavy_hitters::HeavyHitters;
+use crate::parsers::nginx;
+use crate::stats::{self, RequestStats};
+use crate::top_k;
+use std::collections::HashMap;
+
+// Name of the row holding a prefix's children that were evicted when they're bounded
+const OTHER: &str = "(other)";
+
+// Aggregates routes into a tree of path prefixes, where each node holds the stats for every
+// request under that prefix. Nodes are only created down to the configured depth, so deeper
+// paths are rolled up into their ancestor at that depth.
+#[derive(Debug)]
+pub struct RouteTree {
+    depth: usize,
+    // With --max-routes, the most children each prefix keeps, so that a scanner requesting
+    // random paths can't grow the tree without bound
+    max_children: Option<usize>,
+    root: RouteNode,
+}
+
+#[derive(Debug, Default)]
+struct RouteNode {
+    stats: RequestStats,
+    children: Children,
+}
+
+#[derive(Debug, Default)]
+enum Children {
+    // Leaves don't need a table until something is found under them
+    #[default]
+    Empty,
+    Exact(HashMap<String, RouteNode>),
+    // Children that get evicted from the slots, and everything under them, are folded into the
+    // "other" stats
+    Bounded {
+        slots: HeavyHitters<RouteNode>,
+        other: RequestStats,
+    },
+}
+
+impl Children {
+    fn child(&mut self, segment: &str, max_children: Option<usize>) -> &mut RouteNode {
+        match self {
+            Children::Empty => {
+                *self = match max_children {
+                    Some(max_children) => Children::Bounded {
+                        slots: HeavyHitters::new(max_children),
+                        other: RequestStats::new(),
+                    },
+                    None => Children::Exact(HashMap::new()),
+                };
+                self.child(segment, max_children)
+            }
+            Children::Exact(children) => {
+                // Only allocate a key the first time we see this prefix
+                if !children.contains_key(segment) {
+                    children.insert(String::from(segment), RouteNode::default());
+                }
+                children.get_mut(segment).unwrap()
+            }
+            Children::Bounded { slots, other } => {
+                let (child, evicted) = slots.increment(segment);
+                if let Some((_, evicted)) = evicted {
+                    other.merge(&evicted.stats);
+                }
+                child
+            }
+        }
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (&str, &RouteNode)> + '_> {
+        match self {
+            Children::Empty => Box::new(std::iter::empty()),
+            Children::Exact(children) => Box::new(
+                children
+                    .iter()
+                    .map(|(segment, child)| (segment.as_str(), child)),
+            ),
+            Children::Bounded { slots, .. } => Box::new(slots.iter()),
+        }
+    }
+}
+
+impl RouteTree {
+    pub fn new(depth: usize, max_children: Option<usize>) -> RouteTree {
+        RouteTree {
+            depth,
+            max_children,
+            root: RouteNode::default(),
+        }
+    }
+
+    // Increment the stats of every prefix of this path, down to the maximum depth
+    pub fn update(&mut self, path: &str, log: &nginx::NginxCombinedLog) {
+        let mut node = &mut self.root;
+        node.stats.update(log);
+        for segment in path
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .take(self.depth)
+        {
+            node = node.children.child(segment, self.max_children);
+            node.stats.update(log);
+        }
+    }
+
+    // Flatten the tree into (depth, prefix, stats) rows, in display order
+    // Children are ranked by the sort key, and only the highest max_children are kept
+    pub fn rows(&self, max_children: usize, sort: SortKey) -> Vec<(usize, String, RequestStats)> {
+        let mut rows = vec![(0, String::from("/"), self.root.stats)];
+        push_children(&mut rows, &self.root, "", 1, max_children, sort);
+        rows
+    }
+}
+
+fn push_children(
+    rows: &mut Vec<(usize, String, RequestStats)>,
+    node: &RouteNode,
+    prefix: &str,
+    depth: usize,
+    max_children: usize,
+    sort: SortKey,
+) {
+    let children = top_k::top_k(node.children.iter(), max_children, |a, b| {
+        stats::by_rank(sort, (a.0, &a.1.stats), (b.0, &b.1.stats))
+    });
+    for (segment, child) in children {
+        let child_prefix = format!("{}/{}", prefix, segment);
+        rows.push((depth, child_prefix.clone(), child.stats));
+        push_children(rows, child, &child_prefix, depth + 1, max_children, sort);
+    }
+    if let Children::Bounded { other, .. } = &node.children {
+        if other.codes.sum() > 0 {
+            rows.push((depth, format!("{}/{}", prefix, OTHER), *other));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log_with_status(status: u32) -> nginx::NginxCombinedLog<'static> {
+        nginx::NginxCombinedLog {
+            status,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn update_rolls_up_into_parents() {
+        let mut tree = RouteTree::new(2, None);
+        tree.update("/api/v1", &log_with_status(200));
+        tree.update("/api/v2", &log_with_status(500));
+        tree.update("/", &log_with_status(200));
+
+        let rows = tree.rows(10, SortKey::Total);
+        let names: Vec<&str> = rows.iter().map(|row| row.1.as_str()).collect();
+        assert_eq!(names, vec!["/", "/api", "/api/v1", "/api/v2"]);
+
+        assert_eq!(rows[0].2.codes.sum(), 3);
+        assert_eq!(rows[1].2.codes.x2, 1);
+        assert_eq!(rows[1].2.codes.x5, 1);
+        assert_eq!(rows[3].0, 2);
+    }
+
+    #[test]
+    fn update_stops_at_depth() {
+        let mut tree = RouteTree::new(1, None);
+        tree.update("/api/v1/users", &log_with_status(200));
+        tree.update("/api/v2//users/", &log_with_status(404));
+
+        let rows = tree.rows(10, SortKey::Total);
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[1].1, "/api");
+        assert_eq!(rows[1].2.codes.sum(), 2);
+    }
+
+    #[test]
+    fn rows_keeps_largest_children() {
+        let mut tree = RouteTree::new(1, None);
+        tree.update("/a", &log_with_status(200));
+        tree.update("/b", &log_with_status(200));
+        tree.update("/b", &log_with_status(200));
+
+        let rows = tree.rows(1, SortKey::Total);
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[1].1, "/b");
+    }
+
+    #[test]
+    fn bounded_children_fold_into_other() {
+        let mut tree = RouteTree::new(2, Some(2));
+        for path in ["/api/a", "/api/a", "/api/b", "/api/c", "/api/d", "/static"] {
+            tree.update(path, &log_with_status(200));
+        }
+
+        let rows = tree.rows(10, SortKey::Total);
+        let names: Vec<&str> = rows.iter().map(|row| row.1.as_str()).collect();
+        assert_eq!(
+            names,
+            vec!["/", "/api", "/api/a", "/api/d", "/api/(other)", "/static"]
+        );
+        // Evicted children keep counting towards their parent, and the total
+        assert_eq!(rows[0].2.codes.sum(), 6);
+        assert_eq!(rows[1].2.codes.sum(), 5);
+        assert_eq!(rows[4].2.codes.sum(), 2);
+    }
+}
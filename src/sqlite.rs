@@ -0,0 +1,179 @@
+use crate::parsers::nginx::{self, NginxCombinedLog};
+use rusqlite::{params, Connection};
+use std::borrow::Cow;
+use std::io;
+use std::path::Path;
+
+// Rows inserted in each transaction. Committing every row would sync the file each time, and
+// committing only at the end would lose everything if the export were interrupted.
+const BATCH_SIZE: usize = 10_000;
+
+const CREATE_TABLE: &str = "CREATE TABLE IF NOT EXISTS requests (
+    id INTEGER PRIMARY KEY,
+    timestamp INTEGER,
+    ip TEXT,
+    method TEXT,
+    path TEXT,
+    query TEXT,
+    status INTEGER NOT NULL,
+    bytes INTEGER NOT NULL,
+    referer TEXT,
+    ua TEXT,
+    latency REAL
+)";
+const INSERT: &str = "INSERT INTO requests
+    (timestamp, ip, method, path, query, status, bytes, referer, ua, latency)
+    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)";
+// Created once the rows are in, since that's faster than updating them on every insert
+const CREATE_INDEXES: &str = "
+    CREATE INDEX IF NOT EXISTS requests_timestamp ON requests (timestamp);
+    CREATE INDEX IF NOT EXISTS requests_status ON requests (status);
+    CREATE INDEX IF NOT EXISTS requests_path ON requests (path);
+    CREATE INDEX IF NOT EXISTS requests_ip ON requests (ip);
+";
+
+// Writes requests to the "requests" table of a SQLite database, creating it if needed. Exporting
+// to an existing database adds to its rows, so several days of logs can go in one file.
+pub struct Export {
+    connection: Connection,
+    // Rows inserted in the open transaction, and in total
+    pending: usize,
+    rows: usize,
+}
+
+impl Export {
+    pub fn create(path: &Path) -> io::Result<Export> {
+        let connection = Connection::open(path).map_err(to_io)?;
+        connection.execute(CREATE_TABLE, []).map_err(to_io)?;
+        connection.execute_batch("BEGIN").map_err(to_io)?;
+        Ok(Export {
+            connection,
+            pending: 0,
+            rows: 0,
+        })
+    }
+
+    pub fn insert(&mut self, log: &NginxCombinedLog) -> io::Result<()> {
+        let mut insert = self.connection.prepare_cached(INSERT).map_err(to_io)?;
+        insert
+            .execute(params![
+                nginx::unix_time(log.time_local),
+                text(log.remote_addr),
+                log.method.map(|method| method.as_str()),
+                log.request_path.and_then(text),
+                log.request_query.and_then(text),
+                log.status,
+                log.body_bytes_sent,
+                text(log.http_referer),
+                text(log.http_user_agent),
+                log.request_time,
+            ])
+            .map_err(to_io)?;
+        drop(insert);
+
+        self.pending += 1;
+        self.rows += 1;
+        if self.pending == BATCH_SIZE {
+            self.connection
+                .execute_batch("COMMIT; BEGIN")
+                .map_err(to_io)?;
+            self.pending = 0;
+        }
+        Ok(())
+    }
+
+    // Commit the last batch and index the table, returning the number of rows exported
+    pub fn finish(self) -> io::Result<usize> {
+        self.connection.execute_batch("COMMIT").map_err(to_io)?;
+        self.connection
+            .execute_batch(CREATE_INDEXES)
+            .map_err(to_io)?;
+        Ok(self.rows)
+    }
+}
+
+// Text decoded from nginx's escapes, or NULL for nginx's "-" and empty values
+fn text(value: &str) -> Option<Cow<'_, str>> {
+    match value {
+        "" | "-" => None,
+        value => Some(nginx::unescape(value)),
+    }
+}
+
+fn to_io(err: rusqlite::Error) -> io::Error {
+    io::Error::other(err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn rows_are_typed() {
+        let path = env::temp_dir().join(format!("dashlight-sqlite-{}.db", std::process::id()));
+        let lines = [
+            r#"10.0.0.1 - - [09/May/2022:00:00:07 +0000] "GET /caf\xC3\xA9?q=1 HTTP/1.1" 200 2048 "-" "curl/7.79.1" 0.002"#,
+            r#"10.0.0.2 - - [09/May/2022:00:00:08 +0000] "GET / HTTP/1.1" 404 0 "https://example.com/" "-""#,
+        ];
+        let mut export = Export::create(&path).unwrap();
+        for line in lines {
+            export
+                .insert(&nginx::get_log_from_logline(line).unwrap())
+                .unwrap();
+        }
+        assert_eq!(export.finish().unwrap(), 2);
+
+        let connection = Connection::open(&path).unwrap();
+        let row = |id: i64| {
+            connection
+                .query_row(
+                    "SELECT timestamp, path, query, status, referer, ua, latency
+                     FROM requests WHERE id = ?1",
+                    [id],
+                    |row| {
+                        Ok((
+                            row.get::<_, i64>(0)?,
+                            row.get::<_, Option<String>>(1)?,
+                            row.get::<_, Option<String>>(2)?,
+                            row.get::<_, u32>(3)?,
+                            row.get::<_, Option<String>>(4)?,
+                            row.get::<_, Option<String>>(5)?,
+                            row.get::<_, Option<f64>>(6)?,
+                        ))
+                    },
+                )
+                .unwrap()
+        };
+        let first = row(1);
+        let second = row(2);
+        drop(connection);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            first,
+            (
+                1652054407,
+                Some(String::from("/café")),
+                Some(String::from("q=1")),
+                200,
+                None,
+                Some(String::from("curl/7.79.1")),
+                Some(0.002)
+            )
+        );
+        assert_eq!(
+            second,
+            (
+                1652054408,
+                Some(String::from("/")),
+                None,
+                404,
+                Some(String::from("https://example.com/")),
+                None,
+                None
+            )
+        );
+    }
+}
@@ -1,5 +1,7 @@
+use std::cmp::Ordering;
 use std::fmt;
 
+use crate::config::SortKey;
 use crate::parsers::nginx;
 
 #[derive(Debug, Copy, Clone)]
@@ -44,6 +46,162 @@ impl StatusCodeStats {
     }
 }
 
+// Status code counts for each HTTP method, with a final slot for requests without a valid one
+#[derive(Debug, Copy, Clone)]
+pub struct MethodStats {
+    pub by_method: [StatusCodeStats; nginx::HttpMethod::ALL.len() + 1],
+}
+
+impl MethodStats {
+    pub fn new() -> MethodStats {
+        MethodStats {
+            by_method: [StatusCodeStats::new(); nginx::HttpMethod::ALL.len() + 1],
+        }
+    }
+
+    pub fn update(&mut self, log: &nginx::NginxCombinedLog) {
+        let index = match log.method {
+            Some(method) => method as usize,
+            None => nginx::HttpMethod::ALL.len(),
+        };
+        self.by_method[index].update(log);
+    }
+
+    // Each method's name and counts, with "other" for requests without a valid method
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, &StatusCodeStats)> {
+        let names = nginx::HttpMethod::ALL
+            .iter()
+            .map(|method| method.as_str())
+            .chain(["other"]);
+        names.zip(self.by_method.iter())
+    }
+}
+
+// Upper bounds (in seconds) of the latency histogram buckets, with a final overflow bucket
+pub const LATENCY_BOUNDS: [f64; 26] = [
+    0.001, 0.002, 0.003, 0.005, 0.0075, 0.01, 0.015, 0.02, 0.03, 0.05, 0.075, 0.1, 0.15, 0.2, 0.3,
+    0.5, 0.75, 1.0, 1.5, 2.0, 3.0, 5.0, 7.5, 10.0, 30.0, 60.0,
+];
+
+// Fixed-size histogram of request times, so that percentiles don't require storing every sample
+#[derive(Debug, Copy, Clone)]
+pub struct LatencyHistogram {
+    pub buckets: [u32; LATENCY_BOUNDS.len() + 1],
+    pub count: u32,
+    pub sum: f64,
+}
+
+impl LatencyHistogram {
+    pub fn new() -> LatencyHistogram {
+        LatencyHistogram {
+            buckets: [0; LATENCY_BOUNDS.len() + 1],
+            count: 0,
+            sum: 0.0,
+        }
+    }
+
+    pub fn record(&mut self, seconds: f64) {
+        let index = LATENCY_BOUNDS
+            .iter()
+            .position(|&bound| seconds <= bound)
+            .unwrap_or(LATENCY_BOUNDS.len());
+        self.buckets[index] += 1;
+        self.count += 1;
+        self.sum += seconds;
+    }
+
+    // Estimate the given percentile (0-100) as the upper bound of the bucket it falls in
+    // Returns None if there are no samples, and infinity if it falls in the overflow bucket
+    pub fn percentile(&self, percentile: f64) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = (percentile / 100.0 * self.count as f64).ceil().max(1.0) as u32;
+        let mut seen = 0;
+        for (index, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Some(*LATENCY_BOUNDS.get(index).unwrap_or(&f64::INFINITY));
+            }
+        }
+        Some(f64::INFINITY)
+    }
+}
+
+// Everything we track about a group of requests, such as a route
+#[derive(Debug, Copy, Clone)]
+pub struct RequestStats {
+    pub codes: StatusCodeStats,
+    pub bytes: u64,
+    pub latency: LatencyHistogram,
+}
+
+impl RequestStats {
+    pub fn new() -> RequestStats {
+        RequestStats {
+            codes: StatusCodeStats::new(),
+            bytes: 0,
+            latency: LatencyHistogram::new(),
+        }
+    }
+
+    // Fold another group of requests into this one
+    pub fn merge(&mut self, other: &RequestStats) {
+        self.codes.x2 += other.codes.x2;
+        self.codes.x3 += other.codes.x3;
+        self.codes.x4 += other.codes.x4;
+        self.codes.x5 += other.codes.x5;
+        self.bytes += other.bytes;
+        for (bucket, count) in self.latency.buckets.iter_mut().zip(other.latency.buckets) {
+            *bucket += count;
+        }
+        self.latency.count += other.latency.count;
+        self.latency.sum += other.latency.sum;
+    }
+
+    pub fn update(&mut self, log: &nginx::NginxCombinedLog) {
+        self.codes.update(log);
+        self.bytes += log.body_bytes_sent as u64;
+        if let Some(request_time) = log.request_time {
+            self.latency.record(request_time);
+        }
+    }
+
+    // The share of requests that were answered with a server error
+    pub fn error_rate(&self) -> f64 {
+        match self.codes.sum() {
+            0 => 0.0,
+            sum => self.codes.x5 as f64 / sum as f64,
+        }
+    }
+
+    // The value used to rank this group of requests against others
+    pub fn sort_value(&self, key: SortKey) -> f64 {
+        match key {
+            SortKey::Total => self.codes.sum() as f64,
+            SortKey::X2 => self.codes.x2 as f64,
+            SortKey::X3 => self.codes.x3 as f64,
+            SortKey::X4 => self.codes.x4 as f64,
+            SortKey::X5 => self.codes.x5 as f64,
+            SortKey::ErrorRate => self.error_rate(),
+            SortKey::Bytes => self.bytes as f64,
+            SortKey::P99 => self.latency.percentile(99.0).unwrap_or(0.0),
+        }
+    }
+}
+
+impl Default for RequestStats {
+    fn default() -> RequestStats {
+        RequestStats::new()
+    }
+}
+
+// Order two named groups of requests from highest to lowest rank, breaking ties by name
+pub fn by_rank(key: SortKey, a: (&str, &RequestStats), b: (&str, &RequestStats)) -> Ordering {
+    let by_value = b.1.sort_value(key).total_cmp(&a.1.sort_value(key));
+    by_value.then(a.0.cmp(b.0))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -60,8 +218,10 @@ mod tests {
     #[test]
     fn update_with_200_increments_2xx() {
         let mut codes = StatusCodeStats::new();
-        let mut log = nginx::NginxCombinedLog::default();
-        log.status = 200;
+        let log = nginx::NginxCombinedLog {
+            status: 200,
+            ..Default::default()
+        };
         codes.update(&log);
 
         assert_eq!(codes.x2, 1);
@@ -73,8 +233,10 @@ mod tests {
     #[test]
     fn update_with_304_increments_3xx() {
         let mut codes = StatusCodeStats::new();
-        let mut log = nginx::NginxCombinedLog::default();
-        log.status = 304;
+        let log = nginx::NginxCombinedLog {
+            status: 304,
+            ..Default::default()
+        };
         codes.update(&log);
 
         assert_eq!(codes.x2, 0);
@@ -86,8 +248,10 @@ mod tests {
     #[test]
     fn update_with_404_increments_4xx() {
         let mut codes = StatusCodeStats::new();
-        let mut log = nginx::NginxCombinedLog::default();
-        log.status = 404;
+        let log = nginx::NginxCombinedLog {
+            status: 404,
+            ..Default::default()
+        };
         codes.update(&log);
 
         assert_eq!(codes.x2, 0);
@@ -99,8 +263,10 @@ mod tests {
     #[test]
     fn update_with_500_increments_5xx() {
         let mut codes = StatusCodeStats::new();
-        let mut log = nginx::NginxCombinedLog::default();
-        log.status = 500;
+        let log = nginx::NginxCombinedLog {
+            status: 500,
+            ..Default::default()
+        };
         codes.update(&log);
 
         assert_eq!(codes.x2, 0);
@@ -108,4 +274,93 @@ mod tests {
         assert_eq!(codes.x4, 0);
         assert_eq!(codes.x5, 1);
     }
+
+    #[test]
+    fn method_stats_split_by_method() {
+        let mut methods = MethodStats::new();
+        for (method, status) in [
+            (Some(nginx::HttpMethod::GET), 200),
+            (Some(nginx::HttpMethod::GET), 404),
+            (Some(nginx::HttpMethod::PATCH), 200),
+            (None, 400),
+        ] {
+            methods.update(&nginx::NginxCombinedLog {
+                method,
+                status,
+                ..Default::default()
+            });
+        }
+
+        let counts: Vec<(&str, u32, u32)> = methods
+            .iter()
+            .filter(|(_, codes)| codes.sum() > 0)
+            .map(|(name, codes)| (name, codes.x2, codes.x4))
+            .collect();
+        assert_eq!(
+            counts,
+            vec![("GET", 1, 1), ("PATCH", 1, 0), ("other", 0, 1)]
+        );
+    }
+
+    #[test]
+    fn percentile_of_empty_histogram_is_none() {
+        assert_eq!(LatencyHistogram::new().percentile(99.0), None);
+    }
+
+    #[test]
+    fn percentile_uses_bucket_upper_bound() {
+        let mut latency = LatencyHistogram::new();
+        for _ in 0..98 {
+            latency.record(0.004);
+        }
+        latency.record(0.25);
+        latency.record(120.0);
+
+        assert_eq!(latency.percentile(50.0), Some(0.005));
+        assert_eq!(latency.percentile(99.0), Some(0.3));
+        assert_eq!(latency.percentile(100.0), Some(f64::INFINITY));
+    }
+
+    #[test]
+    fn request_stats_update_tracks_bytes_and_latency() {
+        let mut stats = RequestStats::new();
+        let log = nginx::NginxCombinedLog {
+            status: 502,
+            body_bytes_sent: 100,
+            request_time: Some(0.02),
+            ..Default::default()
+        };
+        stats.update(&log);
+        stats.update(&nginx::NginxCombinedLog {
+            status: 200,
+            body_bytes_sent: 50,
+            ..Default::default()
+        });
+
+        assert_eq!(stats.bytes, 150);
+        assert_eq!(stats.latency.count, 1);
+        assert_eq!(stats.error_rate(), 0.5);
+        assert_eq!(stats.sort_value(SortKey::Total), 2.0);
+    }
+
+    #[test]
+    fn request_stats_merge_adds_everything() {
+        let log = nginx::NginxCombinedLog {
+            status: 404,
+            body_bytes_sent: 10,
+            request_time: Some(0.5),
+            ..Default::default()
+        };
+        let mut a = RequestStats::new();
+        a.update(&log);
+        let mut b = RequestStats::new();
+        b.update(&log);
+        b.update(&log);
+        a.merge(&b);
+
+        assert_eq!(a.codes.x4, 3);
+        assert_eq!(a.bytes, 30);
+        assert_eq!(a.latency.count, 3);
+        assert_eq!(a.latency.percentile(50.0), Some(0.5));
+    }
 }
@@ -0,0 +1,71 @@
+use crate::config::SortKey;
+use crate::heavy_hitters::HeavyHitters;
+use crate::parsers::nginx;
+use crate::stats::{self, RequestStats};
+use crate::top_k;
+use std::collections::HashMap;
+
+// Request stats grouped by some key, such as the route or the client address
+// Keys are counted exactly by default, or in a fixed number of slots if memory is capped
+#[derive(Debug)]
+pub enum StatsTable {
+    Exact(HashMap<String, RequestStats>),
+    // Keys that get evicted from the slots are folded into the "other" stats
+    Bounded {
+        slots: HeavyHitters<RequestStats>,
+        other: RequestStats,
+    },
+}
+
+impl StatsTable {
+    pub fn new(max_keys: Option<usize>) -> StatsTable {
+        match max_keys {
+            Some(max_keys) => StatsTable::Bounded {
+                slots: HeavyHitters::new(max_keys),
+                other: RequestStats::new(),
+            },
+            None => StatsTable::Exact(HashMap::new()),
+        }
+    }
+
+    // Update the stats for a key, returning the key that was evicted to make room for it
+    pub fn update(&mut self, key: &str, log: &nginx::NginxCombinedLog) -> Option<String> {
+        match self {
+            StatsTable::Exact(stats_by_key) => {
+                match stats_by_key.get_mut(key) {
+                    Some(stats) => stats.update(log),
+                    None => {
+                        let mut stats = RequestStats::new();
+                        stats.update(log);
+                        stats_by_key.insert(String::from(key), stats);
+                    }
+                }
+                None
+            }
+            StatsTable::Bounded { slots, other } => {
+                let (stats, evicted) = slots.increment(key);
+                stats.update(log);
+                evicted.map(|(evicted_key, evicted_stats)| {
+                    other.merge(&evicted_stats);
+                    evicted_key
+                })
+            }
+        }
+    }
+
+    pub fn iter(&self) -> Box<dyn Iterator<Item = (&str, &RequestStats)> + '_> {
+        match self {
+            StatsTable::Exact(stats_by_key) => Box::new(
+                stats_by_key
+                    .iter()
+                    .map(|(key, stats)| (key.as_str(), stats)),
+            ),
+            StatsTable::Bounded { slots, .. } => Box::new(slots.iter()),
+        }
+    }
+
+    // Rank every key we've seen, and return the highest ones
+    pub fn top(&self, k: usize, sort: SortKey) -> Vec<(&str, &RequestStats)> {
+        top_k::top_k(self.iter(), k, |a, b| stats::by_rank(sort, *a, *b))
+    }
+}
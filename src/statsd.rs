@@ -0,0 +1,266 @@
+use crate::config::SortKey;
+use crate::stats::{LatencyHistogram, RequestStats};
+use crate::view::Interval;
+use std::fmt::Write;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+
+// Largest packet we'll send, so that it fits in an Ethernet frame without fragmenting
+const MAX_PACKET_SIZE: usize = 1432;
+// Prefix of every metric name
+const PREFIX: &str = "dashlight";
+// Request time percentiles sent as gauges, in milliseconds
+const PERCENTILES: [(&str, f64); 3] = [
+    ("request_time.p50", 50.0),
+    ("request_time.p90", 90.0),
+    ("request_time.p99", 99.0),
+];
+
+// Sends each interval's counts to a StatsD server over UDP. Plain StatsD has no tags, so routes
+// and status classes go in the metric names; DogStatsD gets them as tags instead.
+pub struct Statsd {
+    socket: UdpSocket,
+    addr: SocketAddr,
+    dogstatsd: bool,
+    top: usize,
+    sort: SortKey,
+}
+
+impl Statsd {
+    pub fn new(addr: SocketAddr, dogstatsd: bool, top: usize, sort: SortKey) -> io::Result<Statsd> {
+        let local: SocketAddr = match addr {
+            SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+            SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+        };
+        Ok(Statsd {
+            socket: UdpSocket::bind(local)?,
+            addr,
+            dogstatsd,
+            top,
+            sort,
+        })
+    }
+
+    pub fn send(&self, interval: &Interval) -> io::Result<()> {
+        for packet in packets(&self.lines(interval)) {
+            // The socket isn't connected, so a server that isn't listening doesn't cause errors
+            self.socket.send_to(packet.as_bytes(), self.addr)?;
+        }
+        Ok(())
+    }
+
+    // One line per metric: counters for requests by status class and for bytes, and gauges for
+    // request time percentiles, overall and for the interval's top routes
+    fn lines(&self, interval: &Interval) -> Vec<String> {
+        let mut lines = Vec::new();
+        self.push_stats(&mut lines, None, &interval.global);
+        for (route, stats) in interval.routes.top(self.top, self.sort) {
+            self.push_stats(&mut lines, Some(route), stats);
+        }
+        lines
+    }
+
+    fn push_stats(&self, lines: &mut Vec<String>, route: Option<&str>, stats: &RequestStats) {
+        let codes = stats.codes;
+        let classes = [
+            ("2xx", codes.x2),
+            ("3xx", codes.x3),
+            ("4xx", codes.x4),
+            ("5xx", codes.x5),
+        ];
+        for (class, count) in classes {
+            // Overall counts are always sent, so that they read as zero rather than missing
+            if count > 0 || route.is_none() {
+                lines.push(self.line(route, "requests", Some(class), count, "c"));
+            }
+        }
+        lines.push(self.line(route, "bytes", None, stats.bytes, "c"));
+        for (name, value) in latency_aggregates(&stats.latency) {
+            lines.push(self.line(route, name, None, value, "g"));
+        }
+    }
+
+    fn line(
+        &self,
+        route: Option<&str>,
+        name: &str,
+        class: Option<&str>,
+        value: impl std::fmt::Display,
+        kind: &str,
+    ) -> String {
+        let mut line = String::from(PREFIX);
+        if route.is_some() {
+            line.push_str(".route");
+        }
+        if !self.dogstatsd {
+            if let Some(route) = route {
+                line.push('.');
+                push_name(&mut line, route);
+            }
+        }
+        write!(line, ".{}", name).unwrap();
+        if let (false, Some(class)) = (self.dogstatsd, class) {
+            write!(line, ".{}", class).unwrap();
+        }
+        write!(line, ":{}|{}", value, kind).unwrap();
+
+        let tags = [("route", route), ("status_class", class)];
+        let mut tags = tags
+            .iter()
+            .filter_map(|(tag, value)| Some((tag, (*value)?)));
+        if let (true, Some((tag, value))) = (self.dogstatsd, tags.next()) {
+            write!(line, "|#{}:", tag).unwrap();
+            push_tag_value(&mut line, value);
+            for (tag, value) in tags {
+                write!(line, ",{}:", tag).unwrap();
+                push_tag_value(&mut line, value);
+            }
+        }
+        line
+    }
+}
+
+// The mean and percentiles of request times in milliseconds, if there were any. Percentiles in
+// the overflow bucket are left out, since a gauge can't be infinite.
+fn latency_aggregates(latency: &LatencyHistogram) -> Vec<(&'static str, f64)> {
+    if latency.count == 0 {
+        return Vec::new();
+    }
+    let mut aggregates = vec![("request_time.mean", latency.sum / latency.count as f64)];
+    for (name, percentile) in PERCENTILES {
+        match latency.percentile(percentile) {
+            Some(seconds) if seconds.is_finite() => aggregates.push((name, seconds)),
+            _ => {}
+        }
+    }
+    aggregates
+        .into_iter()
+        // Rounded to the microsecond, so that float error doesn't show up as 3.0000000000000004
+        .map(|(name, seconds)| (name, (seconds * 1_000_000.0).round() / 1000.0))
+        .collect()
+}
+
+// A route as one component of a metric name, e.g. "/api/users" as "api_users" and "/" as "root",
+// since dots, colons and pipes would be read as part of the protocol
+fn push_name(line: &mut String, route: &str) {
+    let route = route.trim_start_matches('/');
+    if route.is_empty() {
+        line.push_str("root");
+        return;
+    }
+    for c in route.chars() {
+        match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => line.push(c),
+            _ => line.push('_'),
+        }
+    }
+}
+
+// Commas separate tags and pipes separate sections, so neither can appear in a value
+fn push_tag_value(line: &mut String, value: &str) {
+    for c in value.chars() {
+        match c {
+            ',' | '|' => line.push('_'),
+            c if c.is_control() => line.push('_'),
+            c => line.push(c),
+        }
+    }
+}
+
+// Join lines into as few packets as will fit, one metric per line
+fn packets(lines: &[String]) -> Vec<String> {
+    let mut packets: Vec<String> = Vec::new();
+    for line in lines {
+        match packets.last_mut() {
+            Some(packet) if packet.len() + 1 + line.len() <= MAX_PACKET_SIZE => {
+                packet.push('\n');
+                packet.push_str(line);
+            }
+            _ => packets.push(line.clone()),
+        }
+    }
+    packets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::geoip::Location;
+    use crate::parsers::nginx;
+    use crate::view::View;
+
+    fn interval(dogstatsd: bool) -> (Statsd, Interval) {
+        let args = vec![
+            "dashlight".to_string(),
+            "watch".into(),
+            "--statsd".into(),
+            "127.0.0.1:8125".into(),
+        ];
+        let config = Config::new(args);
+        let mut view = View::new(&config);
+        let lines = [
+            "1.2.3.4 - - [09/May/2022:00:00:07 +0000] \"GET /api/users HTTP/1.1\" 200 100 \"-\" \"curl\"",
+            "1.2.3.4 - - [09/May/2022:00:00:07 +0000] \"GET /api/users HTTP/1.1\" 503 50 \"-\" \"curl\"",
+            "1.2.3.4 - - [09/May/2022:00:00:07 +0000] \"GET / HTTP/1.1\" 200 10 \"-\" \"curl\"",
+        ];
+        for line in lines {
+            let log = nginx::get_log_from_logline(line).unwrap();
+            view.update(log, &Location::default());
+        }
+        let statsd = Statsd::new(config.statsd.unwrap(), dogstatsd, 1, SortKey::Total).unwrap();
+        (statsd, view.take_interval().unwrap())
+    }
+
+    #[test]
+    fn routes_go_in_names() {
+        let (statsd, interval) = interval(false);
+        assert_eq!(
+            statsd.lines(&interval),
+            [
+                "dashlight.requests.2xx:2|c",
+                "dashlight.requests.3xx:0|c",
+                "dashlight.requests.4xx:0|c",
+                "dashlight.requests.5xx:1|c",
+                "dashlight.bytes:160|c",
+                "dashlight.route.api_users.requests.2xx:1|c",
+                "dashlight.route.api_users.requests.5xx:1|c",
+                "dashlight.route.api_users.bytes:150|c",
+            ]
+        );
+    }
+
+    #[test]
+    fn routes_go_in_dogstatsd_tags() {
+        let (statsd, interval) = interval(true);
+        let lines = statsd.lines(&interval);
+        assert_eq!(lines[0], "dashlight.requests:2|c|#status_class:2xx");
+        assert_eq!(lines[4], "dashlight.bytes:160|c");
+        assert_eq!(
+            lines[5],
+            "dashlight.route.requests:1|c|#route:/api/users,status_class:2xx"
+        );
+        assert_eq!(lines[7], "dashlight.route.bytes:150|c|#route:/api/users");
+    }
+
+    #[test]
+    fn latency_is_in_milliseconds() {
+        let mut latency = LatencyHistogram::new();
+        latency.record(0.0025);
+        latency.record(0.0035);
+        latency.record(90.0);
+        assert_eq!(
+            latency_aggregates(&latency),
+            [("request_time.mean", 30002.0), ("request_time.p50", 5.0)]
+        );
+    }
+
+    #[test]
+    fn lines_are_packed_into_packets() {
+        let lines: Vec<String> = (0..100).map(|i| format!("metric.{:03}:1|c", i)).collect();
+        let packets = packets(&lines);
+        assert_eq!(packets.len(), 2);
+        assert!(packets.iter().all(|packet| packet.len() <= MAX_PACKET_SIZE));
+        assert_eq!(packets.join("\n"), lines.join("\n"));
+    }
+}
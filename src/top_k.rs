@@ -0,0 +1,184 @@
+use std::cmp::Ordering;
+
+// Selects the k highest-ranked items from a stream, using O(k) memory and O(n log k) time.
+// Rankings are computed once, when the table is displayed, rather than tracked incrementally on
+// every line: keys like error rate and p99 can go down as well as up, so a route that fell out
+// of an incremental top-k could never be correctly brought back.
+//
+// Items are kept in a binary heap whose root is the lowest-ranked item we're holding on to, so
+// each new item only needs to be compared against the root to know whether it makes the cut.
+pub struct TopK<T, F>
+where
+    F: Fn(&T, &T) -> Ordering,
+{
+    k: usize,
+    // Ordering::Less means that the first item ranks higher than the second
+    by_rank: F,
+    heap: Vec<T>,
+}
+
+impl<T, F> TopK<T, F>
+where
+    F: Fn(&T, &T) -> Ordering,
+{
+    pub fn new(k: usize, by_rank: F) -> TopK<T, F> {
+        TopK {
+            k,
+            by_rank,
+            // k comes from --top, which can be far more than there are items, so the heap only
+            // grows as items arrive
+            heap: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, item: T) {
+        if self.heap.len() < self.k {
+            self.heap.push(item);
+            self.sift_up(self.heap.len() - 1);
+        } else if self.k > 0 && (self.by_rank)(&item, &self.heap[0]) == Ordering::Less {
+            self.heap[0] = item;
+            self.sift_down(0);
+        }
+    }
+
+    // Consume the selector, returning the items from highest to lowest rank
+    pub fn into_sorted_vec(self) -> Vec<T> {
+        let mut items = self.heap;
+        items.sort_unstable_by(&self.by_rank);
+        items
+    }
+
+    // True if the item at a should sit above the item at b, i.e. it ranks lower
+    fn is_lower(&self, a: usize, b: usize) -> bool {
+        (self.by_rank)(&self.heap[a], &self.heap[b]) == Ordering::Greater
+    }
+
+    fn sift_up(&mut self, mut index: usize) {
+        while index > 0 {
+            let parent = (index - 1) / 2;
+            if !self.is_lower(index, parent) {
+                break;
+            }
+            self.heap.swap(index, parent);
+            index = parent;
+        }
+    }
+
+    fn sift_down(&mut self, mut index: usize) {
+        loop {
+            let mut lowest = index;
+            for child in [2 * index + 1, 2 * index + 2] {
+                if child < self.heap.len() && self.is_lower(child, lowest) {
+                    lowest = child;
+                }
+            }
+            if lowest == index {
+                break;
+            }
+            self.heap.swap(index, lowest);
+            index = lowest;
+        }
+    }
+}
+
+// Convenience wrapper for the common case of ranking an iterator in one go
+pub fn top_k<T, F>(items: impl Iterator<Item = T>, k: usize, by_rank: F) -> Vec<T>
+where
+    F: Fn(&T, &T) -> Ordering,
+{
+    let mut top = TopK::new(k, by_rank);
+    items.for_each(|item| top.push(item));
+    top.into_sorted_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SortKey;
+    use crate::parsers::nginx;
+    use crate::stats::{self, RequestStats};
+    use crate::test_rng::Rng;
+    use std::collections::HashMap;
+
+    const SORT_KEYS: [SortKey; 8] = [
+        SortKey::Total,
+        SortKey::X2,
+        SortKey::X3,
+        SortKey::X4,
+        SortKey::X5,
+        SortKey::ErrorRate,
+        SortKey::Bytes,
+        SortKey::P99,
+    ];
+
+    // Build per-route stats from a random stream, skewed so that some routes are much busier
+    fn random_routes(rng: &mut Rng, lines: usize, routes: u64) -> HashMap<String, RequestStats> {
+        let mut stats_by_route: HashMap<String, RequestStats> = HashMap::new();
+        for _ in 0..lines {
+            let busiest = rng.below(routes) + 1;
+            let route = format!("/{}", rng.below(busiest));
+            let log = nginx::NginxCombinedLog {
+                status: [200, 201, 304, 404, 500, 503][rng.below(6) as usize],
+                body_bytes_sent: rng.below(10_000) as u32,
+                request_time: Some(rng.below(5_000) as f64 / 1000.0),
+                ..Default::default()
+            };
+            stats_by_route.entry(route).or_default().update(&log);
+        }
+        stats_by_route
+    }
+
+    #[test]
+    fn matches_brute_force_ranking_on_random_streams() {
+        for seed in 1..=50 {
+            let mut rng = Rng(seed);
+            let lines = rng.below(2_000) as usize;
+            let routes = rng.below(300) + 1;
+            let stats_by_route = random_routes(&mut rng, lines, routes);
+
+            for key in SORT_KEYS {
+                let k = rng.below(15) as usize;
+                let by_rank = |a: &(&str, &RequestStats), b: &(&str, &RequestStats)| {
+                    stats::by_rank(key, *a, *b)
+                };
+                let entries = stats_by_route.iter().map(|(r, s)| (r.as_str(), s));
+
+                let mut expected: Vec<(&str, &RequestStats)> = entries.clone().collect();
+                expected.sort_by(by_rank);
+                expected.truncate(k);
+                let actual = top_k(entries, k, by_rank);
+
+                let expected: Vec<&str> = expected.iter().map(|x| x.0).collect();
+                let actual: Vec<&str> = actual.iter().map(|x| x.0).collect();
+                assert_eq!(actual, expected, "seed {} key {:?} k {}", seed, key, k);
+            }
+        }
+    }
+
+    #[test]
+    fn matches_brute_force_on_random_integers() {
+        let mut rng = Rng(0x5eed);
+        for _ in 0..200 {
+            let items: Vec<u64> = (0..rng.below(500)).map(|_| rng.below(100)).collect();
+            let k = rng.below(20) as usize;
+
+            let mut expected = items.clone();
+            expected.sort_by(|a, b| b.cmp(a));
+            expected.truncate(k);
+            assert_eq!(top_k(items.into_iter(), k, |a, b| b.cmp(a)), expected);
+        }
+    }
+
+    #[test]
+    fn huge_k_keeps_everything() {
+        assert_eq!(
+            top_k([3, 1, 2].into_iter(), usize::MAX, |a, b| b.cmp(a)),
+            [3, 2, 1]
+        );
+    }
+
+    #[test]
+    fn zero_k_keeps_nothing() {
+        assert!(top_k([3, 1, 2].into_iter(), 0, |a, b| b.cmp(a)).is_empty());
+    }
+}
@@ -1,136 +1,1076 @@
-use crate::parsers::nginx;
-use crate::stats::{self, StatusCodeStats};
+use crate::agents::Agent;
+use crate::config::{Config, SortKey, UniqueKey};
+use crate::geoip::Location;
+use crate::hyperloglog::HyperLogLog;
+use crate::json;
+use crate::metrics::Exposition;
+use crate::net::{self, Cidr};
+use crate::parsers::{nginx, url};
+use crate::route_tree::RouteTree;
+use crate::stats::{self, MethodStats, RequestStats};
+use crate::stats_table::StatsTable;
+use std::borrow::Cow;
 use std::collections::HashMap;
-use std::fmt;
+use std::fmt::{self, Write};
+use std::mem;
+use std::net::IpAddr;
+
+// Maximum number of query parameter names or landing routes listed for each row
+const MAX_COUNTS: usize = 5;
+// Name of the row holding keys that were evicted when a table is bounded
+const OTHER: &str = "(other)";
+// HyperLogLog precision for unique clients overall (16KiB, ~0.8% error) and by route (1KiB, ~3.3%)
+const GLOBAL_UNIQUES_PRECISION: u8 = 14;
+const ROUTE_UNIQUES_PRECISION: u8 = 10;
+// Name of the row holding clients that weren't found in a GeoIP database
+const UNKNOWN: &str = "(unknown)";
+// Version of the JSON output's schema, which only changes when fields are removed or redefined
+const JSON_VERSION: u32 = 1;
 
 #[derive(Debug)]
 pub struct View {
-    displayed_routes: Vec<(String, stats::StatusCodeStats)>,
-    global_codes: stats::StatusCodeStats,
-    codes_by_route: HashMap<String, stats::StatusCodeStats>,
+    global_stats: RequestStats,
+    // Stats by virtual host, if the log format includes it
+    hosts: Option<StatsTable>,
+    routes: StatsTable,
+    // Reused when keying routes by host and path
+    route_key: String,
+    // Stats by client address; only populated if requested
+    clients: Option<StatsTable>,
+    // Stats by client subnet, grouped by the configured IPv4 and IPv6 prefix lengths
+    subnets: Option<StatsTable>,
+    subnet_prefixes: (u8, u8),
+    // Stats by client country and autonomous system, if GeoIP databases were provided
+    countries: Option<StatsTable>,
+    asns: Option<StatsTable>,
+    // Stats by user agent family, if requested
+    agents: Option<StatsTable>,
+    // Number of requests left out because they came from bots, if they're being excluded
+    excluded_bots: Option<u32>,
+    excluded_by_rules: Option<u32>,
+    // Number of lines skipped because they didn't match the log format
+    unparsed: u32,
+    // Reused when building subnet and ASN keys, so that each line doesn't need a new allocation
+    scratch_key: String,
+    // Number of routes to display, and the column they are ranked by
+    top: usize,
+    sort: SortKey,
+    // Counts of each query parameter name, by route; only populated if requested
+    query_params_by_route: Option<HashMap<String, HashMap<String, u32>>>,
+    canonicalize: bool,
+    // Number of requests whose path had to be canonicalized
+    canonicalized: u32,
+    // Routes grouped by path prefix; replaces the route table if a depth was provided
+    route_tree: Option<RouteTree>,
+    // Approximate number of distinct clients; only populated if requested
+    uniques: Option<Uniques>,
+    // Where requests were referred from; only populated if requested
+    referrers: Option<Referrers>,
+    // Status codes by method, overall and by route; only populated when exporting metrics
+    methods: Option<Methods>,
+    // Stats since they were last taken, for sinks that are sent each interval's counts
+    interval: Option<Interval>,
+}
+
+#[derive(Debug)]
+pub struct Interval {
+    pub global: RequestStats,
+    pub routes: StatsTable,
+    max_routes: Option<usize>,
+}
+
+impl Interval {
+    fn new(max_routes: Option<usize>) -> Interval {
+        Interval {
+            global: RequestStats::new(),
+            routes: StatsTable::new(max_routes),
+            max_routes,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Methods {
+    global: MethodStats,
+    by_route: HashMap<String, MethodStats>,
+}
+
+#[derive(Debug)]
+struct Referrers {
+    own_domains: Vec<String>,
+    // Requests without a referer, or with one we couldn't read a domain from
+    direct: u32,
+    internal: u32,
+    external: u32,
+    // Stats by external domain, along with the routes that each one sent requests to. Both are
+    // bounded by --max-routes like the route table.
+    domains: StatsTable,
+    landings: HashMap<String, HashMap<String, u32>>,
+}
+
+impl Referrers {
+    // Count a request's referer, returning its domain if it was external
+    fn update<'a>(&mut self, log: &nginx::NginxCombinedLog<'a>) -> Option<Cow<'a, str>> {
+        let domain = match url::referrer_domain(log.http_referer) {
+            Some(domain) => domain,
+            None => {
+                self.direct += 1;
+                return None;
+            }
+        };
+        if url::is_own_domain(&domain, &self.own_domains) {
+            self.internal += 1;
+            return None;
+        }
+        self.external += 1;
+        if let Some(evicted) = self.domains.update(&domain, log) {
+            self.landings.remove(&evicted);
+        }
+        Some(domain)
+    }
+
+    // Forget the landings on a route that was evicted from the route table
+    fn remove_route(&mut self, route: &str) {
+        self.landings.retain(|_, routes| {
+            routes.remove(route);
+            !routes.is_empty()
+        });
+    }
+}
+
+#[derive(Debug)]
+struct Uniques {
+    key: UniqueKey,
+    global: HyperLogLog,
+    by_route: HashMap<String, HyperLogLog>,
 }
 
 impl View {
-    pub fn new() -> View {
+    pub fn new(config: &Config) -> View {
         View {
-            global_codes: stats::StatusCodeStats::new(),
+            global_stats: RequestStats::new(),
             // Only routes that we were able to parse (valid routes) go here
-            codes_by_route: HashMap::new(),
-            displayed_routes: vec![],
+            hosts: config.hosts.then(|| StatsTable::new(config.max_routes)),
+            routes: StatsTable::new(config.max_routes),
+            route_key: String::new(),
+            clients: config.clients.then(|| StatsTable::new(config.max_routes)),
+            subnets: config.subnets.map(|_| StatsTable::new(config.max_routes)),
+            subnet_prefixes: config.subnets.unwrap_or((24, 64)),
+            countries: config
+                .geoip
+                .as_ref()
+                .map(|_| StatsTable::new(config.max_routes)),
+            asns: config
+                .asn
+                .as_ref()
+                .map(|_| StatsTable::new(config.max_routes)),
+            scratch_key: String::new(),
+            agents: config.agents.then(|| StatsTable::new(config.max_routes)),
+            excluded_bots: config.exclude_bots.then_some(0),
+            excluded_by_rules: config.exclusions.is_some().then_some(0),
+            unparsed: 0,
+            top: config.top,
+            sort: config.sort,
+            query_params_by_route: config.query_params.then(HashMap::new),
+            canonicalize: config.canonicalize,
+            canonicalized: 0,
+            route_tree: config
+                .depth
+                .map(|depth| RouteTree::new(depth, config.max_routes)),
+            uniques: config.unique.map(|key| Uniques {
+                key,
+                global: HyperLogLog::new(GLOBAL_UNIQUES_PRECISION),
+                by_route: HashMap::new(),
+            }),
+            referrers: config.referrers.then(|| Referrers {
+                own_domains: config.own_domains.clone(),
+                direct: 0,
+                internal: 0,
+                external: 0,
+                domains: StatsTable::new(config.max_routes),
+                landings: HashMap::new(),
+            }),
+            methods: config.metrics().then(|| Methods {
+                global: MethodStats::new(),
+                by_route: HashMap::new(),
+            }),
+            interval: config.intervals().then(|| Interval::new(config.max_routes)),
+        }
+    }
+
+    // Hand over the stats gathered since the last call, and start counting again
+    pub fn take_interval(&mut self) -> Option<Interval> {
+        self.interval.as_mut().map(|interval| {
+            let next = Interval::new(interval.max_routes);
+            mem::replace(interval, next)
+        })
+    }
+
+    // Count a request that was filtered out, rather than included in the stats
+    pub fn exclude_bot(&mut self) {
+        if let Some(excluded) = &mut self.excluded_bots {
+            *excluded += 1;
+        }
+    }
+
+    pub fn exclude_by_rule(&mut self) {
+        if let Some(excluded) = &mut self.excluded_by_rules {
+            *excluded += 1;
         }
     }
 
-    pub fn update(&mut self, log: nginx::NginxCombinedLog) {
-        self.global_codes.update(&log);
+    pub fn unparsed(&mut self) {
+        self.unparsed += 1;
+    }
+
+    pub fn update(&mut self, log: nginx::NginxCombinedLog, location: &Location) {
+        self.global_stats.update(&log);
+        if let Some(methods) = &mut self.methods {
+            methods.global.update(&log);
+        }
+        if let Some(interval) = &mut self.interval {
+            interval.global.update(&log);
+        }
+
+        if let Some(hosts) = &mut self.hosts {
+            hosts.update(log.host, &log);
+        }
+
+        // Hash the client once, since it's counted both overall and for its route
+        let unique_hash = self.uniques.as_mut().map(|uniques| {
+            let hash = match uniques.key {
+                UniqueKey::Ip => HyperLogLog::hash(log.remote_addr),
+                UniqueKey::IpAndAgent => HyperLogLog::hash(&(log.remote_addr, log.http_user_agent)),
+            };
+            uniques.global.insert_hash(hash);
+            hash
+        });
+
+        if let Some(clients) = &mut self.clients {
+            clients.update(log.remote_addr, &log);
+        }
+
+        if let (Some(subnets), Some(addr)) = (&mut self.subnets, net::parse_addr(log.remote_addr)) {
+            let prefix = match addr {
+                IpAddr::V4(_) => self.subnet_prefixes.0,
+                IpAddr::V6(_) => self.subnet_prefixes.1,
+            };
+            self.scratch_key.clear();
+            write!(self.scratch_key, "{}", Cidr::containing(addr, prefix)).unwrap();
+            subnets.update(&self.scratch_key, &log);
+        }
+
+        if let Some(countries) = &mut self.countries {
+            countries.update(location.country.unwrap_or(UNKNOWN), &log);
+        }
+
+        if let Some(asns) = &mut self.asns {
+            self.scratch_key.clear();
+            match location.asn {
+                Some(asn) => write!(self.scratch_key, "AS{} {}", asn.number, asn.organization),
+                None => write!(self.scratch_key, "{}", UNKNOWN),
+            }
+            .unwrap();
+            asns.update(&self.scratch_key, &log);
+        }
 
-        let request_url = match log.request_url {
+        if let Some(agents) = &mut self.agents {
+            agents.update(Agent::classify(log.http_user_agent).name(), &log);
+        }
+
+        let external_referrer = match &mut self.referrers {
+            Some(referrers) => referrers.update(&log),
+            None => None,
+        };
+
+        // Routes are keyed by path, so that different query strings count towards the same route
+        let request_path = match log.request_path {
             Some(x) => x,
             None => return, // Return early on an invalid route
         };
 
+        let request_url = match self.canonicalize {
+            true => url::canonicalize_path(request_path),
+            false => Cow::Borrowed(request_path),
+        };
+        if let Cow::Owned(_) = request_url {
+            self.canonicalized += 1;
+        }
+        let request_url = request_url.as_ref();
+
+        // The tree groups by path prefix, so it's shared by every host
+        if let Some(tree) = &mut self.route_tree {
+            tree.update(request_url, &log);
+        }
+
+        // With several sites in one log, the same path on each one is a different route
+        let route = match self.hosts {
+            Some(_) => {
+                self.route_key.clear();
+                self.route_key.push_str(log.host);
+                self.route_key.push_str(request_url);
+                self.route_key.as_str()
+            }
+            None => request_url,
+        };
+
         // Get the stats for this particular route, and update them based on the log
-        let codes_for_route = self
-            .codes_by_route
-            .entry(String::from(request_url))
-            .or_insert(stats::StatusCodeStats::new());
-        codes_for_route.update(&log);
-
-        // Update the route's position in the display based on this new information
-        // It might have come into the top 10, or moved up a spot
-        let position = self
-            .displayed_routes
-            .iter()
-            .position(|item| item.0 == request_url);
-        match position {
-            // If the route already exists in our displayed_routes, update it
-            Some(index) => self.displayed_routes[index].1 = *codes_for_route,
-            // Otherwise, check whether it fits in the display
-            None => {
-                let route = String::from(request_url);
-                if self.displayed_routes.len() < 10 {
-                    // The display has a max of 10, so add it if we're under the max
-                    self.displayed_routes.push((route, *codes_for_route));
-                } else if codes_for_route.sum() > self.displayed_routes[9].1.sum() {
-                    // Replace the lowest one (guaranteed by sort) with the current one
-                    self.displayed_routes[9] = (route, *codes_for_route);
+        let evicted = self.routes.update(route, &log);
+        if let Some(interval) = &mut self.interval {
+            interval.routes.update(route, &log);
+        }
+
+        if let (Some(uniques), Some(hash)) = (&mut self.uniques, unique_hash) {
+            if let Some(evicted) = &evicted {
+                uniques.by_route.remove(evicted);
+            }
+            match uniques.by_route.get_mut(route) {
+                Some(hll) => hll.insert_hash(hash),
+                None => {
+                    let mut hll = HyperLogLog::new(ROUTE_UNIQUES_PRECISION);
+                    hll.insert_hash(hash);
+                    uniques.by_route.insert(String::from(route), hll);
+                }
+            }
+        }
+
+        if let Some(methods) = &mut self.methods {
+            if let Some(evicted) = &evicted {
+                methods.by_route.remove(evicted);
+            }
+            match methods.by_route.get_mut(route) {
+                Some(stats) => stats.update(&log),
+                None => {
+                    let mut stats = MethodStats::new();
+                    stats.update(&log);
+                    methods.by_route.insert(String::from(route), stats);
+                }
+            }
+        }
+
+        if let Some(referrers) = &mut self.referrers {
+            if let Some(evicted) = &evicted {
+                referrers.remove_route(evicted);
+            }
+            if let Some(domain) = external_referrer {
+                let routes = match referrers.landings.get_mut(domain.as_ref()) {
+                    Some(routes) => routes,
+                    None => referrers.landings.entry(domain.into_owned()).or_default(),
+                };
+                match routes.get_mut(route) {
+                    Some(count) => *count += 1,
+                    None => {
+                        routes.insert(String::from(route), 1);
+                    }
+                }
+            }
+        }
+
+        if let Some(params_by_route) = &mut self.query_params_by_route {
+            if let Some(evicted) = evicted {
+                params_by_route.remove(&evicted);
+            }
+            if let Some(query) = log.request_query {
+                let params = params_by_route.entry(String::from(route)).or_default();
+                for name in url::query_param_names(query) {
+                    match params.get_mut(name) {
+                        Some(count) => *count += 1,
+                        None => {
+                            params.insert(String::from(name), 1);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl View {
+    // The same sections as the text output, as a single JSON object (see the README for the
+    // schema). Sections that weren't requested are null.
+    pub fn to_json(&self) -> String {
+        let mut out = String::new();
+        write!(out, "{{\"version\":{},\"total\":{{", JSON_VERSION).unwrap();
+        let uniques = self.uniques.as_ref();
+        push_stats(
+            &mut out,
+            &self.global_stats,
+            uniques.map(|uniques| uniques.global.estimate()),
+        );
+        out.push_str("},\"canonicalized\":");
+        push_option(&mut out, self.canonicalize.then_some(self.canonicalized));
+        out.push_str(",\"excluded_bots\":");
+        push_option(&mut out, self.excluded_bots);
+        out.push_str(",\"excluded_by_rules\":");
+        push_option(&mut out, self.excluded_by_rules);
+        write!(out, ",\"unparsed\":{}", self.unparsed).unwrap();
+
+        out.push_str(",\"hosts\":");
+        match &self.hosts {
+            Some(hosts) => push_table(&mut out, hosts, &hosts.top(self.top, SortKey::Total), None),
+            None => out.push_str("null"),
+        }
+
+        let routes = self.routes.top(self.top, self.sort);
+        out.push_str(",\"routes\":");
+        let route_uniques = uniques.map(|uniques| &uniques.by_route);
+        push_table(&mut out, &self.routes, &routes, route_uniques);
+
+        out.push_str(",\"route_tree\":");
+        match &self.route_tree {
+            Some(tree) => {
+                out.push('[');
+                for (index, (depth, prefix, stats)) in
+                    tree.rows(self.top, self.sort).iter().enumerate()
+                {
+                    if index > 0 {
+                        out.push(',');
+                    }
+                    write!(out, "{{\"depth\":{},\"prefix\":", depth).unwrap();
+                    json::push_string(&mut out, prefix);
+                    out.push(',');
+                    push_stats(&mut out, stats, None);
+                    out.push('}');
                 }
-                // Always sort after we replace, to guarantee that the last index holds the lowest
-                self.displayed_routes
-                    .sort_unstable_by_key(|a| std::cmp::Reverse(a.1.sum()))
+                out.push(']');
             }
+            None => out.push_str("null"),
         }
+
+        out.push_str(",\"query_params\":");
+        match &self.query_params_by_route {
+            Some(params_by_route) => {
+                let params = routes.iter().filter_map(|(route, _)| {
+                    let params = params_by_route.get(*route).filter(|p| !p.is_empty())?;
+                    Some((*route, params))
+                });
+                push_counts_by_key(&mut out, params, "route", "params");
+            }
+            None => out.push_str("null"),
+        }
+
+        let tables = [
+            ("clients", &self.clients),
+            ("subnets", &self.subnets),
+            ("countries", &self.countries),
+            ("asns", &self.asns),
+            ("agents", &self.agents),
+        ];
+        for (name, table) in tables {
+            write!(out, ",\"{}\":", name).unwrap();
+            match table {
+                Some(table) => {
+                    push_table(&mut out, table, &table.top(self.top, SortKey::Total), None)
+                }
+                None => out.push_str("null"),
+            }
+        }
+
+        out.push_str(",\"referrers\":");
+        match &self.referrers {
+            Some(referrers) => {
+                write!(
+                    out,
+                    "{{\"direct\":{},\"internal\":{},\"external\":{},\"domains\":",
+                    referrers.direct, referrers.internal, referrers.external
+                )
+                .unwrap();
+                let top_referrers = referrers.domains.top(self.top, SortKey::Total);
+                push_table(&mut out, &referrers.domains, &top_referrers, None);
+                out.push_str(",\"landings\":");
+                let landings = top_referrers
+                    .iter()
+                    .filter_map(|(domain, _)| Some((*domain, referrers.landings.get(*domain)?)));
+                push_counts_by_key(&mut out, landings, "referrer", "routes");
+                out.push('}');
+            }
+            None => out.push_str("null"),
+        }
+
+        out.push('}');
+        out
     }
 }
 
-fn write_dividing_line(f: &mut fmt::Formatter, name_width: usize, num_width: usize) -> fmt::Result {
-    writeln!(
+impl View {
+    // Counters and histograms in the Prometheus text format. Only the top routes get their own
+    // series, so that a scanner requesting random paths can't create unbounded label sets.
+    pub fn to_metrics(&self) -> String {
+        let mut exposition = Exposition::new();
+        let methods = self.methods.as_ref();
+
+        let name = "dashlight_http_requests_total";
+        exposition.family(name, "counter", "Requests, by status class and method.");
+        if let Some(methods) = methods {
+            push_method_samples(&mut exposition, name, None, &methods.global);
+        }
+
+        let routes = self.routes.top(self.top, self.sort);
+        let name = "dashlight_http_route_requests_total";
+        exposition.family(
+            name,
+            "counter",
+            "Requests to the top routes, by status class and method.",
+        );
+        if let Some(methods) = methods {
+            for (route, _) in &routes {
+                if let Some(stats) = methods.by_route.get(*route) {
+                    push_method_samples(&mut exposition, name, Some(route), stats);
+                }
+            }
+        }
+
+        let name = "dashlight_http_response_bytes_total";
+        exposition.family(name, "counter", "Response body bytes sent.");
+        exposition.sample(name, &[], self.global_stats.bytes);
+        let name = "dashlight_http_route_response_bytes_total";
+        exposition.family(
+            name,
+            "counter",
+            "Response body bytes sent by the top routes.",
+        );
+        for (route, stats) in &routes {
+            exposition.sample(name, &[("route", route)], stats.bytes);
+        }
+
+        // Request times are only in the log if its format includes them
+        if self.global_stats.latency.count > 0 {
+            let name = "dashlight_http_request_duration_seconds";
+            exposition.family(name, "histogram", "Request times.");
+            exposition.histogram(name, &[], &self.global_stats.latency);
+            let name = "dashlight_http_route_request_duration_seconds";
+            exposition.family(name, "histogram", "Request times of the top routes.");
+            for (route, stats) in &routes {
+                exposition.histogram(name, &[("route", route)], &stats.latency);
+            }
+        }
+
+        let excluded = [
+            ("bots", self.excluded_bots),
+            ("rules", self.excluded_by_rules),
+        ];
+        if excluded.iter().any(|(_, count)| count.is_some()) {
+            let name = "dashlight_excluded_requests_total";
+            exposition.family(name, "counter", "Requests left out of every other metric.");
+            for (reason, count) in excluded {
+                if let Some(count) = count {
+                    exposition.sample(name, &[("reason", reason)], count);
+                }
+            }
+        }
+
+        // Lines that didn't parse aren't requests, so they're counted apart from exclusions
+        let name = "dashlight_unparsed_lines_total";
+        exposition.family(
+            name,
+            "counter",
+            "Log lines that didn't match the log format.",
+        );
+        exposition.sample(name, &[], self.unparsed);
+
+        if let StatsTable::Bounded { slots, .. } = &self.routes {
+            let name = "dashlight_evicted_routes_total";
+            exposition.family(
+                name,
+                "counter",
+                "Routes evicted to stay within --max-routes.",
+            );
+            exposition.sample(name, &[], slots.evictions());
+        }
+
+        exposition.finish()
+    }
+}
+
+// One sample for each method and status class with any requests
+fn push_method_samples(
+    exposition: &mut Exposition,
+    name: &str,
+    route: Option<&str>,
+    methods: &MethodStats,
+) {
+    for (method, codes) in methods.iter() {
+        let classes = [
+            ("2xx", codes.x2),
+            ("3xx", codes.x3),
+            ("4xx", codes.x4),
+            ("5xx", codes.x5),
+        ];
+        for (class, count) in classes.into_iter().filter(|(_, count)| *count > 0) {
+            let mut labels = vec![("status_class", class), ("method", method)];
+            if let Some(route) = route {
+                labels.insert(0, ("route", route));
+            }
+            exposition.sample(name, &labels, count);
+        }
+    }
+}
+
+fn push_option(out: &mut String, value: Option<u32>) {
+    match value {
+        Some(value) => write!(out, "{}", value).unwrap(),
+        None => out.push_str("null"),
+    }
+}
+
+// The members of a stats object. Percentiles are in seconds, and null if there were no request
+// times or they fell in the overflow bucket; "timed" is the number of requests with a time.
+fn push_stats(out: &mut String, stats: &RequestStats, uniques: Option<u64>) {
+    let codes = stats.codes;
+    write!(
+        out,
+        "\"requests\":{},\"2xx\":{},\"3xx\":{},\"4xx\":{},\"5xx\":{},\"bytes\":{},\"timed\":{}",
+        codes.sum(),
+        codes.x2,
+        codes.x3,
+        codes.x4,
+        codes.x5,
+        stats.bytes,
+        stats.latency.count
+    )
+    .unwrap();
+    for percentile in [50, 90, 99] {
+        write!(out, ",\"p{}\":", percentile).unwrap();
+        match stats.latency.percentile(percentile as f64) {
+            Some(seconds) => json::push_f64(out, seconds),
+            None => out.push_str("null"),
+        }
+    }
+    out.push_str(",\"uniques\":");
+    match uniques {
+        Some(uniques) => write!(out, "{}", uniques).unwrap(),
+        None => out.push_str("null"),
+    }
+}
+
+// The top rows of a table, along with the catch-all row and evictions if it's bounded
+fn push_table(
+    out: &mut String,
+    table: &StatsTable,
+    rows: &[(&str, &RequestStats)],
+    uniques: Option<&HashMap<String, HyperLogLog>>,
+) {
+    out.push_str("{\"rows\":[");
+    for (index, (key, stats)) in rows.iter().enumerate() {
+        if index > 0 {
+            out.push(',');
+        }
+        out.push_str("{\"key\":");
+        json::push_string(out, key);
+        out.push(',');
+        let unique = uniques
+            .and_then(|uniques| uniques.get(*key))
+            .map(HyperLogLog::estimate);
+        push_stats(out, stats, unique);
+        out.push('}');
+    }
+    out.push_str("],\"other\":");
+    match table {
+        StatsTable::Bounded { slots, other } => {
+            out.push('{');
+            push_stats(out, other, None);
+            write!(out, "}},\"evicted\":{}}}", slots.evictions()).unwrap();
+        }
+        StatsTable::Exact(_) => out.push_str("null,\"evicted\":null}"),
+    }
+}
+
+// A list of keys, each with its most common names, e.g. the query parameters of each route
+fn push_counts_by_key<'a>(
+    out: &mut String,
+    counts_by_key: impl Iterator<Item = (&'a str, &'a HashMap<String, u32>)>,
+    key_name: &str,
+    counts_name: &str,
+) {
+    out.push('[');
+    for (index, (key, counts)) in counts_by_key.enumerate() {
+        if index > 0 {
+            out.push(',');
+        }
+        write!(out, "{{\"{}\":", key_name).unwrap();
+        json::push_string(out, key);
+        write!(out, ",\"{}\":[", counts_name).unwrap();
+        for (index, (name, count)) in top_counts(counts).iter().enumerate() {
+            if index > 0 {
+                out.push(',');
+            }
+            out.push_str("{\"name\":");
+            json::push_string(out, name);
+            write!(out, ",\"count\":{}}}", count).unwrap();
+        }
+        out.push_str("]}");
+    }
+    out.push(']');
+}
+
+// Column widths for a table, and whether it includes latency and unique clients
+struct Layout {
+    name_width: usize,
+    num_width: usize,
+    latency: bool,
+    uniques: bool,
+}
+
+fn write_dividing_line(f: &mut fmt::Formatter, layout: &Layout) -> fmt::Result {
+    let Layout {
+        name_width,
+        num_width,
+        ..
+    } = *layout;
+    write!(
         f,
-        " {0:->name_width$} + {0:->num_width$} + {0:->num_width$} + {0:->num_width$} + {0:->num_width$} ",
+        " {0:->name_width$} + {0:->num_width$} + {0:->num_width$} + {0:->num_width$} + {0:->num_width$} + {0:->num_width$} ",
         ""
-    )
+    )?;
+    if layout.latency {
+        write!(f, "+ {0:->num_width$} ", "")?;
+    }
+    if layout.uniques {
+        write!(f, "+ {0:->num_width$} ", "")?;
+    }
+    writeln!(f)
 }
 
 fn write_stats(
     f: &mut fmt::Formatter,
-    name_width: usize,
-    num_width: usize,
+    layout: &Layout,
     name: &str,
-    stats: StatusCodeStats,
+    stats: &RequestStats,
+    uniques: Option<u64>,
 ) -> fmt::Result {
-    writeln!(
+    let Layout {
+        name_width,
+        num_width,
+        ..
+    } = *layout;
+    let codes = stats.codes;
+    write!(
         f,
-        " {:>name_width$} | {:>num_width$} | {:>num_width$} | {:>num_width$} | {:>num_width$} ",
-        name, stats.x2, stats.x3, stats.x4, stats.x5
-    )
+        " {:>name_width$} | {:>num_width$} | {:>num_width$} | {:>num_width$} | {:>num_width$} | {:>num_width$} ",
+        name,
+        codes.x2,
+        codes.x3,
+        codes.x4,
+        codes.x5,
+        format_bytes(stats.bytes)
+    )?;
+    if layout.latency {
+        let p99 = stats
+            .latency
+            .percentile(99.0)
+            .map_or(String::from("-"), format_seconds);
+        write!(f, "| {:>num_width$} ", p99)?;
+    }
+    if layout.uniques {
+        let uniques = uniques.map_or(String::from("-"), |x| x.to_string());
+        write!(f, "| {:>num_width$} ", uniques)?;
+    }
+    writeln!(f)
 }
-fn write_header(
+
+fn write_counts(
     f: &mut fmt::Formatter,
     name_width: usize,
-    num_width: usize,
     name: &str,
+    counts: &HashMap<String, u32>,
 ) -> fmt::Result {
-    writeln!(
+    let names: Vec<String> = top_counts(counts)
+        .iter()
+        .map(|(name, count)| format!("{} ({})", name, count))
+        .collect();
+    writeln!(f, " {:>name_width$} | {}", name, names.join(", "))
+}
+
+// The most common names first, breaking ties alphabetically so the output is stable
+fn top_counts(counts: &HashMap<String, u32>) -> Vec<(&String, &u32)> {
+    let mut counts: Vec<(&String, &u32)> = counts.iter().collect();
+    counts.sort_unstable_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+    counts.truncate(MAX_COUNTS);
+    counts
+}
+
+fn write_header(f: &mut fmt::Formatter, layout: &Layout, name: &str) -> fmt::Result {
+    let Layout {
+        name_width,
+        num_width,
+        ..
+    } = *layout;
+    write!(
         f,
-        " {:>name_width$} | {:>num_width$} | {:>num_width$} | {:>num_width$} | {:>num_width$} ",
-        name, "2xx", "3xx", "4xx", "5xx"
-    )
+        " {:>name_width$} | {:>num_width$} | {:>num_width$} | {:>num_width$} | {:>num_width$} | {:>num_width$} ",
+        name, "2xx", "3xx", "4xx", "5xx", "bytes"
+    )?;
+    if layout.latency {
+        write!(f, "| {:>num_width$} ", "p99")?;
+    }
+    if layout.uniques {
+        write!(f, "| {:>num_width$} ", "uniq")?;
+    }
+    writeln!(f)
 }
 
 impl fmt::Display for View {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let max_width = get_string_length_of_int(self.global_codes.sum());
+        let max_width = get_string_length_of_int(self.global_stats.codes.sum());
         let num_width = if max_width > 5 { max_width } else { 5 };
-        writeln!(f, "")?;
-        write_header(f, num_width, num_width, "count")?;
-        write_dividing_line(f, num_width, num_width)?;
+        // Only show latency if the logs include request times
+        let latency = self.global_stats.latency.count > 0;
+        let layout = Layout {
+            name_width: num_width,
+            num_width,
+            latency,
+            uniques: self.uniques.is_some(),
+        };
+        writeln!(f)?;
+        write_header(f, &layout, "count")?;
+        write_dividing_line(f, &layout)?;
         write_stats(
             f,
-            num_width,
-            num_width,
-            &self.global_codes.sum().to_string(),
-            self.global_codes,
+            &layout,
+            &self.global_stats.codes.sum().to_string(),
+            &self.global_stats,
+            self.uniques
+                .as_ref()
+                .map(|uniques| uniques.global.estimate()),
         )?;
+        if self.canonicalize {
+            writeln!(f, " canonicalized paths: {}", self.canonicalized)?;
+        }
+        if let Some(hosts) = &self.hosts {
+            let top_hosts = hosts.top(self.top, SortKey::Total);
+            write_table(f, &layout, "host", &top_hosts, hosts, None)?;
+        }
+        if let Some(excluded) = self.excluded_bots {
+            writeln!(f, " excluded bots: {}", excluded)?;
+        }
+        if let Some(excluded) = self.excluded_by_rules {
+            writeln!(f, " excluded by rules: {}", excluded)?;
+        }
+        if self.unparsed > 0 {
+            writeln!(f, " unparsed lines: {}", self.unparsed)?;
+        }
 
-        let max_width = self
-            .displayed_routes
-            .iter()
-            .map(|x| x.0.len())
-            .max()
-            .unwrap();
-        let name_width = if max_width > 5 { max_width } else { 5 };
-        writeln!(f, "")?;
-        write_header(f, name_width, num_width, "route")?;
-        for (route, codes) in &self.displayed_routes {
-            write_dividing_line(f, name_width, num_width)?;
-            write_stats(f, name_width, num_width, route, *codes)?;
+        let routes = self.routes.top(self.top, self.sort);
+        match &self.route_tree {
+            Some(tree) => write_route_tree(f, &layout, tree, self.top, self.sort)?,
+            None => {
+                let uniques = self.uniques.as_ref().map(|uniques| &uniques.by_route);
+                write_table(f, &layout, "route", &routes, &self.routes, uniques)?
+            }
+        }
+
+        if let Some(params_by_route) = &self.query_params_by_route {
+            let name_width = name_width(&routes);
+            writeln!(f)?;
+            writeln!(f, " {:>name_width$} | query params", "route")?;
+            writeln!(f, " {0:->name_width$} + {0:-<12}", "")?;
+            for (route, _) in &routes {
+                if let Some(params) = params_by_route.get(*route).filter(|p| !p.is_empty()) {
+                    write_counts(f, name_width, route, params)?;
+                }
+            }
+        }
+
+        if let Some(clients) = &self.clients {
+            let top_clients = clients.top(self.top, SortKey::Total);
+            write_table(f, &layout, "client", &top_clients, clients, None)?;
+        }
+
+        if let Some(subnets) = &self.subnets {
+            let top_subnets = subnets.top(self.top, SortKey::Total);
+            write_table(f, &layout, "subnet", &top_subnets, subnets, None)?;
+        }
+
+        if let Some(countries) = &self.countries {
+            let top_countries = countries.top(self.top, SortKey::Total);
+            write_table(f, &layout, "country", &top_countries, countries, None)?;
+        }
+
+        if let Some(asns) = &self.asns {
+            let top_asns = asns.top(self.top, SortKey::Total);
+            write_table(f, &layout, "asn", &top_asns, asns, None)?;
+        }
+
+        if let Some(agents) = &self.agents {
+            let top_agents = agents.top(self.top, SortKey::Total);
+            write_table(f, &layout, "agent", &top_agents, agents, None)?;
+        }
+
+        if let Some(referrers) = &self.referrers {
+            writeln!(f)?;
+            writeln!(
+                f,
+                " referrers: {} direct, {} internal, {} external",
+                referrers.direct, referrers.internal, referrers.external
+            )?;
+            let top_referrers = referrers.domains.top(self.top, SortKey::Total);
+            write_table(
+                f,
+                &layout,
+                "referrer",
+                &top_referrers,
+                &referrers.domains,
+                None,
+            )?;
+
+            let name_width = name_width(&top_referrers).max("referrer".len());
+            writeln!(f)?;
+            writeln!(f, " {:>name_width$} | landing routes", "referrer")?;
+            writeln!(f, " {0:->name_width$} + {0:-<14}", "")?;
+            for (domain, _) in &top_referrers {
+                if let Some(routes) = referrers.landings.get(*domain) {
+                    write_counts(f, name_width, domain, routes)?;
+                }
+            }
         }
 
         Ok(())
     }
 }
 
+// Write the top rows of a table, along with the catch-all row if it's bounded
+fn write_table(
+    f: &mut fmt::Formatter,
+    layout: &Layout,
+    name: &str,
+    rows: &[(&str, &RequestStats)],
+    table: &StatsTable,
+    uniques: Option<&HashMap<String, HyperLogLog>>,
+) -> fmt::Result {
+    let other_width = match table {
+        StatsTable::Bounded { .. } => OTHER.len(),
+        StatsTable::Exact(_) => 0,
+    };
+    let layout = Layout {
+        name_width: name_width(rows).max(name.len()).max(other_width),
+        num_width: layout.num_width,
+        latency: layout.latency,
+        uniques: uniques.is_some(),
+    };
+    writeln!(f)?;
+    write_header(f, &layout, name)?;
+    for (key, stats) in rows {
+        let unique = uniques
+            .and_then(|uniques| uniques.get(*key))
+            .map(HyperLogLog::estimate);
+        write_dividing_line(f, &layout)?;
+        write_stats(f, &layout, key, stats, unique)?;
+    }
+    if let StatsTable::Bounded { slots, other } = table {
+        if other.codes.sum() > 0 {
+            write_dividing_line(f, &layout)?;
+            write_stats(f, &layout, OTHER, other, None)?;
+        }
+        match name.strip_suffix('y') {
+            Some(stem) => writeln!(f, " evicted {}ies: {}", stem, slots.evictions())?,
+            None => writeln!(f, " evicted {}s: {}", name, slots.evictions())?,
+        }
+    }
+    Ok(())
+}
+
+// Width of the name column needed to fit every row
+fn name_width(rows: &[(&str, &RequestStats)]) -> usize {
+    let max_width = rows.iter().map(|x| x.0.len()).max().unwrap_or(0);
+    if max_width > 5 {
+        max_width
+    } else {
+        5
+    }
+}
+
+// Prefixes are left-aligned and indented by their depth, so that the hierarchy is visible
+fn write_route_tree(
+    f: &mut fmt::Formatter,
+    layout: &Layout,
+    tree: &RouteTree,
+    top: usize,
+    sort: SortKey,
+) -> fmt::Result {
+    let rows: Vec<(String, RequestStats)> = tree
+        .rows(top, sort)
+        .into_iter()
+        .map(|(depth, prefix, stats)| {
+            (
+                format!("{:indent$}{}", "", prefix, indent = depth * 2),
+                stats,
+            )
+        })
+        .collect();
+    let max_width = rows.iter().map(|row| row.0.len()).max().unwrap_or(0);
+    let name_width = if max_width > 6 { max_width } else { 6 };
+    let layout = Layout {
+        name_width,
+        num_width: layout.num_width,
+        latency: layout.latency,
+        uniques: false,
+    };
+
+    writeln!(f)?;
+    write_header(f, &layout, &format!("{:<name_width$}", "prefix"))?;
+    write_dividing_line(f, &layout)?;
+    for (name, stats) in rows {
+        write_stats(f, &layout, &format!("{:<name_width$}", name), &stats, None)?;
+    }
+    Ok(())
+}
+
+// Abbreviate a byte count so that it fits in a column, e.g. 1.5K or 320M
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["K", "M", "G", "T", "P"];
+    if bytes < 1024 {
+        return bytes.to_string();
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    // Anything that would round up to 10 gets no decimal place, to keep the column narrow
+    match value < 9.95 {
+        true => format!("{:.1}{}", value, UNITS[unit]),
+        false => format!("{:.0}{}", value, UNITS[unit]),
+    }
+}
+
+// Format a latency in milliseconds below one second, and in seconds above it
+fn format_seconds(seconds: f64) -> String {
+    if seconds.is_infinite() {
+        format!(
+            ">{}s",
+            stats::LATENCY_BOUNDS[stats::LATENCY_BOUNDS.len() - 1]
+        )
+    } else if seconds < 1.0 {
+        format!("{}ms", (seconds * 1000.0).round())
+    } else {
+        format!("{}s", seconds)
+    }
+}
+
 fn get_string_length_of_int(num: u32) -> usize {
     // Replace with log_10 implementation at some point, bummer to have to allocate here
     // https://github.com/rust-lang/rust/issues/70887
     num.to_string().len()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_bytes_abbreviates() {
+        assert_eq!(format_bytes(512), "512");
+        assert_eq!(format_bytes(1536), "1.5K");
+        assert_eq!(format_bytes(10200), "10K");
+        assert_eq!(format_bytes(20 * 1024 * 1024), "20M");
+    }
+
+    #[test]
+    fn format_seconds_switches_units() {
+        assert_eq!(format_seconds(0.0075), "8ms");
+        assert_eq!(format_seconds(0.25), "250ms");
+        assert_eq!(format_seconds(1.5), "1.5s");
+        assert_eq!(format_seconds(f64::INFINITY), ">60s");
+    }
+}
@@ -1,4 +1,9 @@
-use std::process::Command;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, UdpSocket};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
 
 #[test]
 fn prints_help_log() {
@@ -15,20 +20,220 @@ fn prints_help_log() {
     assert!(stdout.is_empty());
 }
 
-// Extremely naive test to check that it's (probably) adding up the log correctly
-// When I have a stable machine-output format I'll parse that properly
-#[test]
-fn watch_test() {
+// A parsed JSON value, just enough to assert on the output of `--output json`
+#[derive(Debug, PartialEq)]
+enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn parse(text: &str) -> Json {
+        let mut chars = text.trim().chars().peekable();
+        let value = Json::parse_value(&mut chars);
+        assert_eq!(chars.next(), None, "trailing characters after JSON value");
+        value
+    }
+
+    fn parse_value(chars: &mut std::iter::Peekable<std::str::Chars>) -> Json {
+        match chars.next().expect("unexpected end of JSON") {
+            'n' => Json::parse_literal(chars, "ull", Json::Null),
+            't' => Json::parse_literal(chars, "rue", Json::Bool(true)),
+            'f' => Json::parse_literal(chars, "alse", Json::Bool(false)),
+            '"' => Json::String(Json::parse_string(chars)),
+            '[' => {
+                let mut items = vec![];
+                while chars.peek() != Some(&']') {
+                    items.push(Json::parse_value(chars));
+                    if chars.peek() == Some(&',') {
+                        chars.next();
+                    }
+                }
+                chars.next();
+                Json::Array(items)
+            }
+            '{' => {
+                let mut members = vec![];
+                while chars.peek() != Some(&'}') {
+                    assert_eq!(chars.next(), Some('"'));
+                    let key = Json::parse_string(chars);
+                    assert_eq!(chars.next(), Some(':'));
+                    members.push((key, Json::parse_value(chars)));
+                    if chars.peek() == Some(&',') {
+                        chars.next();
+                    }
+                }
+                chars.next();
+                Json::Object(members)
+            }
+            c => {
+                let mut number = String::from(c);
+                while let Some(&c) = chars.peek().filter(|c| "+-.eE0123456789".contains(**c)) {
+                    number.push(c);
+                    chars.next();
+                }
+                Json::Number(number.parse().expect("invalid JSON number"))
+            }
+        }
+    }
+
+    fn parse_literal(
+        chars: &mut std::iter::Peekable<std::str::Chars>,
+        rest: &str,
+        value: Json,
+    ) -> Json {
+        for expected in rest.chars() {
+            assert_eq!(chars.next(), Some(expected));
+        }
+        value
+    }
+
+    fn parse_string(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
+        let mut string = String::new();
+        loop {
+            match chars.next().expect("unterminated JSON string") {
+                '"' => return string,
+                '\\' => match chars.next().unwrap() {
+                    'n' => string.push('\n'),
+                    't' => string.push('\t'),
+                    'r' => string.push('\r'),
+                    'u' => {
+                        let hex: String = chars.take(4).collect();
+                        let code = u32::from_str_radix(&hex, 16).unwrap();
+                        string.push(char::from_u32(code).unwrap());
+                    }
+                    c => string.push(c),
+                },
+                c => string.push(c),
+            }
+        }
+    }
+
+    fn as_f64(&self) -> f64 {
+        match self {
+            Json::Number(n) => *n,
+            other => panic!("expected a number, found {:?}", other),
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        match self {
+            Json::String(s) => s,
+            other => panic!("expected a string, found {:?}", other),
+        }
+    }
+}
+
+impl std::ops::Index<&str> for Json {
+    type Output = Json;
+    fn index(&self, key: &str) -> &Json {
+        match self {
+            Json::Object(members) => members
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value)
+                .unwrap_or_else(|| panic!("missing key {}", key)),
+            other => panic!("expected an object, found {:?}", other),
+        }
+    }
+}
+
+impl std::ops::Index<usize> for Json {
+    type Output = Json;
+    fn index(&self, index: usize) -> &Json {
+        match self {
+            Json::Array(items) => &items[index],
+            other => panic!("expected an array, found {:?}", other),
+        }
+    }
+}
+
+fn watch_json(args: &[&str]) -> Json {
     let output = Command::new("target/debug/dashlight")
         .arg("watch")
-        .arg("-f")
-        .arg("tests/data/short-log")
+        .arg("--output")
+        .arg("json")
+        .args(args)
         .output()
         .unwrap();
+    assert!(output.status.success());
+    Json::parse(&String::from_utf8_lossy(&output.stdout))
+}
 
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    assert!(stdout.contains("     5 |     2 |     2 |     1 |     0 "));
-    assert!(stdout.contains("         / |     1 |     2 |     0 |     0 "));
+#[test]
+fn watch_test() {
+    let json = watch_json(&["-f", "tests/data/short-log"]);
+
+    assert_eq!(json["version"].as_f64(), 1.0);
+    let total = &json["total"];
+    assert_eq!(total["requests"].as_f64(), 5.0);
+    assert_eq!(total["2xx"].as_f64(), 2.0);
+    assert_eq!(total["3xx"].as_f64(), 2.0);
+    assert_eq!(total["4xx"].as_f64(), 1.0);
+    assert_eq!(total["5xx"].as_f64(), 0.0);
+
+    let root = &json["routes"]["rows"][0];
+    assert_eq!(root["key"].as_str(), "/");
+    assert_eq!(root["2xx"].as_f64(), 1.0);
+    assert_eq!(root["3xx"].as_f64(), 2.0);
+    assert_eq!(json["routes"]["other"], Json::Null);
+    assert_eq!(json["clients"], Json::Null);
+}
+
+#[test]
+fn watch_writes_every_section_as_json() {
+    let json = watch_json(&[
+        "--max-routes",
+        "2",
+        "--depth",
+        "1",
+        "--referrers",
+        "--unique",
+        "ip",
+        "-f",
+        "tests/data/referrer-log",
+    ]);
+
+    assert_eq!(json["total"]["uniques"].as_f64(), 5.0);
+    let routes = &json["routes"];
+    assert_eq!(routes["rows"][0]["key"].as_str(), "/blog/launch");
+    assert_eq!(routes["rows"][0]["uniques"].as_f64(), 3.0);
+    assert_eq!(routes["other"]["requests"].as_f64(), 3.0);
+    assert_eq!(routes["evicted"].as_f64(), 3.0);
+
+    assert_eq!(json["route_tree"][1]["prefix"].as_str(), "/blog");
+    assert_eq!(json["route_tree"][1]["depth"].as_f64(), 1.0);
+
+    let referrers = &json["referrers"];
+    assert_eq!(referrers["direct"].as_f64(), 1.0);
+    assert_eq!(referrers["external"].as_f64(), 6.0);
+    assert_eq!(
+        referrers["domains"]["rows"][0]["key"].as_str(),
+        "google.com"
+    );
+    assert_eq!(referrers["domains"]["other"]["requests"].as_f64(), 2.0);
+    assert_eq!(referrers["domains"]["evicted"].as_f64(), 2.0);
+    // Landings are forgotten along with evicted domains and routes, such as google.com's on /
+    let landings = &referrers["landings"];
+    assert!(matches!(landings, Json::Array(items) if items.len() == 1));
+    assert_eq!(landings[0]["referrer"].as_str(), "google.com");
+    let routes = &landings[0]["routes"];
+    assert!(matches!(routes, Json::Array(items) if items.len() == 1));
+    assert_eq!(routes[0]["name"].as_str(), "/blog/launch");
+    assert_eq!(routes[0]["count"].as_f64(), 2.0);
+}
+
+#[test]
+fn watch_json_includes_latency() {
+    let json = watch_json(&["-f", "tests/data/latency-log"]);
+
+    let total = &json["total"];
+    assert!(total["timed"].as_f64() > 0.0);
+    assert!(total["p99"].as_f64() > 0.0);
 }
 
 #[test]
@@ -45,6 +250,262 @@ fn convert_test() {
     assert!(stdout.contains(r#"43.193.122.65"09/May/2022:00:00:07 +0000"POST"/api/user"403"#));
 }
 
+#[test]
+fn convert_writes_chosen_fields() {
+    let output = Command::new("target/debug/dashlight")
+        .arg("convert")
+        .arg("--fields")
+        .arg("status,ip,path,bytes,ua,agent")
+        .arg("-f")
+        .arg("tests/data/clients-log")
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout
+        .starts_with("401\"203.0.113.9\"/login\"32\"python-requests/2.27.1\"python-requests\n"));
+}
+
+// Splits CSV into rows of fields, undoing RFC 4180 quoting
+fn read_csv(text: &str) -> Vec<Vec<String>> {
+    let mut rows = vec![];
+    let mut row = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => row.push(std::mem::take(&mut field)),
+            '\n' if !quoted => {
+                row.push(std::mem::take(&mut field));
+                rows.push(std::mem::take(&mut row));
+            }
+            c => field.push(c),
+        }
+    }
+    rows
+}
+
+#[test]
+fn convert_writes_csv_with_a_header() {
+    let output = Command::new("target/debug/dashlight")
+        .arg("convert")
+        .arg("--output")
+        .arg("csv")
+        .arg("--header")
+        .arg("--fields")
+        .arg("ip,status,ua")
+        .arg("-f")
+        .arg("tests/data/clients-log")
+        .output()
+        .unwrap();
+
+    let rows = read_csv(&String::from_utf8_lossy(&output.stdout));
+    assert_eq!(rows.len(), 10);
+    assert_eq!(rows[0], vec!["ip", "status", "ua"]);
+    assert_eq!(
+        rows[6],
+        vec![
+            "198.51.100.7",
+            "302",
+            "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/15.4 Safari/605.1.15"
+        ]
+    );
+    assert!(rows.iter().all(|row| row.len() == 3));
+}
+
+#[test]
+fn convert_writes_json_lines() {
+    let output = Command::new("target/debug/dashlight")
+        .arg("convert")
+        .arg("--output")
+        .arg("json")
+        .arg("--fields")
+        .arg("ip,time,status,bytes,referer,agent,country")
+        .arg("--geoip")
+        .arg("tests/data/country.mmdb")
+        .arg("-f")
+        .arg("tests/data/clients-log")
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines.len(), 9);
+    assert_eq!(
+        lines[0],
+        r#"{"ip":"203.0.113.9","time":"2022-05-09T00:00:01+00:00","status":401,"bytes":32,"referer":null,"agent":"python-requests","country":"NL"}"#
+    );
+}
+
+#[test]
+fn influx_writes_requests_and_windows() {
+    let output = Command::new("target/debug/dashlight")
+        .arg("convert")
+        .arg("--output")
+        .arg("influx")
+        .arg("-f")
+        .arg("tests/data/latency-log")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines.len(), 6);
+    assert_eq!(
+        lines[4],
+        "dashlight_request,method=POST,path=/api/orders,status_class=5xx status=502i,bytes=128i,\
+         request_time=0.04,ip=\"10.0.0.3\",ua=\"curl/7.79.1\" 1652054411000000000"
+    );
+
+    // The log runs from 00:00:07 to 00:00:12, so windows of four seconds start at 04, 08 and 12
+    let output = Command::new("target/debug/dashlight")
+        .arg("watch")
+        .arg("--output")
+        .arg("influx")
+        .arg("--interval")
+        .arg("4")
+        .arg("-f")
+        .arg("tests/data/latency-log")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let requests: Vec<&str> = stdout
+        .lines()
+        .filter(|line| line.starts_with("dashlight_requests,status_class=2xx "))
+        .collect();
+    assert_eq!(
+        requests,
+        [
+            "dashlight_requests,status_class=2xx requests=1i 1652054404000000000",
+            "dashlight_requests,status_class=2xx requests=3i 1652054408000000000",
+            "dashlight_requests,status_class=2xx requests=1i 1652054412000000000",
+        ]
+    );
+    assert!(stdout.contains(
+        "dashlight_requests,route=/api/orders,status_class=5xx requests=1i 1652054408000000000\n"
+    ));
+}
+
+#[test]
+fn follow_writes_influx_windows() {
+    let path = std::env::temp_dir().join(format!("dashlight-influx-{}", std::process::id()));
+    let line = |second: u32| {
+        format!(
+            "10.0.0.1 - - [09/May/2022:00:00:0{} +0000] \"GET / HTTP/1.1\" 200 10 \"-\" \"curl\"\n",
+            second
+        )
+    };
+    fs::write(&path, line(1)).unwrap();
+
+    let mut child = Command::new("target/debug/dashlight")
+        .arg("watch")
+        .arg("--follow")
+        .arg("--output")
+        .arg("influx")
+        .arg("--interval")
+        .arg("1")
+        .arg("-f")
+        .arg(&path)
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let stdout = BufReader::new(child.stdout.take().unwrap());
+    let (sender, receiver) = std::sync::mpsc::channel();
+    thread::spawn(move || {
+        for line in stdout.lines().map_while(Result::ok) {
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    // Each line is from a later window than the one before, and is appended after the reporting
+    // thread has had a chance to run, which mustn't take the window's counts
+    let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+    for second in [2, 3] {
+        thread::sleep(Duration::from_millis(1500));
+        file.write_all(line(second).as_bytes()).unwrap();
+    }
+    // Nothing comes after the last line, so its window is written once its time has passed
+    let mut requests = Vec::new();
+    while requests.len() < 3 {
+        let Ok(line) = receiver.recv_timeout(Duration::from_secs(5)) else {
+            break;
+        };
+        if line.starts_with("dashlight_requests,status_class=2xx ") {
+            requests.push(line);
+        }
+    }
+    child.kill().unwrap();
+    child.wait().unwrap();
+    fs::remove_file(&path).unwrap();
+
+    assert_eq!(
+        requests,
+        [
+            "dashlight_requests,status_class=2xx requests=1i 1652054401000000000",
+            "dashlight_requests,status_class=2xx requests=1i 1652054402000000000",
+            "dashlight_requests,status_class=2xx requests=1i 1652054403000000000",
+        ]
+    );
+}
+
+#[test]
+fn export_writes_sqlite() {
+    let path = std::env::temp_dir().join(format!("dashlight-export-{}.db", std::process::id()));
+    // Exporting a second log adds to the same table
+    for log in ["tests/data/latency-log", "tests/data/short-log"] {
+        let output = Command::new("target/debug/dashlight")
+            .arg("export")
+            .arg("--sqlite")
+            .arg(&path)
+            .arg("-f")
+            .arg(log)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+    }
+
+    let connection = rusqlite::Connection::open(&path).unwrap();
+    let (rows, errors, slowest): (u32, u32, String) = connection
+        .query_row(
+            "SELECT count(*),
+                 (SELECT count(*) FROM requests WHERE status >= 500),
+                 (SELECT path FROM requests ORDER BY latency DESC LIMIT 1)
+             FROM requests",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .unwrap();
+    let indexes: Vec<String> = connection
+        .prepare("SELECT name FROM sqlite_master WHERE type = 'index' ORDER BY name")
+        .unwrap()
+        .query_map([], |row| row.get(0))
+        .unwrap()
+        .map(|name| name.unwrap())
+        .collect();
+    drop(connection);
+    fs::remove_file(&path).unwrap();
+
+    assert_eq!(rows, 11);
+    assert_eq!(errors, 1);
+    assert_eq!(slowest, "/report");
+    assert_eq!(
+        indexes,
+        [
+            "requests_ip",
+            "requests_path",
+            "requests_status",
+            "requests_timestamp"
+        ]
+    );
+}
+
 #[test]
 fn print_help() {
     let output = Command::new("target/debug/dashlight")
@@ -59,3 +520,599 @@ fn print_help() {
     assert!(first_line.contains("Usage: dashlight"));
     assert!(stdout.is_empty());
 }
+
+#[test]
+fn watch_groups_routes_by_path() {
+    let output = Command::new("target/debug/dashlight")
+        .arg("watch")
+        .arg("--query-params")
+        .arg("-f")
+        .arg("tests/data/query-log")
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(" /search |     2 |     0 |     1 |     0 "));
+    assert!(stdout.contains(" /search | q (3), page (1), sort (1)"));
+    assert!(!stdout.contains("cats"));
+}
+
+#[test]
+fn watch_canonicalizes_paths() {
+    let output = Command::new("target/debug/dashlight")
+        .arg("watch")
+        .arg("--canonicalize")
+        .arg("-f")
+        .arg("tests/data/canonical-log")
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(" canonicalized paths: 4"));
+    assert!(stdout.contains(" /api/user |     4 |     0 |     1 |     0 "));
+}
+
+#[test]
+fn watch_prints_route_tree() {
+    let output = Command::new("target/debug/dashlight")
+        .arg("watch")
+        .arg("--depth")
+        .arg("2")
+        .arg("-f")
+        .arg("tests/data/tree-log")
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(" /                  |     4 |     1 |     0 |     1 "));
+    assert!(stdout.contains("   /api             |     3 |     0 |     0 |     1 "));
+    assert!(stdout.contains("     /api/v2        |     2 |     0 |     0 |     1 "));
+    assert!(!stdout.contains("/api/v2/orders"));
+}
+
+#[test]
+fn watch_sorts_and_limits_routes() {
+    let output = Command::new("target/debug/dashlight")
+        .arg("watch")
+        .arg("--sort")
+        .arg("p99")
+        .arg("--top")
+        .arg("2")
+        .arg("-f")
+        .arg("tests/data/latency-log")
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let routes: Vec<&str> = stdout
+        .lines()
+        .filter(|line| line.contains(" /"))
+        .map(|line| line.split('|').next().unwrap().trim())
+        .collect();
+    assert_eq!(routes, vec!["/report", "/api/orders"]);
+    assert!(stdout.contains(" /api/orders |     1 |     0 |     0 |     1 |   384 |  75ms "));
+}
+
+#[test]
+fn watch_bounds_route_memory() {
+    let output = Command::new("target/debug/dashlight")
+        .arg("watch")
+        .arg("--max-routes")
+        .arg("2")
+        .arg("-f")
+        .arg("tests/data/tree-log")
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(" /api/v2/orders |     1 |     0 |     0 |     1 "));
+    assert!(stdout.contains("        (other) |     3 |     0 |     0 |     0 "));
+    assert!(stdout.contains(" evicted routes: 3"));
+}
+
+#[test]
+fn watch_bounds_tables_with_max_routes() {
+    let output = Command::new("target/debug/dashlight")
+        .arg("watch")
+        .arg("--clients")
+        .arg("--subnet-v4")
+        .arg("24")
+        .arg("--geoip")
+        .arg("tests/data/country.mmdb")
+        .arg("--asn")
+        .arg("tests/data/asn.mmdb")
+        .arg("--agents")
+        .arg("--max-routes")
+        .arg("2")
+        .arg("-f")
+        .arg("tests/data/clients-log")
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("     (other) |     2 |     1 |     1 |     0 |  7.0K "));
+    assert!(stdout.contains(" evicted clients: 3"));
+    assert!(stdout.contains("        (other) |     1 |     1 |     0 |     0 |  5.0K "));
+    assert!(stdout.contains(" evicted subnets: 1"));
+    assert!(stdout.contains(" evicted countries: 1"));
+    assert!(stdout.contains(" evicted asns: 1"));
+    assert!(stdout.contains(" evicted agents: 2"));
+}
+
+#[test]
+fn watch_lists_top_clients() {
+    let output = Command::new("target/debug/dashlight")
+        .arg("watch")
+        .arg("--clients")
+        .arg("-f")
+        .arg("tests/data/clients-log")
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("       client |   2xx |   3xx |   4xx |   5xx | bytes "));
+    assert!(stdout.contains("  203.0.113.9 |     0 |     0 |     4 |     0 |   128 "));
+    assert!(stdout.contains(" 198.51.100.7 |     1 |     1 |     0 |     0 |  5.0K "));
+}
+
+#[test]
+fn watch_groups_clients_by_subnet() {
+    let output = Command::new("target/debug/dashlight")
+        .arg("watch")
+        .arg("--subnet-v4")
+        .arg("24")
+        .arg("--subnet-v6")
+        .arg("64")
+        .arg("-f")
+        .arg("tests/data/clients-log")
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("  203.0.113.0/24 |     0 |     0 |     5 |     0 |   160 "));
+    assert!(stdout.contains("   2001:db8::/64 |     2 |     0 |     0 |     0 |  4.0K "));
+}
+
+#[test]
+fn watch_skips_lines_that_dont_parse() {
+    // Lines in another format than the one given are counted rather than ending the run
+    let output = Command::new("target/debug/dashlight")
+        .arg("watch")
+        .arg("--log-format")
+        .arg(r#"$remote_addr - $remote_user [$time_local] "$request" $status $body_bytes_sent "$http_referer" "$http_user_agent" "$http_x_forwarded_for""#)
+        .arg("-f")
+        .arg("tests/data/short-log")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains(" unparsed lines: 5"));
+
+    let output = Command::new("target/debug/dashlight")
+        .arg("watch")
+        .arg("--output")
+        .arg("json")
+        .arg("-f")
+        .arg("tests/data/vhost-log")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains(r#""unparsed":6"#));
+
+    // Nor do lines that are cut short, have a non-numeric status, or aren't UTF-8
+    let mut child = Command::new("target/debug/dashlight")
+        .arg("watch")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(b"a - b\n").unwrap();
+    stdin
+        .write_all(
+            b"10.0.0.1 - - [09/May/2022:00:00:07 +0000] \"GET / HTTP/1.1\" OK 1 \"-\" \"c\"\n",
+        )
+        .unwrap();
+    stdin
+        .write_all(
+            b"10.0.0.1 - - [09/May/2022:00:00:07 +0000] \"GET /\xff HTTP/1.1\" 200 1 \"-\" \"c\"\n",
+        )
+        .unwrap();
+    stdin
+        .write_all(
+            b"10.0.0.1 - - [09/May/2022:00:00:07 +0000] \"GET / HTTP/1.1\" 200 1 \"-\" \"c\"\n",
+        )
+        .unwrap();
+    drop(stdin);
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(" unparsed lines: 3"));
+    assert!(stdout.contains("     / |     1 |     0 |     0 |     0 |     1 "));
+}
+
+#[test]
+fn watch_takes_client_from_trusted_proxies() {
+    let output = Command::new("target/debug/dashlight")
+        .arg("watch")
+        .arg("--clients")
+        .arg("--log-format")
+        .arg(r#"$remote_addr - $remote_user [$time_local] "$request" $status $body_bytes_sent "$http_referer" "$http_user_agent" "$http_x_forwarded_for""#)
+        .arg("--set-real-ip-from")
+        .arg("10.0.0.0/8")
+        .arg("--real-ip-recursive")
+        .arg("-f")
+        .arg("tests/data/proxied-log")
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("   203.0.113.9 |     2 |     0 |     0 |     0 |  2.0K "));
+    assert!(stdout.contains("  198.51.100.7 |     0 |     0 |     1 |     0 |    64 "));
+    // Without a forwarded address, the proxy's own address is kept
+    assert!(stdout.contains("      10.0.0.2 |     0 |     0 |     0 |     1 |    64 "));
+    assert!(!stdout.contains("192.0.2.1"));
+}
+
+#[test]
+fn watch_lists_top_countries_and_asns() {
+    let output = Command::new("target/debug/dashlight")
+        .arg("watch")
+        .arg("--geoip")
+        .arg("tests/data/country.mmdb")
+        .arg("--asn")
+        .arg("tests/data/asn.mmdb")
+        .arg("-f")
+        .arg("tests/data/clients-log")
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(" country |   2xx |   3xx |   4xx |   5xx | bytes "));
+    assert!(stdout.contains("      NL |     0 |     0 |     5 |     0 |   160 "));
+    assert!(stdout.contains("      DE |     2 |     0 |     0 |     0 |  4.0K "));
+    assert!(stdout.contains(" AS64501 Example Broadband |     1 |     1 |     0 |     0 |  5.0K "));
+}
+
+#[test]
+fn convert_adds_country_and_asn_columns() {
+    let output = Command::new("target/debug/dashlight")
+        .arg("convert")
+        .arg("--geoip")
+        .arg("tests/data/country.mmdb")
+        .arg("--asn")
+        .arg("tests/data/asn.mmdb")
+        .arg("-f")
+        .arg("tests/data/clients-log")
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.starts_with(
+        "203.0.113.9\"09/May/2022:00:00:01 +0000\"POST\"/login\"401\"NL\"64500\"Example Hosting\n"
+    ));
+}
+
+#[test]
+fn watch_estimates_unique_clients() {
+    let output = Command::new("target/debug/dashlight")
+        .arg("watch")
+        .arg("--unique")
+        .arg("ip")
+        .arg("-f")
+        .arg("tests/data/clients-log")
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(" count |   2xx |   3xx |   4xx |   5xx | bytes |  uniq "));
+    assert!(stdout.contains("     9 |     3 |     1 |     5 |     0 |  9.2K |     5 "));
+    assert!(stdout.contains("     /login |     0 |     1 |     5 |     0 |   160 |     3 "));
+}
+
+#[test]
+fn watch_lists_agents_and_excludes_bots() {
+    let output = Command::new("target/debug/dashlight")
+        .arg("watch")
+        .arg("--agents")
+        .arg("-f")
+        .arg("tests/data/clients-log")
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(" python-requests |     0 |     0 |     5 |     0 |   160 "));
+    assert!(stdout.contains("          Safari |     1 |     1 |     0 |     0 |  5.0K "));
+
+    let output = Command::new("target/debug/dashlight")
+        .arg("watch")
+        .arg("--exclude-bots")
+        .arg("-f")
+        .arg("tests/data/clients-log")
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("     3 |     2 |     1 |     0 |     0 |  7.0K "));
+    assert!(stdout.contains(" excluded bots: 6"));
+}
+
+#[test]
+fn watch_excludes_assets_and_health_checks() {
+    let output = Command::new("target/debug/dashlight")
+        .arg("watch")
+        .arg("--exclude-preset")
+        .arg("static,health")
+        .arg("-f")
+        .arg("tests/data/assets-log")
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("     2 |     2 |     0 |     0 |     0 |  5.0K "));
+    assert!(stdout.contains(" excluded by rules: 7"));
+    assert!(!stdout.contains("/healthz"));
+
+    let output = Command::new("target/debug/dashlight")
+        .arg("watch")
+        .arg("--exclude-regex")
+        .arg("(")
+        .arg("-f")
+        .arg("tests/data/assets-log")
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Invalid --exclude-regex '(': unclosed '('"));
+}
+
+#[test]
+fn watch_reports_referrers() {
+    let output = Command::new("target/debug/dashlight")
+        .arg("watch")
+        .arg("--referrers")
+        .arg("--own-host")
+        .arg("example.com")
+        .arg("-f")
+        .arg("tests/data/referrer-log")
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(" referrers: 1 direct, 2 internal, 4 external"));
+    assert!(stdout.contains("           google.com |     3 |     0 |     0 |     0 |   10K "));
+    assert!(stdout.contains("           google.com | /blog/launch (2), / (1)"));
+    assert!(!stdout.contains("example.com |"));
+}
+
+#[test]
+fn watch_groups_by_virtual_host() {
+    let output = Command::new("target/debug/dashlight")
+        .arg("watch")
+        .arg("--log-format")
+        .arg(r#"$host $remote_addr [$time_local] "$request" $status $body_bytes_sent"#)
+        .arg("-f")
+        .arg("tests/data/vhost-log")
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(" shop.example.com |     2 |     0 |     0 |     1 |  4.6K "));
+    assert!(stdout.contains(" shop.example.com/cart |     1 |     0 |     0 |     1 |   640 "));
+    assert!(stdout.contains("     shop.example.com/ |     1 |     0 |     0 |     0 |  4.0K "));
+    assert!(stdout.contains("          example.com/ |     1 |     0 |     0 |     0 |  2.0K "));
+
+    // Anyone can send a Host header, so the hosts are capped like routes
+    let output = Command::new("target/debug/dashlight")
+        .arg("watch")
+        .arg("--log-format")
+        .arg(r#"$host $remote_addr [$time_local] "$request" $status $body_bytes_sent"#)
+        .arg("--max-routes")
+        .arg("1")
+        .arg("-f")
+        .arg("tests/data/vhost-log")
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("          (other) |     4 |     0 |     0 |     1 |  7.6K "));
+    assert!(stdout.contains(" evicted hosts: 2"));
+}
+
+#[test]
+fn where_filters_requests() {
+    let output = Command::new("target/debug/dashlight")
+        .arg("convert")
+        .arg("--where")
+        .arg(r#"!(ua ~ "python") && ip in 2001:db8::/32"#)
+        .arg("-f")
+        .arg("tests/data/clients-log")
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(
+        stdout,
+        "2001:db8::1\"09/May/2022:00:00:05 +0000\"GET\"/\"200\n\
+         2001:db8::2\"09/May/2022:00:00:06 +0000\"GET\"/\"200\n"
+    );
+}
+
+#[test]
+fn where_reports_bad_expressions() {
+    let output = Command::new("target/debug/dashlight")
+        .arg("watch")
+        .arg("--where")
+        .arg("status >= 5xx")
+        .arg("-f")
+        .arg("tests/data/clients-log")
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains(
+        "Invalid --where expression: expected a number for 'status', found '5xx' at column 11"
+    ));
+}
+
+// Fetch a page from the metrics server, returning the whole response
+fn http_get(addr: &str, path: &str) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(stream, "GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", path, addr).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn follow_serves_metrics() {
+    let path = std::env::temp_dir().join(format!("dashlight-metrics-{}", std::process::id()));
+    let lines = fs::read_to_string("tests/data/latency-log").unwrap();
+    let mut lines = lines.lines();
+    fs::write(&path, format!("{}\n", lines.next().unwrap())).unwrap();
+
+    let mut child = Command::new("target/debug/dashlight")
+        .arg("watch")
+        .arg("--follow")
+        .arg("--listen")
+        .arg("127.0.0.1:0")
+        .arg("--top")
+        .arg("1")
+        .arg("-f")
+        .arg(&path)
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stderr = BufReader::new(child.stderr.take().unwrap());
+    let mut first_line = String::new();
+    stderr.read_line(&mut first_line).unwrap();
+    let addr = first_line
+        .trim()
+        .strip_prefix("Serving metrics at http://")
+        .and_then(|url| url.strip_suffix("/metrics"))
+        .unwrap()
+        .to_string();
+
+    // Lines appended after startup are picked up as they're written
+    let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+    for line in lines {
+        writeln!(file, "{}", line).unwrap();
+    }
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let mut response = http_get(&addr, "/metrics");
+    while !response.contains("dashlight_http_request_duration_seconds_count 6\n")
+        && Instant::now() < deadline
+    {
+        thread::sleep(Duration::from_millis(50));
+        response = http_get(&addr, "/metrics");
+    }
+    let not_found = http_get(&addr, "/");
+    child.kill().unwrap();
+    child.wait().unwrap();
+    fs::remove_file(&path).unwrap();
+
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("Content-Type: text/plain; version=0.0.4; charset=utf-8\r\n"));
+    assert!(response.contains("dashlight_http_request_duration_seconds_count 6\n"));
+    assert!(response
+        .contains("dashlight_http_requests_total{status_class=\"5xx\",method=\"POST\"} 1\n"));
+    // Only the top route gets its own series
+    assert!(response.contains(
+        "dashlight_http_route_requests_total{route=\"/\",status_class=\"2xx\",method=\"GET\"} 3\n"
+    ));
+    assert!(!response.contains("route=\"/api/orders\""));
+    assert!(not_found.starts_with("HTTP/1.1 404 Not Found\r\n"));
+}
+
+#[test]
+fn watch_writes_metrics_file() {
+    let dir = std::env::temp_dir().join(format!("dashlight-textfile-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("dashlight.prom");
+
+    let output = Command::new("target/debug/dashlight")
+        .arg("watch")
+        .arg("--metrics-file")
+        .arg(&path)
+        .arg("-f")
+        .arg("tests/data/latency-log")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let metrics = fs::read_to_string(&path).unwrap();
+    // The temporary file is renamed over the real one, so nothing else is left behind
+    let entries = fs::read_dir(&dir).unwrap().count();
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(entries, 1);
+    assert!(metrics.starts_with("# HELP dashlight_http_requests_total "));
+    assert!(metrics.contains("dashlight_http_request_duration_seconds_count 6\n"));
+    assert!(metrics.contains("dashlight_unparsed_lines_total 0\n"));
+    assert!(
+        metrics.contains("dashlight_http_requests_total{status_class=\"5xx\",method=\"POST\"} 1\n")
+    );
+}
+
+#[test]
+fn follow_sends_statsd_deltas() {
+    let path = std::env::temp_dir().join(format!("dashlight-statsd-{}", std::process::id()));
+    let lines = fs::read_to_string("tests/data/latency-log").unwrap();
+    let lines: Vec<&str> = lines.lines().collect();
+    fs::write(&path, format!("{}\n", lines[..3].join("\n"))).unwrap();
+
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut child = Command::new("target/debug/dashlight")
+        .arg("watch")
+        .arg("--follow")
+        .arg("--interval")
+        .arg("1")
+        .arg("--statsd")
+        .arg(socket.local_addr().unwrap().to_string())
+        .arg("--dogstatsd")
+        .arg("--top")
+        .arg("1")
+        .arg("-f")
+        .arg(&path)
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    // Wait for a packet with the given metric, skipping intervals without it
+    let receive = |metric: &str| {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut buf = [0; 2048];
+        while Instant::now() < deadline {
+            let Ok(read) = socket.recv(&mut buf) else {
+                break;
+            };
+            let packet = String::from_utf8_lossy(&buf[..read]).into_owned();
+            if packet.lines().any(|line| line == metric) {
+                return Some(packet);
+            }
+        }
+        None
+    };
+    let first = receive("dashlight.requests:3|c|#status_class:2xx");
+
+    // Each interval only counts the requests since the last one. They're appended in one write,
+    // so that they all land in the same interval.
+    let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+    file.write_all(format!("{}\n", lines[3..].join("\n")).as_bytes())
+        .unwrap();
+    let second = receive("dashlight.requests:1|c|#status_class:5xx");
+    child.kill().unwrap();
+    child.wait().unwrap();
+    fs::remove_file(&path).unwrap();
+
+    let first = first.unwrap();
+    assert!(first.contains("dashlight.route.requests:3|c|#route:/,status_class:2xx\n"));
+    assert!(first.contains("dashlight.route.request_time.p50:2|g|#route:/\n"));
+    let second = second.unwrap();
+    assert!(second.contains("dashlight.requests:2|c|#status_class:2xx\n"));
+    assert!(second.contains("dashlight.bytes:90384|c\n"));
+}